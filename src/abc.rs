@@ -0,0 +1,1292 @@
+//! Code for reading tunes written in [ABC notation](https://abcnotation.com/wiki/abc:standard:v2.1).
+//! ABC tunes are converted into MusicXML, so that they're loaded by exactly the same code as
+//! [`MusicXmlScore`](crate::music_xml::MusicXmlScore)s (and produce the same annotated scores).
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Context;
+use elementtree::Element;
+use itertools::Itertools;
+
+use crate::music_xml::note_type_quarters;
+
+/// The number of `<divisions>` per quarter note in the converted MusicXML.  This is divisible by
+/// enough small numbers that tuplets and broken rhythms can be represented exactly.
+const DIVISIONS: u32 = 10080;
+
+/// The highest number an ending can have.  This stops ranges like `[1-999999` from expanding
+/// into millions of passes.
+const MAX_ENDING: usize = 16;
+
+/// Determine whether some bytes look like an ABC file, i.e. they start with an `X:` (reference
+/// number) field or an `%abc` version comment.
+pub fn looks_like_abc(bytes: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]);
+    let text = text.trim_start_matches('\u{FEFF}');
+    text.starts_with("%abc")
+        || text
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('%'))
+            .is_some_and(|line| line.starts_with("X:"))
+}
+
+/// Convert the first tune of an ABC file into a `score-partwise` MusicXML tree.  Each voice
+/// (`V:`) of the tune becomes a separate part.
+pub fn to_music_xml(text: &str) -> anyhow::Result<Element> {
+    let mut tune = Tune::default();
+    let mut is_in_header = true;
+    let mut has_tune_started = false;
+    for (line_idx, line) in text.lines().enumerate() {
+        let line = line.trim_end();
+        let field = field(line);
+        // Only read the first tune, which starts with an `X:` field (if there is one)
+        if let Some(('X', _)) = field {
+            if has_tune_started {
+                break;
+            }
+            has_tune_started = true;
+            continue;
+        }
+        if line.trim().is_empty() && !is_in_header {
+            break; // Tunes end with an empty line
+        }
+
+        let result = match field {
+            Some((name, value)) => tune.read_field(name, value, is_in_header),
+            None if line.trim_start().starts_with('%') => Ok(()), // Comments and directives
+            None if is_in_header && line.trim().is_empty() => Ok(()),
+            None => {
+                is_in_header = false;
+                tune.read_music(line)
+            }
+        };
+        result.with_context(|| format!("Error on line {}", line_idx + 1))?;
+        // The `K:` field ends the header
+        if let (Some(('K', _)), true) = (field, is_in_header) {
+            tune.finish_header();
+            is_in_header = false;
+        }
+    }
+    tune.finish()
+}
+
+/// If a line is an information field (like `T:Title`), return its name and value.
+fn field(line: &str) -> Option<(char, &str)> {
+    let mut chars = line.chars();
+    let name = chars.next()?;
+    let is_field = name.is_ascii_alphabetic() && chars.next() == Some(':');
+    // Lines like `|:` can't be confused with fields, but `w:` (lyrics) fields must be skipped
+    is_field.then(|| (name, line[2..].trim()))
+}
+
+/// The state of a tune whose music is being read.
+#[derive(Debug, Default)]
+struct Tune {
+    title: Option<String>,
+    /// The settings given in the header, which are used as the defaults for every voice
+    defaults: VoiceSettings,
+    /// The `<sound tempo>` given in the header, in quarter notes per minute
+    tempo: Option<(Option<String>, Option<f64>)>,
+    voices: Vec<Voice>,
+    /// The index of the voice which is currently being written
+    current_voice: usize,
+}
+
+/// The settings which can be changed by information fields.
+#[derive(Debug, Clone, Default)]
+struct VoiceSettings {
+    /// The unit note length (`L:`), in quarter notes
+    unit_length: Option<f64>,
+    /// The time signature (`M:`), as `(<beats>, <beat type>)`.  Free meter is `None`.
+    meter: Option<(String, u32)>,
+    /// The alteration of each note letter given by the key signature (`K:`)
+    key: HashMap<char, i8>,
+    /// The number of sharps (or negative number of flats) in the key signature, if it's a
+    /// standard key
+    key_fifths: Option<i32>,
+}
+
+impl VoiceSettings {
+    /// The unit note length, which defaults to an eighth note (or a sixteenth in short meters).
+    fn unit_length(&self) -> f64 {
+        self.unit_length
+            .unwrap_or_else(|| match self.meter_quarters() {
+                Some(quarters) if quarters < 3.0 => 0.25,
+                _ => 0.5,
+            })
+    }
+
+    fn meter_quarters(&self) -> Option<f64> {
+        let (beats, beat_type) = self.meter.as_ref()?;
+        let beats = beats
+            .split('+')
+            .map(|b| b.parse::<f64>().ok())
+            .sum::<Option<f64>>()?;
+        Some(beats * 4.0 / *beat_type as f64)
+    }
+}
+
+/// A single voice of a tune, which becomes one MusicXML part.
+#[derive(Debug)]
+struct Voice {
+    id: String,
+    name: Option<String>,
+    settings: VoiceSettings,
+    /// The measures which have been finished
+    measures: Vec<Element>,
+    /// The measure currently being written
+    measure: Element,
+    /// The accidentals written so far in the current measure, keyed by `(<letter>, <octave>)`
+    measure_accidentals: HashMap<(char, i8), i8>,
+    /// The pitches (in semitones) of the notes which have started a tie but not ended it
+    open_ties: HashSet<i32>,
+    /// The indices (in `measure`) of the `<note>`s of the last chord, its duration in quarter
+    /// notes and its tuplet.  This is used to apply broken rhythms (like `A>B`).
+    last_chord: Option<(Vec<usize>, f64, Option<Tuplet>)>,
+    /// The amount by which the next note is lengthened by a broken rhythm
+    next_note_factor: Option<f64>,
+    /// The current tuplet, and the number of notes left in it
+    tuplet: Option<(Tuplet, usize)>,
+    is_in_grace_group: bool,
+    is_fermata_pending: bool,
+    /// The numbers of the ending (volta) which hasn't been finished yet, if there is one
+    open_ending: Option<String>,
+}
+
+impl Voice {
+    fn new(id: String, settings: VoiceSettings) -> Self {
+        // The first measure contains the initial attributes
+        let mut measure = Element::new("measure");
+        let attributes = measure.append_new_child("attributes");
+        attributes
+            .append_new_child("divisions")
+            .set_text(DIVISIONS.to_string());
+        if let Some(fifths) = settings.key_fifths {
+            append_key(attributes, fifths);
+        }
+        if let Some(meter) = &settings.meter {
+            append_time(attributes, meter);
+        }
+        Self {
+            id,
+            name: None,
+            settings,
+            measures: Vec::new(),
+            measure,
+            measure_accidentals: HashMap::new(),
+            open_ties: HashSet::new(),
+            last_chord: None,
+            next_note_factor: None,
+            tuplet: None,
+            is_in_grace_group: false,
+            is_fermata_pending: false,
+            open_ending: None,
+        }
+    }
+}
+
+/// A tuplet, where `actual` notes are played in the time of `normal` notes.
+#[derive(Debug, Clone, Copy)]
+struct Tuplet {
+    actual: usize,
+    normal: usize,
+}
+
+/// A single note (or rest) parsed from the music.
+#[derive(Debug, Clone, Copy)]
+struct ParsedNote {
+    /// The `(<step>, <accidental>, <octave>)` of the note, or `None` for rests.  The accidental is
+    /// `None` if the note doesn't have one written.
+    pitch: Option<(char, Option<i8>, i8)>,
+    /// The length of the note, in unit note lengths
+    length: f64,
+    /// Is this note tied to the next one?
+    is_tied: bool,
+}
+
+////////////////////////
+// INFORMATION FIELDS //
+////////////////////////
+
+impl Tune {
+    fn read_field(&mut self, name: char, value: &str, is_in_header: bool) -> anyhow::Result<()> {
+        // Remove any comment
+        let value = value.split('%').next().unwrap_or_default().trim();
+        match name {
+            'T' => {
+                self.title.get_or_insert_with(|| value.to_owned());
+            }
+            'V' => self.select_voice(value, is_in_header),
+            'L' => {
+                let length = parse_fraction(value).context("Invalid unit note length")?;
+                self.settings_mut(is_in_header).unit_length = Some(length * 4.0);
+            }
+            'M' => {
+                let meter = parse_meter(value).context("Invalid meter")?;
+                self.settings_mut(is_in_header).meter = meter.clone();
+                if let (false, Some(meter)) = (is_in_header, &meter) {
+                    let voice = self.voice_mut();
+                    append_time(voice.measure.append_new_child("attributes"), meter);
+                }
+            }
+            'K' => {
+                let (key, fifths) = parse_key(value).context("Invalid key")?;
+                let settings = self.settings_mut(is_in_header);
+                settings.key = key;
+                settings.key_fifths = fifths;
+                if let (false, Some(fifths)) = (is_in_header, fifths) {
+                    let voice = self.voice_mut();
+                    append_key(voice.measure.append_new_child("attributes"), fifths);
+                }
+            }
+            'Q' => {
+                let tempo = parse_tempo(value, self.defaults.unit_length());
+                if is_in_header {
+                    self.tempo = Some(tempo);
+                } else {
+                    let voice = self.voice_mut();
+                    append_tempo(&mut voice.measure, &tempo);
+                }
+            }
+            _ => {} // Other fields (e.g. composer or lyrics) don't affect the whacks
+        }
+        Ok(())
+    }
+
+    /// The settings changed by a field: either the defaults (in the header), or the settings of
+    /// the current voice.
+    fn settings_mut(&mut self, is_in_header: bool) -> &mut VoiceSettings {
+        if is_in_header {
+            &mut self.defaults
+        } else {
+            &mut self.voice_mut().settings
+        }
+    }
+
+    /// Switch to the voice given by a `V:` field, creating it if it doesn't exist yet.
+    fn select_voice(&mut self, value: &str, is_in_header: bool) {
+        let id = value
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+        let voice_idx = match self.voices.iter().position(|v| v.id == id) {
+            Some(idx) => idx,
+            None => {
+                self.voices.push(Voice::new(id, self.defaults.clone()));
+                self.voices.len() - 1
+            }
+        };
+        let name = ["name=", "nm="].iter().find_map(|prefix| {
+            let rest = &value[value.find(prefix)? + prefix.len()..];
+            let name = match rest.strip_prefix('"') {
+                Some(quoted) => quoted.split('"').next()?,
+                None => rest.split_whitespace().next()?,
+            };
+            Some(name.to_owned())
+        });
+        if name.is_some() {
+            self.voices[voice_idx].name = name;
+        }
+        if !is_in_header {
+            self.current_voice = voice_idx;
+        }
+    }
+
+    /// Apply the header's settings to the voices declared in the header.  These were created
+    /// before the header was finished, so they might have missed some of its fields.
+    fn finish_header(&mut self) {
+        for voice in &mut self.voices {
+            let name = voice.name.take();
+            *voice = Voice::new(std::mem::take(&mut voice.id), self.defaults.clone());
+            voice.name = name;
+        }
+    }
+
+    fn voice_mut(&mut self) -> &mut Voice {
+        if self.voices.is_empty() {
+            self.voices
+                .push(Voice::new(String::new(), self.defaults.clone()));
+        }
+        &mut self.voices[self.current_voice]
+    }
+
+    /// Finish reading the tune, and convert all the voices into MusicXML.
+    fn finish(mut self) -> anyhow::Result<Element> {
+        let mut music_xml = Element::new("score-partwise");
+        music_xml.set_attr("version", "3.1");
+        let part_list = music_xml.append_new_child("part-list");
+        let num_voices = self.voices.len();
+        let mut parts = Vec::new();
+        for (idx, mut voice) in self.voices.drain(..).enumerate() {
+            voice.finish_measure(true);
+            if let Some(numbers) = voice.open_ending.take() {
+                if let Some(last_measure) = voice.measures.last_mut() {
+                    append_ending(barline(last_measure, "right"), &numbers, "discontinue");
+                }
+            }
+            let part_id = format!("P{}", idx + 1);
+            let name = match (&voice.name, &self.title) {
+                (Some(name), _) => name.clone(),
+                (None, Some(title)) if num_voices == 1 => title.clone(),
+                _ if voice.id.is_empty() => format!("Voice {}", idx + 1),
+                _ => voice.id.clone(),
+            };
+            let score_part = part_list.append_new_child("score-part");
+            score_part.set_attr("id", part_id.as_str());
+            score_part.append_new_child("part-name").set_text(name);
+
+            let mut part = Element::new("part");
+            part.set_attr("id", part_id);
+            for (measure_idx, mut measure) in voice.measures.into_iter().enumerate() {
+                measure.set_attr("number", (measure_idx + 1).to_string());
+                part.append_child(measure);
+            }
+            parts.push(part);
+        }
+        anyhow::ensure!(!parts.is_empty(), "ABC tune doesn't contain any music");
+        for part in parts {
+            music_xml.append_child(part);
+        }
+        Ok(music_xml)
+    }
+}
+
+/// Parse a meter like `3/4`, `C` (common time) or `C|` (cut time).  `none` is free meter.
+fn parse_meter(value: &str) -> Option<Option<(String, u32)>> {
+    let meter = match value.split_whitespace().next().unwrap_or("none") {
+        "none" => None,
+        "C" => Some(("4".to_owned(), 4)),
+        "C|" => Some(("2".to_owned(), 2)),
+        meter => {
+            let (beats, beat_type) = meter.split_once('/')?;
+            let beats = beats.trim_matches(|c| c == '(' || c == ')');
+            Some((beats.to_owned(), beat_type.parse().ok()?))
+        }
+    };
+    Some(meter)
+}
+
+/// Parse a key like `G`, `F#m`, `Bbmix` or `D ^c`, returning the alteration of every note letter
+/// which is changed by the key signature and the number of fifths in the standard key signature.
+fn parse_key(value: &str) -> Option<(HashMap<char, i8>, Option<i32>)> {
+    let mut key = HashMap::new();
+    let mut key_fifths = None;
+    let mut tokens = value.split_whitespace().peekable();
+    // The tonic and mode (e.g. `F#` and `minor`), which can be written with or without a space
+    let key_name = tokens.next_if(|t| !t.contains('=') && !t.starts_with(['^', '_']));
+    let key_name = key_name.unwrap_or("C");
+    if key_name != "none" && !key_name.starts_with(['H', 'h']) {
+        let mut chars = key_name.chars().peekable();
+        let tonic = chars.next()?.to_ascii_uppercase();
+        let accidental = match chars.next_if(|c| *c == '#' || *c == 'b') {
+            Some('#') => 1,
+            Some(_) => -1,
+            None => 0,
+        };
+        let mut mode = chars.collect::<String>();
+        if mode.is_empty() {
+            if let Some(mode_token) = tokens.next_if(|t| mode_fifths(t).is_some()) {
+                mode = mode_token.to_owned();
+            }
+        }
+        let mode_offset = mode_fifths(&mode)?;
+        // Count the sharps (positive) or flats (negative) by going around the circle of fifths
+        let tonic_fifths = "FCGDAEB".find(tonic)? as i32 - 1;
+        let fifths = tonic_fifths + 7 * accidental + mode_offset;
+        key_fifths = Some(fifths).filter(|f| f.abs() <= 7);
+        for idx in 0..fifths.unsigned_abs() as usize {
+            match fifths > 0 {
+                true => key.insert("FCGDAEB".chars().nth(idx % 7)?, 1 + (idx / 7) as i8),
+                false => key.insert("BEADGCF".chars().nth(idx % 7)?, -1 - (idx / 7) as i8),
+            };
+        }
+    }
+    // Explicit accidentals, like `^f` or `=c`
+    for token in tokens {
+        let letter = token.chars().last()?.to_ascii_uppercase();
+        let alter = match token.trim_end_matches(|c: char| c.is_alphabetic()) {
+            "^" => 1,
+            "^^" => 2,
+            "_" => -1,
+            "__" => -2,
+            "=" => 0,
+            _ => continue, // E.g. `clef=treble`
+        };
+        key.insert(letter, alter);
+        key_fifths = None; // The key signature is no longer a standard one
+    }
+    Some((key, key_fifths))
+}
+
+/// The number of fifths between a mode (like `dorian` or `m`) and the major mode.
+fn mode_fifths(mode: &str) -> Option<i32> {
+    let mode = mode.to_lowercase();
+    Some(match mode.get(..3).unwrap_or(&mode) {
+        "" | "maj" | "ion" => 0,
+        "m" | "min" | "aeo" => -3,
+        "mix" => -1,
+        "dor" => -2,
+        "phr" => -4,
+        "lyd" => 1,
+        "loc" => -5,
+        _ => return None,
+    })
+}
+
+/// Parse a tempo like `1/4=120`, `"Allegro" 3/8=60` or (in older tunes) `120`, returning any
+/// text and the tempo in quarter notes per minute.
+fn parse_tempo(value: &str, unit_length: f64) -> (Option<String>, Option<f64>) {
+    let mut text = None;
+    let mut rest = String::new();
+    for (idx, part) in value.split('"').enumerate() {
+        if idx % 2 == 1 {
+            text = Some(part.trim().to_owned());
+        } else {
+            rest.push_str(part);
+        }
+    }
+    let tempo = match rest.split_once('=') {
+        Some((beats, bpm)) => {
+            // The beat can be several note lengths added together, e.g. `1/4 3/8=40`
+            let beat_length = beats
+                .split_whitespace()
+                .map(parse_fraction)
+                .sum::<Option<f64>>()
+                .map(|whole_notes| whole_notes * 4.0);
+            let bpm = bpm.trim().parse::<f64>().ok();
+            bpm.zip(beat_length.filter(|l| *l > 0.0))
+                .map(|(bpm, length)| bpm * length)
+        }
+        None => (rest.trim().parse::<f64>().ok()).map(|bpm| bpm * unit_length),
+    };
+    (text, tempo)
+}
+
+/// Parse a fraction like `1/8` (or a whole number like `1`).
+fn parse_fraction(text: &str) -> Option<f64> {
+    let value = match text.trim().split_once('/') {
+        Some((numerator, denominator)) => {
+            numerator.trim().parse::<f64>().ok()? / denominator.trim().parse::<f64>().ok()?
+        }
+        None => text.trim().parse::<f64>().ok()?,
+    };
+    value.is_finite().then_some(value)
+}
+
+///////////
+// MUSIC //
+///////////
+
+impl Tune {
+    /// Read a line of music.
+    fn read_music(&mut self, line: &str) -> anyhow::Result<()> {
+        let default_tempo = self.tempo.take();
+        let voice = self.voice_mut();
+        if let Some(tempo) = &default_tempo {
+            append_tempo(&mut voice.measure, tempo);
+        }
+        let chars = line.chars().collect_vec();
+        let mut idx = 0;
+        while let Some(&c) = chars.get(idx) {
+            idx += 1;
+            match c {
+                '%' => break, // Comment
+                ' ' | '\t' | '`' | '\\' | 'y' | ')' | '&' => {}
+                '"' => {
+                    let text = take_until(&chars, &mut idx, '"');
+                    self.voice_mut().read_annotation(&text);
+                }
+                '!' | '+' => {
+                    let decoration = take_until(&chars, &mut idx, c);
+                    self.voice_mut().read_decoration(&decoration);
+                }
+                '.' | '~' | 'L' | 'M' | 'P' | 'T' | 'u' | 'v' => {} // Decorations without effect
+                'H' => self.voice_mut().read_decoration("fermata"),
+                'O' => self.voice_mut().read_decoration("coda"),
+                'S' => self.voice_mut().read_decoration("segno"),
+                '{' => self.voice_mut().is_in_grace_group = true,
+                '}' => self.voice_mut().is_in_grace_group = false,
+                '(' => {
+                    if chars.get(idx).is_some_and(char::is_ascii_digit) {
+                        let meter_quarters = self.voice_mut().settings.meter_quarters();
+                        let tuplet = parse_tuplet(&chars, &mut idx, meter_quarters);
+                        self.voice_mut().tuplet = Some(tuplet);
+                    }
+                }
+                '[' if chars.get(idx + 1) == Some(&':') => {
+                    // Inline field, e.g. `[K:D]`
+                    let name = chars[idx];
+                    idx += 2;
+                    let value = take_until(&chars, &mut idx, ']');
+                    self.read_field(name, &value, false)?;
+                }
+                '[' if chars.get(idx).is_some_and(char::is_ascii_digit) => {
+                    // Ending without a bar line, e.g. `[2`
+                    let ending = parse_ending(&chars, &mut idx)?;
+                    self.voice_mut().read_bar("", ending);
+                }
+                '[' if chars.get(idx) != Some(&'|') => {
+                    // Chord, e.g. `[CEG]2`
+                    let mut notes = Vec::new();
+                    while idx < chars.len() && chars[idx] != ']' {
+                        match parse_note(&chars, &mut idx) {
+                            Some(note) => notes.push(note),
+                            None => idx += 1, // Skip anything else inside the chord
+                        }
+                    }
+                    idx += 1; // Skip the `]`
+                    let chord_length = parse_length(&chars, &mut idx);
+                    let is_tied = take_char(&chars, &mut idx, '-');
+                    for note in &mut notes {
+                        note.is_tied |= is_tied;
+                    }
+                    let length = notes.first().map_or(1.0, |n| n.length) * chord_length;
+                    self.voice_mut().add_chord(&notes, length);
+                }
+                '|' | ':' | '[' | ']' => {
+                    // Bar lines, e.g. `|`, `||`, `:|`, `|:`, `::` or `:|2`
+                    let mut bar = c.to_string();
+                    while let Some(&next) = chars.get(idx).filter(|c| "|:[]".contains(**c)) {
+                        // Stop before `[` which starts an inline field or a chord
+                        if next == '[' && !chars.get(idx + 1).is_some_and(char::is_ascii_digit) {
+                            break;
+                        }
+                        bar.push(next);
+                        idx += 1;
+                    }
+                    let ending = parse_ending(&chars, &mut idx)?;
+                    self.voice_mut().read_bar(&bar, ending);
+                }
+                '>' | '<' => {
+                    let mut num_arrows = 1;
+                    while take_char(&chars, &mut idx, c) {
+                        num_arrows += 1;
+                    }
+                    // `>` dots the previous note and halves the next (`<` does the opposite)
+                    let short = 0.5f64.powi(num_arrows);
+                    let long = 2.0 - short;
+                    let (prev, next) = if c == '>' {
+                        (long, short)
+                    } else {
+                        (short, long)
+                    };
+                    self.voice_mut().apply_broken_rhythm(prev, next);
+                }
+                'Z' | 'X' => {
+                    let num_measures = take_number(&chars, &mut idx).unwrap_or(1);
+                    self.voice_mut().add_measure_rests(num_measures);
+                }
+                'x' => {
+                    // Invisible rests take up time without being written
+                    let length = parse_length(&chars, &mut idx);
+                    let duration = self.voice_mut().note_duration(length);
+                    append_duration(&mut self.voice_mut().measure, "forward", duration);
+                }
+                _ => {
+                    idx -= 1;
+                    match parse_note(&chars, &mut idx) {
+                        Some(note) => self.voice_mut().add_chord(&[note], note.length),
+                        None => {
+                            let context = chars[idx..].iter().take(10).collect::<String>();
+                            anyhow::bail!("Unexpected {c:?} at {context:?}");
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Voice {
+    /// Read a quoted string.  Chord symbols (like `"Am"`) are ignored, but annotations (like
+    /// `"^rit."`) are added as words, which can change the tempo.
+    fn read_annotation(&mut self, text: &str) {
+        if let Some(words) = text.strip_prefix(['^', '_', '<', '>', '@']) {
+            self.measure
+                .append_new_child("direction")
+                .append_new_child("direction-type")
+                .append_new_child("words")
+                .set_text(words.trim());
+        }
+    }
+
+    /// Read a decoration like `!fermata!` or `!D.C.!`.
+    fn read_decoration(&mut self, decoration: &str) {
+        let sound = |voice: &mut Self, attr: &str, value: &str| {
+            voice
+                .measure
+                .append_new_child("direction")
+                .append_new_child("sound")
+                .set_attr(attr, value);
+        };
+        match decoration {
+            "fermata" | "invertedfermata" => self.is_fermata_pending = true,
+            "segno" => sound(self, "segno", "segno"),
+            "coda" => sound(self, "coda", "coda"),
+            "fine" => sound(self, "fine", "yes"),
+            "D.C." | "dacapo" | "D.C.alfine" | "D.C.alcoda" => sound(self, "dacapo", "yes"),
+            "D.S." | "D.S.alfine" | "D.S.alcoda" => sound(self, "dalsegno", "segno"),
+            "dacoda" => sound(self, "tocoda", "coda"),
+            _ => {}
+        }
+    }
+
+    /// Add a chord (or a single note or rest) which lasts `length` unit note lengths.
+    fn add_chord(&mut self, notes: &[ParsedNote], length: f64) {
+        let is_grace = self.is_in_grace_group;
+        let mut duration = self.note_duration(length);
+        let mut tuplet = None;
+        if !is_grace {
+            duration *= self.next_note_factor.take().unwrap_or(1.0);
+            if let Some((current_tuplet, notes_left)) = &mut self.tuplet {
+                tuplet = Some(*current_tuplet);
+                duration *= current_tuplet.normal as f64 / current_tuplet.actual as f64;
+                *notes_left -= 1;
+                if *notes_left == 0 {
+                    self.tuplet = None;
+                }
+            }
+        }
+        let has_fermata = !is_grace && std::mem::take(&mut self.is_fermata_pending);
+
+        let mut note_idxs = Vec::new();
+        let mut tied_pitches = Vec::new();
+        for (idx_in_chord, parsed) in notes.iter().enumerate() {
+            let mut note = Element::new("note");
+            if is_grace {
+                note.append_new_child("grace");
+            }
+            if idx_in_chord > 0 {
+                note.append_new_child("chord");
+            }
+            match parsed.pitch {
+                Some((letter, accidental, octave)) => {
+                    let alter = self.note_alter(letter, accidental, octave);
+                    let pitch = note.append_new_child("pitch");
+                    pitch.append_new_child("step").set_text(letter.to_string());
+                    pitch.append_new_child("alter").set_text(alter.to_string());
+                    pitch
+                        .append_new_child("octave")
+                        .set_text(octave.to_string());
+                    let semitones = "C D EF G A B".find(letter).unwrap_or(0) as i32
+                        + alter as i32
+                        + octave as i32 * 12;
+                    if !is_grace {
+                        note.append_new_child("duration");
+                    }
+                    if self.open_ties.remove(&semitones) {
+                        note.append_new_child("tie").set_attr("type", "stop");
+                    }
+                    if parsed.is_tied {
+                        note.append_new_child("tie").set_attr("type", "start");
+                        tied_pitches.push(semitones);
+                    }
+                }
+                None => {
+                    note.append_new_child("rest");
+                    note.append_new_child("duration");
+                }
+            }
+            note.append_new_child("voice").set_text("1");
+            set_note_length(&mut note, duration, tuplet);
+            if has_fermata {
+                note.append_new_child("notations")
+                    .append_new_child("fermata");
+            }
+            note_idxs.push(self.measure.child_count());
+            self.measure.append_child(note);
+        }
+        // Ties only connect to the next note
+        if !is_grace {
+            self.open_ties = tied_pitches.into_iter().collect();
+            self.last_chord = Some((note_idxs, duration, tuplet));
+        }
+    }
+
+    /// The alteration of a note, given its explicit accidental (if any).  Accidentals last until
+    /// the end of the measure, and otherwise the key signature applies.
+    fn note_alter(&mut self, letter: char, accidental: Option<i8>, octave: i8) -> i8 {
+        match accidental {
+            Some(alter) => {
+                self.measure_accidentals.insert((letter, octave), alter);
+                alter
+            }
+            None => (self.measure_accidentals.get(&(letter, octave)))
+                .or_else(|| self.settings.key.get(&letter))
+                .copied()
+                .unwrap_or(0),
+        }
+    }
+
+    /// The duration (in quarter notes) of a note which is `length` unit note lengths long.
+    fn note_duration(&self, length: f64) -> f64 {
+        length * self.settings.unit_length()
+    }
+
+    /// Lengthen or shorten the last chord, and the next chord, by a broken rhythm.
+    fn apply_broken_rhythm(&mut self, prev_factor: f64, next_factor: f64) {
+        if let Some((note_idxs, duration, tuplet)) = &self.last_chord {
+            for &idx in note_idxs {
+                if let Some(note) = self.measure.get_child_mut(idx) {
+                    set_note_length(note, duration * prev_factor, *tuplet);
+                }
+            }
+        }
+        self.next_note_factor = Some(next_factor);
+    }
+
+    /// Add `num_measures` measures of rests.
+    fn add_measure_rests(&mut self, num_measures: usize) {
+        let duration = self.settings.meter_quarters().unwrap_or(4.0);
+        for idx in 0..num_measures {
+            if idx > 0 {
+                self.read_bar("|", None);
+            }
+            let note = self.measure.append_new_child("note");
+            note.append_new_child("rest").set_attr("measure", "yes");
+            note.append_new_child("duration")
+                .set_text(duration_text(duration));
+            note.append_new_child("voice").set_text("1");
+        }
+    }
+
+    /// Read a bar line like `|`, `:|` or `|:`, which may also start an ending (e.g. `:|2`).
+    fn read_bar(&mut self, bar: &str, ending: Option<String>) {
+        let is_repeat_end = bar.starts_with(':');
+        let is_repeat_start = bar.len() > 1 && bar.ends_with(':');
+        let is_section_end = bar.contains("||") || bar.contains(']') || bar.contains("[|");
+
+        // Endings last until the next repeat or double bar, or the next ending
+        let finished_ending =
+            if is_repeat_end || is_repeat_start || is_section_end || ending.is_some() {
+                self.open_ending.take()
+            } else {
+                None
+            };
+        if is_repeat_end || finished_ending.is_some() {
+            // A bar line at the very start of a line belongs to the previous measure
+            let measure = match self.measure_has_notes() {
+                true => &mut self.measure,
+                false => self.measures.last_mut().unwrap_or(&mut self.measure),
+            };
+            let barline = barline(measure, "right");
+            if let Some(numbers) = finished_ending {
+                // Only endings which loop back have a jog at their end
+                let ending_type = if is_repeat_end { "stop" } else { "discontinue" };
+                append_ending(barline, &numbers, ending_type);
+            }
+            if is_repeat_end {
+                barline
+                    .append_new_child("repeat")
+                    .set_attr("direction", "backward");
+            }
+        }
+        if !bar.is_empty() {
+            self.finish_measure(false);
+        }
+
+        if is_repeat_start || ending.is_some() {
+            let barline = barline(&mut self.measure, "left");
+            if let Some(numbers) = &ending {
+                append_ending(barline, numbers, "start");
+            }
+            if is_repeat_start {
+                barline
+                    .append_new_child("repeat")
+                    .set_attr("direction", "forward");
+            }
+        }
+        if ending.is_some() {
+            self.open_ending = ending;
+        }
+    }
+
+    fn measure_has_notes(&self) -> bool {
+        self.measure
+            .children()
+            .any(|child| matches!(child.tag().name(), "note" | "forward"))
+    }
+
+    /// Finish the current measure (if it contains any notes) and start a new one.
+    fn finish_measure(&mut self, is_end_of_tune: bool) {
+        if !self.measure_has_notes() && !is_end_of_tune {
+            return; // E.g. a bar line at the start of a line
+        }
+        let measure = std::mem::replace(&mut self.measure, Element::new("measure"));
+        if measure.child_count() > 0 {
+            self.measures.push(measure);
+        }
+        self.measure_accidentals.clear();
+        self.last_chord = None;
+    }
+}
+
+/// Parse a single note or rest (e.g. `^c'3/2-`), or return `None` if there isn't one at `idx`.
+fn parse_note(chars: &[char], idx: &mut usize) -> Option<ParsedNote> {
+    let start_idx = *idx;
+    let mut accidental = None;
+    while let Some(&c) = chars.get(*idx).filter(|c| matches!(c, '^' | '_' | '=')) {
+        let alter = accidental.unwrap_or(0);
+        accidental = Some(match c {
+            '^' => alter + 1,
+            '_' => alter - 1,
+            _ => 0,
+        });
+        *idx += 1;
+    }
+    let pitch = match chars.get(*idx) {
+        Some(&c) if ('A'..='G').contains(&c) => Some((c, 4)),
+        Some(&c) if ('a'..='g').contains(&c) => Some((c.to_ascii_uppercase(), 5)),
+        Some('z') if accidental.is_none() => None,
+        _ => {
+            *idx = start_idx;
+            return None;
+        }
+    };
+    *idx += 1;
+    let pitch = pitch.map(|(letter, mut octave): (char, i8)| {
+        while let Some(&mark) = chars.get(*idx).filter(|c| matches!(c, '\'' | ',')) {
+            octave += if mark == '\'' { 1 } else { -1 };
+            *idx += 1;
+        }
+        (letter, accidental, octave)
+    });
+    let length = parse_length(chars, idx);
+    let is_tied = take_char(chars, idx, '-');
+    Some(ParsedNote {
+        pitch,
+        length,
+        is_tied,
+    })
+}
+
+/// Parse a note length multiplier like `2`, `3/2`, `/`, `//` or `/4`.
+fn parse_length(chars: &[char], idx: &mut usize) -> f64 {
+    let mut length = take_number(chars, idx).unwrap_or(1) as f64;
+    while take_char(chars, idx, '/') {
+        let denominator = take_number(chars, idx).unwrap_or(2);
+        length /= denominator.max(1) as f64;
+    }
+    length
+}
+
+/// Parse a tuplet like `(3` or `(3:2:4`, returning it and how many notes it contains.
+fn parse_tuplet(chars: &[char], idx: &mut usize, meter_quarters: Option<f64>) -> (Tuplet, usize) {
+    let p = take_number(chars, idx).unwrap_or(3).max(1);
+    let q = take_char(chars, idx, ':')
+        .then(|| take_number(chars, idx))
+        .flatten();
+    let r = take_char(chars, idx, ':')
+        .then(|| take_number(chars, idx))
+        .flatten();
+    let is_compound = meter_quarters.is_some_and(|q| (q / 1.5).fract() == 0.0 && q > 3.0);
+    let q = q.unwrap_or(match p {
+        2 | 4 | 8 => 3,
+        3 | 6 => 2,
+        _ if is_compound => 3,
+        _ => 2,
+    });
+    let tuplet = Tuplet {
+        actual: p,
+        normal: q.max(1),
+    };
+    (tuplet, r.unwrap_or(p).max(1))
+}
+
+/// Parse the numbers of an ending like `1`, `1,2` or `1-3`, expanding ranges.
+fn parse_ending(chars: &[char], idx: &mut usize) -> anyhow::Result<Option<String>> {
+    let mut text = String::new();
+    while let Some(&c) = chars
+        .get(*idx)
+        .filter(|c| c.is_ascii_digit() || **c == ',' || **c == '-')
+    {
+        // A `-` is only part of the ending if it's followed by a digit (otherwise it's a tie)
+        if c == '-' && !chars.get(*idx + 1).is_some_and(char::is_ascii_digit) {
+            break;
+        }
+        text.push(c);
+        *idx += 1;
+    }
+    if text.is_empty() {
+        return Ok(None);
+    }
+    let mut numbers = Vec::new();
+    for range in text.split(',') {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let parse = |number: &str| match number.parse::<usize>() {
+            Ok(n) if n <= MAX_ENDING => Ok(n),
+            Err(_) if number.is_empty() => Ok(1),
+            _ => Err(anyhow::anyhow!(
+                "Ending {range:?} is numbered above {MAX_ENDING}"
+            )),
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        numbers.extend(start..=end.max(start));
+    }
+    Ok(Some(numbers.iter().join(",")))
+}
+
+fn take_number(chars: &[char], idx: &mut usize) -> Option<usize> {
+    let start_idx = *idx;
+    while chars.get(*idx).is_some_and(char::is_ascii_digit) {
+        *idx += 1;
+    }
+    chars[start_idx..*idx]
+        .iter()
+        .collect::<String>()
+        .parse()
+        .ok()
+}
+
+fn take_char(chars: &[char], idx: &mut usize, c: char) -> bool {
+    let is_match = chars.get(*idx) == Some(&c);
+    if is_match {
+        *idx += 1;
+    }
+    is_match
+}
+
+/// Take the text up to (and skipping) the next `end` character.
+fn take_until(chars: &[char], idx: &mut usize, end: char) -> String {
+    let text = chars[*idx..].iter().take_while(|c| **c != end).collect();
+    *idx = (*idx + chars[*idx..].iter().take_while(|c| **c != end).count() + 1).min(chars.len());
+    text
+}
+
+/////////////
+// WRITING //
+/////////////
+
+fn append_key(attributes: &mut Element, fifths: i32) {
+    let key = attributes.append_new_child("key");
+    key.append_new_child("fifths").set_text(fifths.to_string());
+}
+
+fn append_time(attributes: &mut Element, (beats, beat_type): &(String, u32)) {
+    let time = attributes.append_new_child("time");
+    time.append_new_child("beats").set_text(beats.as_str());
+    time.append_new_child("beat-type")
+        .set_text(beat_type.to_string());
+}
+
+fn append_tempo(measure: &mut Element, (text, tempo): &(Option<String>, Option<f64>)) {
+    let direction = measure.append_new_child("direction");
+    if let Some(text) = text {
+        direction
+            .append_new_child("direction-type")
+            .append_new_child("words")
+            .set_text(text.as_str());
+    }
+    if let Some(tempo) = tempo {
+        direction
+            .append_new_child("sound")
+            .set_attr("tempo", tempo.to_string());
+    }
+}
+
+/// Append a `<backup>` or `<forward>` element.
+fn append_duration(measure: &mut Element, tag: &str, duration: f64) {
+    measure
+        .append_new_child(tag)
+        .append_new_child("duration")
+        .set_text(duration_text(duration));
+}
+
+/// Set the `<duration>`, `<type>`, `<dot>`s and `<time-modification>` of a `<note>`, given its
+/// duration in quarter notes.  Grace notes don't have a `<duration>` element, but still get a
+/// `<type>`.
+fn set_note_length(note: &mut Element, duration: f64, tuplet: Option<Tuplet>) {
+    if let Some(duration_elem) = note.find_mut("duration") {
+        duration_elem.set_text(duration_text(duration));
+    }
+    // These must be written in this order, after the `<voice>`
+    for tag in ["type", "dot", "time-modification"] {
+        remove_children(note, tag);
+    }
+    let notations_idx = note.children().position(|c| c.tag().name() == "notations");
+    let notations = notations_idx.and_then(|idx| note.remove_child(idx));
+
+    let written_duration = match tuplet {
+        Some(tuplet) => duration * tuplet.actual as f64 / tuplet.normal as f64,
+        None => duration,
+    };
+    // Notes which can't be written as a single (dotted) note are left for the reader to split
+    let note_type = NOTE_TYPES.iter().find_map(|note_type| {
+        (0..=3)
+            .find(|&dots| {
+                let quarters = note_type_quarters(note_type, dots).unwrap_or_default();
+                (quarters - written_duration).abs() < 1e-6
+            })
+            .map(|dots| (*note_type, dots))
+    });
+    if let Some((note_type, num_dots)) = note_type {
+        note.append_new_child("type").set_text(note_type);
+        for _ in 0..num_dots {
+            note.append_new_child("dot");
+        }
+    }
+    if let Some(tuplet) = tuplet {
+        let time_modification = note.append_new_child("time-modification");
+        time_modification
+            .append_new_child("actual-notes")
+            .set_text(tuplet.actual.to_string());
+        time_modification
+            .append_new_child("normal-notes")
+            .set_text(tuplet.normal.to_string());
+    }
+    if let Some(notations) = notations {
+        note.append_child(notations);
+    }
+}
+
+const NOTE_TYPES: [&str; 10] = [
+    "breve", "whole", "half", "quarter", "eighth", "16th", "32nd", "64th", "128th", "256th",
+];
+
+/// Convert a duration in quarter notes into a number of `<divisions>`.
+fn duration_text(quarters: f64) -> String {
+    ((quarters * DIVISIONS as f64).round() as u64).to_string()
+}
+
+fn remove_children(elem: &mut Element, tag: &str) {
+    let indices = elem
+        .children()
+        .positions(|child| child.tag().name() == tag)
+        .collect_vec();
+    for idx in indices.into_iter().rev() {
+        elem.remove_child(idx);
+    }
+}
+
+/// The `<barline>` at the given `location` of a measure, which is added if it doesn't exist.
+fn barline<'e>(measure: &'e mut Element, location: &str) -> &'e mut Element {
+    let existing_idx = measure.children().position(|child| {
+        child.tag().name() == "barline" && child.get_attr("location") == Some(location)
+    });
+    match existing_idx {
+        Some(idx) => measure.get_child_mut(idx).unwrap(),
+        None => measure
+            .append_new_child("barline")
+            .set_attr("location", location),
+    }
+}
+
+/// Add an `<ending>` to a barline.  This must be done before adding a `<repeat>`, to follow the
+/// order of the MusicXML schema.
+fn append_ending(barline: &mut Element, numbers: &str, ending_type: &str) {
+    barline
+        .append_new_child("ending")
+        .set_attr("number", numbers)
+        .set_attr("type", ending_type);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        music_xml::{LoadOptions, MusicXmlScore},
+        score::{Score, Timestamp},
+    };
+
+    /// Load a tune, returning the `(onset, release, note name)` of each event.  Times are in
+    /// seconds, so tunes played at 60 quarter notes per minute give times in quarter notes.
+    fn events(abc: &str) -> Vec<(f64, f64, String)> {
+        let tree = to_music_xml(abc).unwrap();
+        let score = MusicXmlScore::from_partwise_tree(tree, &LoadOptions::default()).unwrap();
+        let secs = |time: Timestamp| (Timestamp::ZERO.secs_until(time) * 1e6).round() / 1e6;
+        (score.events().iter())
+            .map(|e| (secs(e.onset), secs(e.release), e.instrument.name()))
+            .collect()
+    }
+
+    fn expected(events: &[(f64, f64, &str)]) -> Vec<(f64, f64, String)> {
+        (events.iter())
+            .map(|(onset, release, name)| (*onset, *release, name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn header_fields() {
+        let abc = "X:1\nT:Test tune\nM:3/4\nL:1/4\nQ:1/4=60\nK:G\nF G A|\n";
+        let tree = to_music_xml(abc).unwrap();
+        let part_name = tree.navigate(&["part-list", "score-part", "part-name"]);
+        assert_eq!(part_name.unwrap().text(), "Test tune");
+        let time = tree
+            .navigate(&["part", "measure", "attributes", "time"])
+            .unwrap();
+        assert_eq!(time.find("beats").unwrap().text(), "3");
+        assert_eq!(time.find("beat-type").unwrap().text(), "4");
+        // The key of G sharpens every F
+        assert_eq!(
+            events(abc),
+            expected(&[(0.0, 1.0, "F♯4"), (1.0, 2.0, "G4"), (2.0, 3.0, "A4")])
+        );
+        // The tempo is set by `Q:`
+        let abc = "X:1\nL:1/4\nQ:1/4=120\nK:C\nC D|\n";
+        assert_eq!(events(abc), expected(&[(0.0, 0.5, "C4"), (0.5, 1.0, "D4")]));
+    }
+
+    #[test]
+    fn unit_lengths() {
+        // The unit length defaults to an eighth note
+        let abc = "X:1\nM:4/4\nQ:1/4=60\nK:C\nC D2 E/2 F3/2|\n";
+        assert_eq!(
+            events(abc),
+            expected(&[
+                (0.0, 0.5, "C4"),
+                (0.5, 1.5, "D4"),
+                (1.5, 1.75, "E4"),
+                (1.75, 2.5, "F4"),
+            ])
+        );
+        // ... or a sixteenth note in short meters
+        let abc = "X:1\nM:2/4\nQ:1/4=60\nK:C\nC D2|\n";
+        assert_eq!(
+            events(abc),
+            expected(&[(0.0, 0.25, "C4"), (0.25, 0.75, "D4")])
+        );
+        // `L:` sets it explicitly
+        let abc = "X:1\nM:2/4\nL:1/2\nQ:1/4=60\nK:C\nC D/2|\n";
+        assert_eq!(events(abc), expected(&[(0.0, 2.0, "C4"), (2.0, 3.0, "D4")]));
+    }
+
+    #[test]
+    fn broken_rhythm() {
+        let abc = "X:1\nL:1/4\nQ:1/4=60\nK:C\nC>D E<F|\n";
+        assert_eq!(
+            events(abc),
+            expected(&[
+                (0.0, 1.5, "C4"),
+                (1.5, 2.0, "D4"),
+                (2.0, 2.5, "E4"),
+                (2.5, 4.0, "F4"),
+            ])
+        );
+    }
+
+    #[test]
+    fn chords() {
+        let abc = "X:1\nL:1/4\nQ:1/4=60\nK:C\n[CEG]2 c|\n";
+        assert_eq!(
+            events(abc),
+            expected(&[
+                (0.0, 2.0, "C4"),
+                (0.0, 2.0, "E4"),
+                (0.0, 2.0, "G4"),
+                (2.0, 3.0, "C5"),
+            ])
+        );
+    }
+
+    #[test]
+    fn ties_across_bars() {
+        let abc = "X:1\nM:4/4\nL:1/4\nQ:1/4=60\nK:C\nC2 D2-|D2 E2|\n";
+        assert_eq!(
+            events(abc),
+            expected(&[(0.0, 2.0, "C4"), (2.0, 6.0, "D4"), (6.0, 8.0, "E4")])
+        );
+    }
+
+    #[test]
+    fn accidentals_last_until_the_end_of_the_bar() {
+        let abc = "X:1\nL:1/4\nQ:1/4=60\nK:C\n^C C c =C|C|\n";
+        assert_eq!(
+            events(abc),
+            expected(&[
+                (0.0, 1.0, "C♯4"),
+                (1.0, 2.0, "C♯4"),
+                // Accidentals only apply to the same octave
+                (2.0, 3.0, "C5"),
+                (3.0, 4.0, "C4"),
+                (4.0, 5.0, "C4"),
+            ])
+        );
+    }
+
+    #[test]
+    fn octave_marks() {
+        let abc = "X:1\nL:1/4\nQ:1/4=60\nK:C\nC, C c c'|\n";
+        assert_eq!(
+            events(abc),
+            expected(&[
+                (0.0, 1.0, "C3"),
+                (1.0, 2.0, "C4"),
+                (2.0, 3.0, "C5"),
+                (3.0, 4.0, "C6"),
+            ])
+        );
+    }
+
+    #[test]
+    fn repeats_and_endings() {
+        let abc = "X:1\nL:1/4\nQ:1/4=60\nK:C\n|: C |1 D :|2 E | F |]\n";
+        assert_eq!(
+            events(abc),
+            expected(&[
+                (0.0, 1.0, "C4"),
+                (1.0, 2.0, "D4"),
+                (2.0, 3.0, "C4"),
+                (3.0, 4.0, "E4"),
+                (4.0, 5.0, "F4"),
+            ])
+        );
+        // Each ending is closed with the same number as it started, in the same barline as its
+        // repeat.  The final ending lasts until the end of the section.
+        let tree = to_music_xml(abc).unwrap();
+        let measures = tree.find("part").unwrap().find_all("measure").collect_vec();
+        let barlines = |measure: &Element| {
+            (measure.find_all("barline"))
+                .map(|barline| {
+                    let children = (barline.children())
+                        .map(|c| {
+                            let attrs = c.attrs().map(|(_, value)| value).join(" ");
+                            format!("{} {attrs}", c.tag().name())
+                        })
+                        .collect_vec();
+                    (barline.get_attr("location").unwrap().to_owned(), children)
+                })
+                .collect_vec()
+        };
+        let barline = |location: &str, children: &[&str]| {
+            let children = children.iter().map(|c| c.to_string()).collect_vec();
+            (location.to_owned(), children)
+        };
+        assert_eq!(
+            barlines(measures[0]),
+            [barline("left", &["repeat forward"])]
+        );
+        assert_eq!(
+            barlines(measures[1]),
+            [
+                barline("left", &["ending 1 start"]),
+                barline("right", &["ending 1 stop", "repeat backward"]),
+            ]
+        );
+        assert_eq!(
+            barlines(measures[2]),
+            [barline("left", &["ending 2 start"])]
+        );
+        assert_eq!(
+            barlines(measures[3]),
+            [barline("right", &["ending 2 discontinue"])]
+        );
+    }
+
+    #[test]
+    fn header_fields_after_voices() {
+        // The unit length and key apply to both voices, even though they come after the `V:`s
+        let abc = "X:1\nQ:1/4=60\nV:1\nV:2\nL:1/2\nK:G\nV:1\nF|\nV:2\nF,|\n";
+        let mut events = events(abc);
+        events.sort_by(|a, b| a.2.cmp(&b.2));
+        assert_eq!(events, expected(&[(0.0, 2.0, "F♯3"), (0.0, 2.0, "F♯4")]));
+    }
+
+    #[test]
+    fn huge_endings() {
+        assert!(to_music_xml("X:1\nK:C\n|: C |1-16 D :|\n").is_ok());
+        assert!(to_music_xml("X:1\nK:C\n|: C |1-999999999 D :|\n").is_err());
+    }
+}
@@ -1,39 +1,202 @@
 //! Code for computing the assignment of boomwhackers to players
 
-use std::ops::Range;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    ops::Range,
+    str::FromStr,
+    time::Duration,
+};
 
+use index_vec::IndexVec;
 use itertools::Itertools;
 use ordered_float::OrderedFloat;
 use rand::{seq::SliceRandom, Rng, SeedableRng};
 
 use crate::{
-    music_xml::{MusicXmlScore, Timestamp},
-    note::Note,
+    constraint::Constraint,
+    instrument::Instrument,
+    music_xml::Hand,
+    roster::Player,
+    score::{Event, Score, Timestamp, Whacks},
 };
 
 /// An `Assignment` of boomwhackers to players.
 #[derive(Debug, Clone)]
 pub struct Assignment {
-    pub players: Vec<(Vec<Note>, Vec<Note>)>,
+    /// The players who the boomwhackers are assigned to, in the same order as `players`
+    pub roster: Vec<Player>,
+    /// The boomwhackers held in each player's `(left, right)` hands
+    pub players: Vec<(Vec<Instrument>, Vec<Instrument>)>,
     pub score: f64,
 }
 
-impl Assignment {
-    pub fn search(music: &MusicXmlScore, num_players: usize, seed: u64) -> Self {
-        let fast_assignment = FastAssignment::from_search(music, num_players, seed);
+/// Options which control how [`Assignment::search`] searches for a good assignment.
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub strategy: SearchStrategy,
+    /// How many times the search starts again from a new random assignment.  The best
+    /// assignment found by any run is kept.
+    pub restarts: usize,
+    /// How many moves are tried in each run.  Tabu search scores a sample of swaps for every
+    /// move, so does more work per iteration than the other strategies.
+    pub iterations: usize,
+    /// The fixed latency between a player whacking one note and being able to start swapping
+    /// whackers for the next, which is taken out of every swap.  This isn't a [`TimingModel`]:
+    /// delaying every timestamp by the same amount wouldn't change any of the gaps between them,
+    /// and which gaps need a reaction depends on which notes end up in the same hand.
+    ///
+    /// [`TimingModel`]: crate::tempo::TimingModel
+    pub reaction_time: Duration,
+    pub seed: u64,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
         Self {
-            score: fast_assignment.score(music),
+            strategy: SearchStrategy::Greedy,
+            restarts: 100,
+            iterations: 1_000,
+            reaction_time: Duration::ZERO,
+            seed: 0,
+        }
+    }
+}
+
+/// How each run of the search moves between assignments.  Every move swaps two whackers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchStrategy {
+    /// Only move to assignments which are strictly better (i.e. stochastic hill-climbing)
+    Greedy,
+    /// Simulated annealing: worse assignments are sometimes accepted, with a probability which
+    /// shrinks as the temperature is lowered geometrically from `start_temperature` to
+    /// `end_temperature` over the run
+    Annealing {
+        start_temperature: f64,
+        end_temperature: f64,
+    },
+    /// Tabu search: always move to the best of a sample of swaps (even if it's worse than the
+    /// current assignment), but don't move the swapped whackers again for the next `tenure`
+    /// moves unless that would find a new best assignment
+    Tabu { tenure: usize },
+}
+
+impl FromStr for SearchStrategy {
+    type Err = anyhow::Error;
+
+    /// Parse `greedy`, `annealing[:<start temperature>[:<end temperature>]]` or
+    /// `tabu[:<tenure>]`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut fields = s.split(':');
+        let name = fields.next().unwrap_or_default();
+        let params = fields.map(str::trim).collect_vec();
+        let invalid_params = || anyhow::anyhow!("Invalid parameters for search strategy {s:?}");
+        let param = |idx: usize, default: f64| match params.get(idx) {
+            Some(param) => param.parse::<f64>().map_err(|_| invalid_params()),
+            None => Ok(default),
+        };
+        let (strategy, max_params) = match name {
+            "greedy" => (Self::Greedy, 0),
+            "annealing" => {
+                let start_temperature = param(0, 5.0)?;
+                let end_temperature = param(1, 0.01)?;
+                anyhow::ensure!(
+                    start_temperature >= end_temperature && end_temperature > 0.0,
+                    "Annealing temperatures must be positive, and can't increase"
+                );
+                let strategy = Self::Annealing {
+                    start_temperature,
+                    end_temperature,
+                };
+                (strategy, 2)
+            }
+            "tabu" => {
+                let tenure = match params.first() {
+                    Some(tenure) => tenure.parse::<usize>().map_err(|_| invalid_params())?,
+                    None => 5,
+                };
+                (Self::Tabu { tenure }, 1)
+            }
+            _ => anyhow::bail!("Unknown search strategy {s:?} (expected greedy/annealing/tabu)"),
+        };
+        anyhow::ensure!(params.len() <= max_params, "Too many parameters in {s:?}");
+        Ok(strategy)
+    }
+}
+
+impl Assignment {
+    /// Search for a good `Assignment` of the `score`'s instruments to the players in the
+    /// `roster`, giving no player more whackers than they can manage, never making one hand
+    /// play two notes at once and following every one of the `constraints`.
+    pub fn search(
+        score: &dyn Score,
+        roster: &[Player],
+        constraints: &[Constraint],
+        options: &SearchOptions,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(options.restarts > 0, "The search needs at least one run");
+        let whacks = score.whacks();
+        let hand_capacities = hand_capacities(roster);
+        let hand_sizes = hand_sizes(whacks.len(), &hand_capacities)?;
+        let clashes = Clashes::new(&whacks);
+        // Every note of a chord needs its own hand
+        let num_hands = hand_capacities.iter().filter(|c| **c > 0).count();
+        if let Some((chord, size)) = clashes.largest_chord.filter(|(_, size)| *size > num_hands) {
+            anyhow::bail!(
+                "The chord at {chord} needs {size} hands, but the roster only has {num_hands}"
+            );
+        }
+        // Sorting makes search deterministic despite nondeterminism of `HashMap::keys()`
+        let instruments = whacks
+            .keys()
+            .cloned()
+            .sorted()
+            .collect::<IndexVec<WhackerIdx, _>>();
+        let constraints =
+            HandConstraints::new(constraints, &instruments, roster, &hand_capacities)?;
+        let reaction_secs = options.reaction_time.as_secs_f64();
+        let ctx = SearchContext::new(
+            &whacks,
+            instruments,
+            hand_capacities,
+            reaction_secs,
+            &clashes,
+            &constraints,
+        );
+        let fast_assignment = FastAssignment::from_search(&ctx, roster, &hand_sizes, options);
+        // The search only penalises clashes and broken constraints (and nothing stops it breaking
+        // `Together`/`Apart` constraints), so the best assignment it finds has to be checked
+        let broken_constraints = fast_assignment.broken_constraints(&constraints);
+        if fast_assignment.num_clashes() > 0 || !broken_constraints.is_empty() {
+            let mut problems = fast_assignment.describe_clashes(&ctx, roster);
+            for constraint in broken_constraints {
+                writeln!(problems, "  {constraint} (broken)").unwrap();
+            }
+            anyhow::bail!(
+                "Couldn't find an assignment where no hand plays two notes at once and every \
+                 constraint is followed.  The best assignment found still has these \
+                 problems:\n{problems}"
+            );
+        }
+        let instruments_in = |range: Range<usize>| {
+            (fast_assignment.whackers[range].iter())
+                .map(|idx| ctx.instruments[*idx].clone())
+                .collect_vec()
+        };
+        Ok(Self {
+            roster: roster.to_vec(),
+            score: fast_assignment.score(),
             players: fast_assignment
-                .players
-                .into_iter()
+                .hands
+                .iter()
+                .cloned()
+                .tuples()
                 .map(|(left_range, right_range)| {
-                    (
-                        fast_assignment.whackers[left_range].to_vec(),
-                        fast_assignment.whackers[right_range].to_vec(),
-                    )
+                    (instruments_in(left_range), instruments_in(right_range))
                 })
                 .collect_vec(),
-        }
+        })
     }
 
     #[allow(dead_code)]
@@ -44,7 +207,9 @@ impl Assignment {
             .map(|(left, _right)| left.len())
             .max()
             .unwrap();
-        for (left, right) in &self.players {
+        let max_name_length = self.roster.iter().map(|p| p.name.chars().count()).max();
+        for (player, (left, right)) in self.roster.iter().zip_eq(&self.players) {
+            print!("{:<1$}  ", player.name, max_name_length.unwrap_or(0));
             for _ in 0..(max_num_whackers_in_left_hand - left.len()) {
                 print!("     ");
             }
@@ -65,131 +230,898 @@ impl Assignment {
 // SEARCH //
 ////////////
 
+index_vec::define_index_type! {
+    /// The index of an [`Instrument`] in [`SearchContext::instruments`]
+    struct WhackerIdx = usize;
+}
+
+/// Everything that the search needs to know about the whackers being assigned.  This also
+/// precomputes the values which the search would otherwise keep recomputing.
+#[derive(Debug, Clone)]
+struct SearchContext<'w> {
+    /// Every [`Instrument`] being assigned, sorted
+    instruments: IndexVec<WhackerIdx, Instrument>,
+    /// The [`Event`]s played on each of the `instruments`, sorted by onset
+    events: IndexVec<WhackerIdx, &'w [Event]>,
+    /// The most whackers which each hand can hold, with hands numbered like in
+    /// [`HandConstraints`]
+    hand_capacities: Vec<usize>,
+    /// Number of seconds after a whack before the player can start swapping whackers
+    reaction_secs: f64,
+    clashes: &'w Clashes,
+    /// `clash_matrix[a][b]` is `true` if `a` and `b` are ever played at the same instant
+    clash_matrix: IndexVec<WhackerIdx, IndexVec<WhackerIdx, bool>>,
+    /// `pair_scores[a][b]` is the score of a hand which holds only `a` and `b`.  Most hands hold
+    /// two whackers, so this saves most of the work of scoring.
+    pair_scores: IndexVec<WhackerIdx, IndexVec<WhackerIdx, f64>>,
+    constraints: &'w HandConstraints,
+}
+
+impl<'w> SearchContext<'w> {
+    fn new(
+        whacks: &'w Whacks,
+        instruments: IndexVec<WhackerIdx, Instrument>,
+        hand_capacities: Vec<usize>,
+        reaction_secs: f64,
+        clashes: &'w Clashes,
+        constraints: &'w HandConstraints,
+    ) -> Self {
+        let events = instruments
+            .iter()
+            .map(|instrument| whacks[instrument].as_slice())
+            .collect();
+        let mut ctx = Self {
+            instruments,
+            events,
+            hand_capacities,
+            reaction_secs,
+            clashes,
+            clash_matrix: IndexVec::new(),
+            pair_scores: IndexVec::new(),
+            constraints,
+        };
+        ctx.clash_matrix = pair_matrix(&ctx.instruments, |a, b| {
+            clashes
+                .between(&ctx.instruments[a], &ctx.instruments[b])
+                .is_some()
+        });
+        // `swap_score` only reads the `events` and `reaction_secs`, which are already set
+        ctx.pair_scores = pair_matrix(&ctx.instruments, |a, b| swap_score(&[a, b], &ctx));
+        ctx
+    }
+}
+
+/// Build a matrix containing `f(a, b)` for every pair of whackers `a` and `b`.
+fn pair_matrix<T>(
+    instruments: &IndexVec<WhackerIdx, Instrument>,
+    mut f: impl FnMut(WhackerIdx, WhackerIdx) -> T,
+) -> IndexVec<WhackerIdx, IndexVec<WhackerIdx, T>> {
+    (instruments.indices())
+        .map(|a| instruments.indices().map(|b| f(a, b)).collect())
+        .collect()
+}
+
+/// The [`Constraint`]s, resolved into the whackers and hands which they refer to.  Hands are
+/// numbered in roster order, with each player's left hand just before their right hand.
+#[derive(Debug, Clone)]
+struct HandConstraints {
+    constraints: Vec<(Constraint, ResolvedConstraint)>,
+    /// For every whacker which is pinned or forbidden, which hands are allowed to hold it
+    allowed_hands: IndexVec<WhackerIdx, Option<Vec<bool>>>,
+}
+
+#[derive(Debug, Clone)]
+enum ResolvedConstraint {
+    /// The `whacker` must be held by one of the hands marked as allowed
+    Hands {
+        whacker: WhackerIdx,
+        allowed: Vec<bool>,
+    },
+    /// The two whackers must be in the same hand (if `together`) or different hands
+    Pair {
+        a: WhackerIdx,
+        b: WhackerIdx,
+        together: bool,
+    },
+}
+
+impl HandConstraints {
+    fn new(
+        constraints: &[Constraint],
+        instruments: &IndexVec<WhackerIdx, Instrument>,
+        roster: &[Player],
+        hand_capacities: &[usize],
+    ) -> anyhow::Result<Self> {
+        let num_hands = roster.len() * 2;
+        let whacker = |instrument: &Instrument| {
+            // Unpitched instruments are written by name, since their ids come from the score
+            let is_match = |inst: &Instrument| match instrument {
+                Instrument::Unpitched { name, .. } => inst.name() == **name,
+                _ => inst == instrument,
+            };
+            let mut matches = (instruments.iter_enumerated())
+                .filter(|(_, inst)| is_match(inst))
+                .map(|(idx, _)| idx);
+            match (matches.next(), matches.next()) {
+                (Some(idx), None) => Ok(idx),
+                (None, _) => anyhow::bail!("The score doesn't play {}", instrument.name()),
+                (Some(_), Some(_)) => {
+                    anyhow::bail!(
+                        "The score has several instruments called {}",
+                        instrument.name()
+                    )
+                }
+            }
+        };
+        let player_idx = |name: &str| {
+            (roster.iter().position(|player| player.name == name))
+                .ok_or_else(|| anyhow::anyhow!("No player is called {name:?}"))
+        };
+
+        let mut resolved = Vec::new();
+        for constraint in constraints {
+            let resolved_constraint = match constraint {
+                Constraint::Pin {
+                    instrument,
+                    player,
+                    hand,
+                } => {
+                    let idx = player_idx(player)?;
+                    let mut allowed = vec![false; num_hands];
+                    match hand {
+                        Some(Hand::Left) => allowed[idx * 2] = true,
+                        Some(Hand::Right) => allowed[idx * 2 + 1] = true,
+                        None => allowed[idx * 2..idx * 2 + 2].fill(true),
+                    }
+                    let whacker = whacker(instrument)?;
+                    ResolvedConstraint::Hands { whacker, allowed }
+                }
+                Constraint::Forbid { instrument, player } => {
+                    let idx = player_idx(player)?;
+                    let mut allowed = vec![true; num_hands];
+                    allowed[idx * 2..idx * 2 + 2].fill(false);
+                    let whacker = whacker(instrument)?;
+                    ResolvedConstraint::Hands { whacker, allowed }
+                }
+                Constraint::Together(a, b) | Constraint::Apart(a, b) => ResolvedConstraint::Pair {
+                    a: whacker(a)?,
+                    b: whacker(b)?,
+                    together: matches!(constraint, Constraint::Together(..)),
+                },
+            };
+            resolved.push((constraint.clone(), resolved_constraint));
+        }
+
+        // Combine all the constraints on each whacker, checking that some hand can hold it
+        let hand_has_room = hand_capacities.iter().map(|c| *c > 0).collect_vec();
+        let mut allowed_hands = IndexVec::from_vec(vec![None; instruments.len()]);
+        for (_, constraint) in &resolved {
+            if let ResolvedConstraint::Hands { whacker, allowed } = constraint {
+                let hands: &mut Vec<bool> =
+                    allowed_hands[*whacker].get_or_insert_with(|| hand_has_room.clone());
+                for (hand_allowed, allowed) in hands.iter_mut().zip_eq(allowed) {
+                    *hand_allowed &= *allowed;
+                }
+            }
+        }
+        for (whacker, hands) in allowed_hands.iter_enumerated() {
+            anyhow::ensure!(
+                hands.as_ref().is_none_or(|hands| hands.contains(&true)),
+                "The constraints don't leave any hand which can play {}",
+                instruments[whacker].name()
+            );
+        }
+        Ok(Self {
+            constraints: resolved,
+            allowed_hands,
+        })
+    }
+
+    /// Can the given hand hold `whacker`?
+    fn allows(&self, whacker: WhackerIdx, hand_idx: usize) -> bool {
+        (self.allowed_hands[whacker].as_ref()).is_none_or(|allowed| allowed[hand_idx])
+    }
+}
+
+/// The pairs of [`Instrument`]s which are played at the same instant, and so can never be held
+/// in the same hand.
+#[derive(Debug, Clone, Default)]
+struct Clashes {
+    /// Maps each clashing pair `(a, b)` (where `a < b`) to the chords where both are played
+    pairs: HashMap<(Instrument, Instrument), Vec<ClashingChord>>,
+    /// The chord with the most distinct instruments, along with how many it has
+    largest_chord: Option<(ClashingChord, usize)>,
+}
+
+/// A chord where two [`Instrument`]s are played together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ClashingChord {
+    onset: Timestamp,
+    /// The `note_idx` of the chord's first note (see
+    /// [`Event::chord_note_idx`](crate::score::Event::chord_note_idx))
+    chord_note_idx: usize,
+}
+
+impl Clashes {
+    fn new(whacks: &Whacks) -> Self {
+        // Notes clash if they're in the same chord or start at exactly the same time (e.g.
+        // because they're in different parts)
+        let mut chords_by_onset = BTreeMap::<Timestamp, (usize, Vec<&Instrument>)>::new();
+        let mut chords_by_note_idx = BTreeMap::<usize, (Timestamp, Vec<&Instrument>)>::new();
+        for (instrument, events) in whacks {
+            for event in events {
+                let by_onset = chords_by_onset
+                    .entry(event.onset)
+                    .or_insert((event.chord_note_idx, Vec::new()));
+                by_onset.1.push(instrument);
+                let by_note_idx = chords_by_note_idx
+                    .entry(event.chord_note_idx)
+                    .or_insert((event.onset, Vec::new()));
+                by_note_idx.1.push(instrument);
+            }
+        }
+        let chords = (chords_by_onset.into_iter())
+            .map(|(onset, (chord_note_idx, instruments))| (onset, chord_note_idx, instruments))
+            .chain(
+                (chords_by_note_idx.into_iter())
+                    .map(|(chord_note_idx, (onset, insts))| (onset, chord_note_idx, insts)),
+            );
+
+        let mut clashes = Self::default();
+        for (onset, chord_note_idx, instruments) in chords {
+            let chord = ClashingChord {
+                onset,
+                chord_note_idx,
+            };
+            let instruments = instruments.into_iter().sorted().dedup().collect_vec();
+            if clashes
+                .largest_chord
+                .is_none_or(|(_, size)| instruments.len() > size)
+            {
+                clashes.largest_chord = Some((chord, instruments.len()));
+            }
+            for (a, b) in instruments.into_iter().tuple_combinations() {
+                let chords = clashes.pairs.entry((a.clone(), b.clone())).or_default();
+                // The same chord is usually found both by its onset and its `note_idx`
+                if !chords.iter().any(|c| c.onset == onset) {
+                    chords.push(chord);
+                }
+            }
+        }
+        clashes
+    }
+
+    /// The chords in which `a` and `b` are played together (if any).
+    fn between(&self, a: &Instrument, b: &Instrument) -> Option<&[ClashingChord]> {
+        let key = if a < b {
+            (a.clone(), b.clone())
+        } else {
+            (b.clone(), a.clone())
+        };
+        self.pairs.get(&key).map(Vec::as_slice)
+    }
+
+    /// The pairs of [`Instrument`]s in a single hand which clash.
+    fn in_hand<'h>(
+        &'h self,
+        whackers_in_hand: &'h [Instrument],
+    ) -> impl Iterator<Item = (&'h Instrument, &'h Instrument, &'h [ClashingChord])> + 'h {
+        (whackers_in_hand.iter().tuple_combinations())
+            .filter_map(|(a, b)| Some((a, b, self.between(a, b)?)))
+    }
+}
+
+impl std::fmt::Display for ClashingChord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = Timestamp::ZERO.secs_until(self.onset);
+        write!(f, "{secs:.2}s (note #{})", self.chord_note_idx)
+    }
+}
+
 /// An `Assignment` of boomwhackers to players, optimised for the operations used by the search.
+/// The score and clashes of each hand are cached, so that a move only has to rescore the two
+/// hands which it changes.
 #[derive(Debug, Clone)]
 struct FastAssignment {
-    /// A flat list representing all the [`Hand`]s' [`Whacker`] assignments concatenated together.
+    /// A flat list representing all the hands' whacker assignments concatenated together.
     ///
-    /// Storing them as a single flat list makes [`Self::make_swap`] substantially easier and more
-    /// efficient (since we can uniformly sample two whackers from this list).  Also we use
-    /// [`WhackerIdx`]s instead of [`Whacker`]s for more efficient lookups.
-    whackers: Vec<Note>,
-    /// Each [`Hand`] is assigned to some sub-[`Range`] of `whackers`
-    players: Vec<(Range<usize>, Range<usize>)>,
+    /// Storing them as a single flat list makes [`Self::random_move`] substantially easier and
+    /// more efficient (since we can uniformly sample two whackers from this list).  Also we use
+    /// [`WhackerIdx`]s instead of [`Instrument`]s for more efficient lookups.
+    whackers: Vec<WhackerIdx>,
+    /// Each hand is assigned to some sub-[`Range`] of `whackers`.  Hands are numbered like in
+    /// [`HandConstraints`]
+    hands: Vec<Range<usize>>,
+    /// The hand which holds each slot of `whackers`
+    slot_hands: Vec<usize>,
+    /// The slot of `whackers` which holds each whacker
+    positions: IndexVec<WhackerIdx, usize>,
+    /// The [`score_for_hand`] of each hand
+    hand_scores: Vec<f64>,
+    /// The number of pairs of clashing whackers held by each hand
+    hand_clashes: Vec<usize>,
+}
+
+/// A change which the search can make to a [`FastAssignment`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Move {
+    /// Swap the whackers in two slots, which are in different hands
+    Swap(usize, usize),
+    /// Move the whacker in `slot` to another hand, inserting it `offset` slots into that hand.
+    /// This is how hands change size, so the other hand must have room for the whacker.
+    Transfer {
+        slot: usize,
+        to_hand: usize,
+        offset: usize,
+    },
+}
+
+/// A [`Move`] made by [`FastAssignment::make_move`], which can be reverted by
+/// [`FastAssignment::undo`].
+#[derive(Debug, Clone, Copy)]
+struct MadeMove {
+    /// The move which puts every whacker back where it was
+    reverse: Move,
+    hands: (usize, usize),
+    old_hand_scores: (f64, f64),
+    old_hand_clashes: (usize, usize),
 }
 
 impl FastAssignment {
-    /// Search for an `Assignment` which works well for the given [`MusicXmlScore`].
-    fn from_search(music: &MusicXmlScore, num_players: usize, seed: u64) -> Self {
-        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
-        // Run 100 runs of `gradient_ascent`, each starting from a random assignment
-        let mut assignment = (0..100)
-            .map(|_| Self::gradient_ascent(music, num_players, &mut rng))
-            .max_by_key(|assignment| OrderedFloat(assignment.score(&music)))
+    /// Search for an `Assignment` which works well for the given `whacks`.
+    fn from_search(
+        ctx: &SearchContext,
+        roster: &[Player],
+        hand_sizes: &[(usize, usize)],
+        options: &SearchOptions,
+    ) -> Self {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(options.seed);
+        // Do several runs of the search, each starting from a random assignment
+        let mut assignment = (0..options.restarts)
+            .map(|_| {
+                let start = FastAssignment::random(ctx, hand_sizes, &mut rng);
+                let iterations = options.iterations;
+                match options.strategy {
+                    SearchStrategy::Greedy => start.gradient_ascent(ctx, iterations, &mut rng),
+                    SearchStrategy::Annealing {
+                        start_temperature,
+                        end_temperature,
+                    } => start.anneal(
+                        ctx,
+                        iterations,
+                        (start_temperature, end_temperature),
+                        &mut rng,
+                    ),
+                    SearchStrategy::Tabu { tenure } => {
+                        start.tabu_search(ctx, iterations, tenure, &mut rng)
+                    }
+                }
+            })
+            .max_by_key(|assignment| assignment.quality(ctx))
             .unwrap();
-        // Sort the hands by their lowest `Note`, and re-pair them.  TODO: Assign hand patterns
-        // during search
-        let mut hands = std::mem::take(&mut assignment.players)
-            .into_iter()
-            .flat_map(|(l, r)| [l, r])
-            .collect_vec();
-        for hand_range in &hands {
-            assignment.whackers[hand_range.clone()].sort(); // Sort individual ranges
+        // Sort each hand's whackers, and give the lower hand of each two-handed player to their
+        // left hand (unless a whacker is pinned to one hand).  Hands can't be moved between
+        // players, since players can manage different numbers of whackers.
+        let num_broken_constraints = assignment.num_broken_constraints(ctx.constraints);
+        for (player_idx, player) in roster.iter().enumerate() {
+            let (left, right) = (player_idx * 2, player_idx * 2 + 1);
+            for hand_idx in [left, right] {
+                let range = assignment.hands[hand_idx].clone();
+                assignment.whackers[range].sort();
+            }
+            assignment.update_positions();
+            let lowest_whacker =
+                |hand_idx: usize| assignment.whackers[assignment.hands[hand_idx].clone()].first();
+            if !player.one_handed && lowest_whacker(right) < lowest_whacker(left) {
+                assignment.hands.swap(left, right);
+                assignment.update_positions();
+                if assignment.num_broken_constraints(ctx.constraints) > num_broken_constraints {
+                    assignment.hands.swap(left, right);
+                    assignment.update_positions();
+                }
+            }
         }
-        hands.sort_by_key(|range| assignment.whackers[range.clone()].first().copied());
-        assignment.players = hands.into_iter().tuples().collect_vec();
-
-        assignment
+        // Swapping hands moves their cached values
+        Self::new(ctx, assignment.whackers, assignment.hands)
     }
 
-    /// Perform one run of stochastic gradient 'ascent' to generate one pretty-well-optimised
-    /// [`HandAssignment`]
+    /// Perform one run of stochastic gradient 'ascent', starting from this assignment, to
+    /// generate one pretty-well-optimised `FastAssignment`
     fn gradient_ascent(
-        music: &MusicXmlScore,
-        num_players: usize,
+        mut self,
+        ctx: &SearchContext,
+        iterations: usize,
         rng: &mut impl Rng,
-    ) -> FastAssignment {
-        let mut assignment = FastAssignment::random(music, num_players, rng);
-        let mut next_assignment = assignment.clone();
-        for _ in 0..1_000 {
-            // Try to generate another assignment by swapping some values
-            next_assignment.clone_from(&assignment);
-            next_assignment.make_swap(rng);
-            // If the new assignment is better, move to it
-            if next_assignment.score(&music) > assignment.score(&music) {
-                std::mem::swap(&mut assignment, &mut next_assignment);
+    ) -> Self {
+        let mut quality = self.quality(ctx);
+        for _ in 0..iterations {
+            // Try to generate another assignment by moving some whackers
+            let Some(mv) = self.random_move(ctx, rng) else {
+                continue;
+            };
+            let made_move = self.make_move(ctx, mv);
+            // If the new assignment is better, stay there.  Otherwise, go back
+            let next_quality = self.quality(ctx);
+            if next_quality > quality {
+                quality = next_quality;
+            } else {
+                self.undo(made_move);
             }
         }
-        assignment
+        self
+    }
+
+    /// Perform one run of simulated annealing, starting from this assignment and cooling from
+    /// `start_temperature` to `end_temperature`.  Returns the best assignment seen during the
+    /// run.
+    fn anneal(
+        mut self,
+        ctx: &SearchContext,
+        iterations: usize,
+        (start_temperature, end_temperature): (f64, f64),
+        rng: &mut impl Rng,
+    ) -> Self {
+        let mut quality = self.quality(ctx);
+        let mut best_assignment = self.clone();
+        let mut best_quality = quality;
+        for iteration in 0..iterations {
+            let progress = iteration as f64 / iterations as f64;
+            let cooling = (end_temperature / start_temperature).powf(progress);
+            let temperature = start_temperature * cooling;
+            let Some(mv) = self.random_move(ctx, rng) else {
+                continue;
+            };
+            let made_move = self.make_move(ctx, mv);
+            let next_quality = self.quality(ctx);
+            // Infeasible assignments are always worse than feasible ones, so temperature only
+            // lets the search accept worse scores between equally feasible assignments
+            let is_accepted = match next_quality.0.cmp(&quality.0) {
+                std::cmp::Ordering::Greater => true,
+                std::cmp::Ordering::Less => false,
+                std::cmp::Ordering::Equal => {
+                    let score_diff = next_quality.1 .0 - quality.1 .0;
+                    score_diff >= 0.0 || rng.gen::<f64>() < (score_diff / temperature).exp()
+                }
+            };
+            if is_accepted {
+                quality = next_quality;
+                if quality > best_quality {
+                    best_assignment.clone_from(&self);
+                    best_quality = quality;
+                }
+            } else {
+                self.undo(made_move);
+            }
+        }
+        best_assignment
     }
 
-    /// Create a new `Assignment` where all the [`Whacker`]s are randomly assigned.
-    fn random(music: &MusicXmlScore, num_players: usize, rng: &mut impl Rng) -> Self {
-        let num_hands = num_players * 2;
+    /// Perform one run of tabu search, starting from this assignment.  Each of the `iterations`
+    /// moves goes to the best of a random sample of [`Move`]s, after which the moved whackers
+    /// can't be moved back to the hands they came from for `tenure` moves (unless that would
+    /// find a new best assignment).  Returns the best assignment seen during the run.
+    fn tabu_search(
+        mut self,
+        ctx: &SearchContext,
+        iterations: usize,
+        tenure: usize,
+        rng: &mut impl Rng,
+    ) -> Self {
+        /// How many moves are tried before each move is made
+        const NUM_CANDIDATES: usize = 20;
+
+        let mut best_assignment = self.clone();
+        let mut best_quality = self.quality(ctx);
+        // `tabu_until[whacker][hand_idx]` is the move before which `whacker` can't be moved back
+        // to that hand
+        let no_tabus = vec![0; self.hands.len()];
+        let mut tabu_until =
+            IndexVec::<WhackerIdx, _>::from_vec(vec![no_tabus; self.positions.len()]);
+        for move_idx in 0..iterations {
+            let mut best_move = None;
+            for _ in 0..NUM_CANDIDATES {
+                let Some(mv) = self.random_move(ctx, rng) else {
+                    continue;
+                };
+                // Try the move, then go back to compare it with the other candidates
+                let made_move = self.make_move(ctx, mv);
+                let quality = self.quality(ctx);
+                self.undo(made_move);
+                let is_tabu = (self.moved_whackers(mv))
+                    .any(|(whacker, _, to_hand)| move_idx < tabu_until[whacker][to_hand]);
+                if is_tabu && quality <= best_quality {
+                    continue;
+                }
+                if best_move.is_none_or(|(q, _)| quality > q) {
+                    best_move = Some((quality, mv));
+                }
+            }
+            let Some((quality, mv)) = best_move else {
+                continue; // Every sampled move was tabu
+            };
+            for (whacker, from_hand, _) in self.moved_whackers(mv).collect_vec() {
+                tabu_until[whacker][from_hand] = move_idx + 1 + tenure;
+            }
+            self.make_move(ctx, mv);
+            if quality > best_quality {
+                best_assignment.clone_from(&self);
+                best_quality = quality;
+            }
+        }
+        best_assignment
+    }
+
+    /// Create a new `Assignment` where all the whackers are randomly assigned, with each
+    /// player's hands holding the numbers of whackers given by `hand_sizes`.  Pinned and
+    /// forbidden whackers are only given to hands which are allowed to hold them (if possible).
+    fn random(ctx: &SearchContext, hand_sizes: &[(usize, usize)], rng: &mut impl Rng) -> Self {
         // Shuffle the `WhackerIdx`s to create the random starting assignment
-        let mut whackers = music.whacks.keys().copied().collect_vec();
-        whackers.sort(); // Makes search deterministic despite nondeterminism of `HashMap::keys()`
-        whackers.shuffle(rng);
-        // Determine how many whackers must be given to each hand (with a few hands taking one
-        // extra to make the difference).  I.e. we assign the same number of whackers to all the
-        // hands, with some hands taking one extra.
-        let base_whackers_per_hand = whackers.len() / num_hands;
-        let num_hands_with_one_extra = whackers.len() % num_hands;
+        let mut shuffled_whackers = ctx.instruments.indices().collect_vec();
+        shuffled_whackers.shuffle(rng);
+        // Place the most constrained whackers first, so that they still have a choice of hands
+        let allowed_hands = &ctx.constraints.allowed_hands;
+        shuffled_whackers.sort_by_key(|whacker| {
+            allowed_hands[*whacker]
+                .as_ref()
+                .map_or(usize::MAX, |hands| hands.iter().filter(|a| **a).count())
+        });
+        // Give each whacker to a random free slot in an allowed hand
+        let hand_sizes_flat = hand_sizes.iter().flat_map(|(l, r)| [*l, *r]).collect_vec();
+        let mut hands = vec![Vec::new(); hand_sizes_flat.len()];
+        for whacker in shuffled_whackers {
+            let free_slots = |hand_idx: usize| hand_sizes_flat[hand_idx] - hands[hand_idx].len();
+            let mut candidate_hands = (0..hands.len())
+                .filter(|idx| free_slots(*idx) > 0 && ctx.constraints.allows(whacker, *idx))
+                .collect_vec();
+            if candidate_hands.is_empty() {
+                // The allowed hands are full, so the search will have to fix this whacker later
+                candidate_hands = (0..hands.len())
+                    .filter(|idx| free_slots(*idx) > 0)
+                    .collect();
+            }
+            let total_free_slots: usize = candidate_hands.iter().map(|idx| free_slots(*idx)).sum();
+            let mut slot = rng.gen_range(0..total_free_slots);
+            let hand_idx = candidate_hands
+                .into_iter()
+                .find(|idx| match slot.checked_sub(free_slots(*idx)) {
+                    Some(remaining_slot) => {
+                        slot = remaining_slot;
+                        false
+                    }
+                    None => true,
+                })
+                .unwrap();
+            hands[hand_idx].push(whacker);
+        }
+        let whackers = hands.into_iter().flatten().collect_vec();
         // Determine what ranges are given to each hand
-        let mut hands = Vec::<Range<usize>>::new();
         let mut whackers_allocated = 0;
-        for i in 0..num_hands {
-            // Give the spare whackers to the first `num_hands_with_one_extra` hands
-            let num_whackers =
-                base_whackers_per_hand + if i < num_hands_with_one_extra { 1 } else { 0 };
-            hands.push(whackers_allocated..whackers_allocated + num_whackers);
-            whackers_allocated += num_whackers;
-        }
-        // Group the hands into players
-        assert_eq!(hands.len() % 2, 0);
-        let players: Vec<(_, _)> = hands.into_iter().tuples().collect_vec();
-
-        Self { players, whackers }
-    }
-
-    /// Swap two boomwhackers in this `Assignment`, returning a [`Swap`] object that can be used to
-    /// undo the swap if needed.
-    fn make_swap(&mut self, rng: &mut impl Rng) {
-        let swap_idx_1 = rng.gen_range(0..self.whackers.len());
-        let swap_idx_2 = rng.gen_range(0..self.whackers.len());
-        self.whackers.swap(swap_idx_1, swap_idx_2);
-    }
-
-    // TODO/PERF: Cache scores (and possibly also intermediate values)
-    fn score(&self, music: &MusicXmlScore) -> f64 {
-        let mut score = 0.0;
-        for (left_range, right_range) in &self.players {
-            score += score_for_player(
-                &self.whackers[left_range.clone()],
-                &self.whackers[right_range.clone()],
-                music,
-            );
+        let hand_ranges = hand_sizes_flat
+            .iter()
+            .map(|num_whackers| {
+                let range = whackers_allocated..whackers_allocated + num_whackers;
+                whackers_allocated += num_whackers;
+                range
+            })
+            .collect_vec();
+        assert_eq!(whackers_allocated, whackers.len());
+
+        Self::new(ctx, whackers, hand_ranges)
+    }
+
+    /// Create a `FastAssignment` where each hand holds its sub-[`Range`] of `whackers`, filling
+    /// in all the cached values.
+    fn new(ctx: &SearchContext, whackers: Vec<WhackerIdx>, hands: Vec<Range<usize>>) -> Self {
+        let mut assignment = Self {
+            positions: IndexVec::from_vec(vec![0; whackers.len()]),
+            slot_hands: vec![0; whackers.len()],
+            hand_scores: vec![0.0; hands.len()],
+            hand_clashes: vec![0; hands.len()],
+            whackers,
+            hands,
+        };
+        assignment.update_positions();
+        for hand_idx in 0..assignment.hands.len() {
+            assignment.rescore_hand(ctx, hand_idx);
+        }
+        assignment
+    }
+
+    /// Recompute `self.slot_hands` and `self.positions` after `self.whackers` or `self.hands`
+    /// has been rearranged.  This doesn't update the cached scores.
+    fn update_positions(&mut self) {
+        for (hand_idx, range) in self.hands.iter().enumerate() {
+            for slot in range.clone() {
+                self.slot_hands[slot] = hand_idx;
+                self.positions[self.whackers[slot]] = slot;
+            }
+        }
+    }
+
+    /// Pick a random [`Move`]: either a swap of two whackers in different hands, or a transfer
+    /// of one whacker to another hand which has room for it.  Moves which would take a whacker
+    /// out of the hands which are allowed to hold it are never made, so this returns `None` if
+    /// no allowed move was found.
+    fn random_move(&self, ctx: &SearchContext, rng: &mut impl Rng) -> Option<Move> {
+        const MAX_ATTEMPTS: usize = 100;
+
+        let has_room = |hand_idx: usize| self.hands[hand_idx].len() < ctx.hand_capacities[hand_idx];
+        let hands_with_room = (0..self.hands.len())
+            .filter(|idx| has_room(*idx))
+            .collect_vec();
+        let num_hands_in_use = self.hands.iter().filter(|range| !range.is_empty()).count();
+        let can_leave_hand = num_hands_in_use >= 2
+            || (num_hands_in_use == 1 && hands_with_room.iter().any(|h| self.hands[*h].is_empty()));
+        if !can_leave_hand {
+            return None; // Every whacker is in the same full hand (or there are no whackers)
         }
-        score
+        for _ in 0..MAX_ATTEMPTS {
+            let slot_1 = rng.gen_range(0..self.whackers.len());
+            let hand_1 = self.slot_hands[slot_1];
+            // Moving within a hand doesn't change anything, so pick a slot from the other hands
+            // or (counting each one like a single slot) another hand with room
+            let hand_1_range = self.hands[hand_1].clone();
+            let num_other_slots = self.whackers.len() - hand_1_range.len();
+            let other_hands_with_room = (hands_with_room.iter().copied())
+                .filter(|idx| *idx != hand_1)
+                .collect_vec();
+            let num_choices = num_other_slots + other_hands_with_room.len();
+            if num_choices == 0 {
+                continue;
+            }
+            let mut slot_2 = rng.gen_range(0..num_choices);
+            let mv = match slot_2.checked_sub(num_other_slots) {
+                Some(hand_choice) => {
+                    let to_hand = other_hands_with_room[hand_choice];
+                    let offset = self.hands[to_hand].len();
+                    Move::Transfer {
+                        slot: slot_1,
+                        to_hand,
+                        offset,
+                    }
+                }
+                None => {
+                    if slot_2 >= hand_1_range.start {
+                        slot_2 += hand_1_range.len();
+                    }
+                    Move::Swap(slot_1, slot_2)
+                }
+            };
+            let stays_allowed = |(whacker, from_hand, to_hand): (WhackerIdx, usize, usize)| {
+                !ctx.constraints.allows(whacker, from_hand)
+                    || ctx.constraints.allows(whacker, to_hand)
+            };
+            if self.moved_whackers(mv).all(stays_allowed) {
+                return Some(mv);
+            }
+        }
+        None
+    }
+
+    /// The `(whacker, from_hand, to_hand)` of each whacker which `mv` would move between hands.
+    fn moved_whackers(&self, mv: Move) -> impl Iterator<Item = (WhackerIdx, usize, usize)> {
+        let (first, second) = match mv {
+            Move::Swap(slot_1, slot_2) => {
+                let (hand_1, hand_2) = (self.slot_hands[slot_1], self.slot_hands[slot_2]);
+                (
+                    (self.whackers[slot_1], hand_1, hand_2),
+                    Some((self.whackers[slot_2], hand_2, hand_1)),
+                )
+            }
+            Move::Transfer { slot, to_hand, .. } => {
+                ((self.whackers[slot], self.slot_hands[slot], to_hand), None)
+            }
+        };
+        std::iter::once(first).chain(second)
+    }
+
+    /// Make a [`Move`], rescoring only the two hands which change.
+    fn make_move(&mut self, ctx: &SearchContext, mv: Move) -> MadeMove {
+        let (hand_1, hand_2) = match mv {
+            Move::Swap(slot_1, slot_2) => (self.slot_hands[slot_1], self.slot_hands[slot_2]),
+            Move::Transfer { slot, to_hand, .. } => (self.slot_hands[slot], to_hand),
+        };
+        let old_hand_scores = (self.hand_scores[hand_1], self.hand_scores[hand_2]);
+        let old_hand_clashes = (self.hand_clashes[hand_1], self.hand_clashes[hand_2]);
+        let reverse = self.apply_move(mv);
+        self.rescore_hand(ctx, hand_1);
+        self.rescore_hand(ctx, hand_2);
+        MadeMove {
+            reverse,
+            hands: (hand_1, hand_2),
+            old_hand_scores,
+            old_hand_clashes,
+        }
+    }
+
+    /// Revert a [`MadeMove`], which must be the last one made.
+    fn undo(&mut self, made_move: MadeMove) {
+        self.apply_move(made_move.reverse);
+        let (hand_1, hand_2) = made_move.hands;
+        (self.hand_scores[hand_1], self.hand_scores[hand_2]) = made_move.old_hand_scores;
+        (self.hand_clashes[hand_1], self.hand_clashes[hand_2]) = made_move.old_hand_clashes;
+    }
+
+    /// Move the whackers for a [`Move`] (without updating the cached scores), returning the
+    /// [`Move`] which reverses it.
+    fn apply_move(&mut self, mv: Move) -> Move {
+        match mv {
+            Move::Swap(slot_1, slot_2) => {
+                self.swap_whackers(slot_1, slot_2);
+                mv // Swaps reverse themselves
+            }
+            Move::Transfer {
+                slot,
+                to_hand,
+                offset,
+            } => {
+                let from_hand = self.slot_hands[slot];
+                let from_offset = slot - self.hands[from_hand].start;
+                // Take the whacker out of its hand, shifting the later hands down one slot...
+                let whacker = self.whackers.remove(slot);
+                self.hands[from_hand].end -= 1;
+                for range in &mut self.hands[from_hand + 1..] {
+                    *range = range.start - 1..range.end - 1;
+                }
+                // ...then put it into the other hand, shifting the later hands back up
+                self.whackers
+                    .insert(self.hands[to_hand].start + offset, whacker);
+                self.hands[to_hand].end += 1;
+                for range in &mut self.hands[to_hand + 1..] {
+                    *range = range.start + 1..range.end + 1;
+                }
+                self.update_positions();
+                Move::Transfer {
+                    slot: self.hands[to_hand].start + offset,
+                    to_hand: from_hand,
+                    offset: from_offset,
+                }
+            }
+        }
+    }
+
+    fn swap_whackers(&mut self, slot_1: usize, slot_2: usize) {
+        self.whackers.swap(slot_1, slot_2);
+        self.positions[self.whackers[slot_1]] = slot_1;
+        self.positions[self.whackers[slot_2]] = slot_2;
+    }
+
+    /// Recompute the cached score and clashes of one hand.
+    fn rescore_hand(&mut self, ctx: &SearchContext, hand_idx: usize) {
+        let whackers_in_hand = &self.whackers[self.hands[hand_idx].clone()];
+        self.hand_scores[hand_idx] = score_for_hand(whackers_in_hand, ctx);
+        self.hand_clashes[hand_idx] = (whackers_in_hand.iter().tuple_combinations())
+            .filter(|(a, b)| ctx.clash_matrix[**a][**b])
+            .count();
+    }
+
+    /// The index of the hand which holds `whacker`.
+    fn hand_of(&self, whacker: WhackerIdx) -> usize {
+        self.slot_hands[self.positions[whacker]]
+    }
+
+    /// Does this assignment break the given [`ResolvedConstraint`]?
+    fn breaks(&self, constraint: &ResolvedConstraint) -> bool {
+        match constraint {
+            ResolvedConstraint::Hands { whacker, allowed } => !allowed[self.hand_of(*whacker)],
+            ResolvedConstraint::Pair { a, b, together } => {
+                (self.hand_of(*a) == self.hand_of(*b)) != *together
+            }
+        }
+    }
+
+    /// The [`Constraint`]s which this assignment doesn't follow.
+    fn broken_constraints<'c>(&self, constraints: &'c HandConstraints) -> Vec<&'c Constraint> {
+        (constraints.constraints.iter())
+            .filter(|(_, resolved)| self.breaks(resolved))
+            .map(|(constraint, _)| constraint)
+            .collect()
+    }
+
+    fn num_broken_constraints(&self, constraints: &HandConstraints) -> usize {
+        (constraints.constraints.iter())
+            .filter(|(_, resolved)| self.breaks(resolved))
+            .count()
+    }
+
+    /// How good this assignment is, for comparing assignments during the search.  Assignments
+    /// where a hand has to play two notes at once (or which break a [`Constraint`]) are
+    /// infeasible, so removing those problems always comes before improving the score.
+    fn quality(&self, ctx: &SearchContext) -> (Reverse<usize>, OrderedFloat<f64>) {
+        let num_problems = self.num_clashes() + self.num_broken_constraints(ctx.constraints);
+        (Reverse(num_problems), OrderedFloat(self.score()))
+    }
+
+    /// The number of pairs of whackers which are held in the same hand but played at once.
+    fn num_clashes(&self) -> usize {
+        self.hand_clashes.iter().sum()
+    }
+
+    /// Describe the clashing whackers in each hand, for reporting that no feasible assignment
+    /// was found.
+    fn describe_clashes(&self, ctx: &SearchContext, roster: &[Player]) -> String {
+        const MAX_CHORDS_PER_PAIR: usize = 3;
+
+        let mut description = String::new();
+        for (player, hands) in roster.iter().zip_eq(self.hands.chunks(2)) {
+            for (hand_name, range) in ["left", "right"].into_iter().zip_eq(hands) {
+                let instruments = (self.whackers[range.clone()].iter())
+                    .map(|idx| ctx.instruments[*idx].clone())
+                    .collect_vec();
+                for (a, b, chords) in ctx.clashes.in_hand(&instruments) {
+                    let chord_list = chords.iter().take(MAX_CHORDS_PER_PAIR).join(", ");
+                    write!(
+                        description,
+                        "  {}'s {hand_name} hand plays {} and {} together at {chord_list}",
+                        player.name,
+                        a.name(),
+                        b.name()
+                    )
+                    .unwrap();
+                    if chords.len() > MAX_CHORDS_PER_PAIR {
+                        let num_more = chords.len() - MAX_CHORDS_PER_PAIR;
+                        write!(description, " and {num_more} more").unwrap();
+                    }
+                    description.push('\n');
+                }
+            }
+        }
+        description
+    }
+
+    fn score(&self) -> f64 {
+        self.hand_scores.iter().sum()
     }
 }
 
-/// Given the [`Note`]s of the whackers played by each hand of a player, compute the score
-/// generated from that player having to swap which whacker they hold in each hand.  All swaps
-/// contribute negative score, and this score is weighted by (the inverse of) how long the swap
-/// requires.
-fn score_for_player(left_hand: &[Note], right_hand: &[Note], music: &MusicXmlScore) -> f64 {
-    score_for_hand(left_hand, music) + score_for_hand(right_hand, music)
+/// The most whackers which each hand can hold, with hands numbered like in [`HandConstraints`].
+fn hand_capacities(roster: &[Player]) -> Vec<usize> {
+    roster
+        .iter()
+        .flat_map(|player| {
+            let (left, right) = player.hand_capacities();
+            [left, right]
+        })
+        .collect_vec()
 }
 
-/// Given a set of [`Whacker`]s which need to be played by a single hand, compute the score
+/// Determine how many whackers each player's `(left, right)` hands start the search with.  The
+/// whackers are spread as evenly as possible, except that no hand is given more than its
+/// `capacities`.  The search then moves whackers between hands, so the final sizes can be
+/// uneven if that's better.
+fn hand_sizes(num_whackers: usize, capacities: &[usize]) -> anyhow::Result<Vec<(usize, usize)>> {
+    let total_capacity = capacities.iter().fold(0usize, |a, b| a.saturating_add(*b));
+    anyhow::ensure!(
+        total_capacity >= num_whackers,
+        "The score needs {num_whackers} boomwhackers, but the roster can only hold {total_capacity}"
+    );
+    // Give each whacker to the emptiest hand which still has room for it
+    let mut sizes = vec![0; capacities.len()];
+    for _ in 0..num_whackers {
+        let hand_idx = (0..sizes.len())
+            .filter(|idx| sizes[*idx] < capacities[*idx])
+            .min_by_key(|idx| sizes[*idx])
+            .unwrap(); // Can't panic, because there's enough capacity for every whacker
+        sizes[hand_idx] += 1;
+    }
+    Ok(sizes.into_iter().tuples().collect_vec())
+}
+
+/// Given a set of whackers which need to be played by a single hand, compute the score
 /// generated from the swaps.  All swaps contribute negative score, and this score is weighted
 /// by how long the swap requires.
-fn score_for_hand(whackers_in_hand: &[Note], music: &MusicXmlScore) -> f64 {
-    if whackers_in_hand.len() <= 1 {
-        return 0.0; // Any hand with 0 or 1 whackers doesn't need any swaps
+fn score_for_hand(whackers_in_hand: &[WhackerIdx], ctx: &SearchContext) -> f64 {
+    match whackers_in_hand {
+        [] | [_] => 0.0, // Any hand with 0 or 1 whackers doesn't need any swaps
+        [a, b] => ctx.pair_scores[*a][*b],
+        _ => swap_score(whackers_in_hand, ctx),
     }
+}
 
+/// Compute the score of a hand holding at least two whackers, by replaying every whack played
+/// by that hand (see [`score_for_hand`]).
+fn swap_score(whackers_in_hand: &[WhackerIdx], ctx: &SearchContext) -> f64 {
+    let whacks = &ctx.events;
     let mut score = 0.0;
 
     // If there are at least two whackers that have to be played by this hand, then we need to
@@ -199,22 +1131,22 @@ fn score_for_hand(whackers_in_hand: &[Note], music: &MusicXmlScore) -> f64 {
 
     let mut whack_iterators = whackers_in_hand
         .iter()
-        .map(|note| music.whacks[note].iter().peekable())
+        .map(|idx| whacks[*idx].iter().peekable())
         .collect_vec();
 
     // Find the whacker with the first time, and assume the player starts holding that whacker
     let mut last_played_iter_idx = whackers_in_hand
         .iter()
-        .position_min_by_key(|idx| music.whacks[*idx][0])
-        .unwrap(); // Can't panic because early return guarantees >1 whacker
-    let mut last_whack_time = Timestamp::ZERO;
+        .position_min_by_key(|idx| whacks[**idx][0].onset)
+        .unwrap(); // Can't panic because there are always at least 2 whackers
+    let mut hand_free_time = Timestamp::ZERO;
     loop {
         // Determine which boomwhacker is the next to play
         let mut best_next_time = Timestamp::MAX;
         let mut next_iter_idx = None;
         for (iter_idx, whack) in whack_iterators.iter_mut().enumerate() {
             if let Some(next_whack) = whack.peek() {
-                let next_time = next_whack.timestamp;
+                let next_time = next_whack.onset;
                 if next_time < best_next_time {
                     best_next_time = next_time;
                     next_iter_idx = Some(iter_idx);
@@ -227,14 +1159,12 @@ fn score_for_hand(whackers_in_hand: &[Note], music: &MusicXmlScore) -> f64 {
         };
 
         // Consume the next hit from the corresponding iterator
-        assert_eq!(
-            whack_iterators[next_iter_idx].next().map(|w| w.timestamp),
-            Some(best_next_time)
-        );
+        let next_whack = whack_iterators[next_iter_idx].next().unwrap();
+        assert_eq!(next_whack.onset, best_next_time);
 
         // Update score if this hit requires us to switch boomwhackers
         if last_played_iter_idx != next_iter_idx {
-            let mut time_diff = last_whack_time.secs_until(best_next_time);
+            let mut time_diff = hand_free_time.secs_until(best_next_time) - ctx.reaction_secs;
             if time_diff < 0.01 {
                 time_diff = 0.01;
             }
@@ -243,9 +1173,415 @@ fn score_for_hand(whackers_in_hand: &[Note], music: &MusicXmlScore) -> f64 {
             // much less relevant once we have a few seconds for the switch.
             score -= 1.0 / time_diff;
         }
-        last_whack_time = best_next_time;
+        // The hand is busy until the end of sustained notes, and the next swap can only start
+        // once it's free
+        hand_free_time = if next_iter_idx == last_played_iter_idx {
+            hand_free_time.max(next_whack.hand_free_at())
+        } else {
+            next_whack.hand_free_at()
+        };
         last_played_iter_idx = next_iter_idx;
     }
 
     score
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::score::Articulations;
+
+    /// A score which only exists in memory, so that the search can be tested without loading
+    /// any files.
+    struct TestScore {
+        events: Vec<Event>,
+    }
+
+    impl TestScore {
+        /// Create a score from a list of `(onset, notes)` chords, where each note is held for
+        /// half a second.
+        fn new(chords: &[(f64, &[&str])]) -> Self {
+            let mut events = Vec::new();
+            for (onset, notes) in chords {
+                let chord_note_idx = events.len();
+                for note in *notes {
+                    events.push(Event {
+                        onset: Timestamp::from_secs(*onset),
+                        release: Timestamp::from_secs(onset + 0.5),
+                        instrument: whacker(note),
+                        note_idx: events.len(),
+                        chord_note_idx,
+                        articulations: Articulations::default(),
+                    });
+                }
+            }
+            events.sort();
+            Self { events }
+        }
+    }
+
+    impl Score for TestScore {
+        fn events(&self) -> &[Event] {
+            &self.events
+        }
+
+        fn player_file(&self, _: &[Instrument], _: &[Instrument]) -> (&'static str, Vec<u8>) {
+            ("txt", Vec::new())
+        }
+    }
+
+    /// Search options which are enough for these small scores
+    fn quick_search() -> SearchOptions {
+        SearchOptions {
+            restarts: 10,
+            ..SearchOptions::default()
+        }
+    }
+
+    /// Parse an [`Instrument`], which is a boomwhacker unless it's given by name (like
+    /// `Shaker`)
+    fn whacker(name: &str) -> Instrument {
+        name.parse().unwrap()
+    }
+
+    fn roster_of(players: &[&str]) -> Vec<Player> {
+        players.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn hand_sizes_are_even() {
+        let roster = roster_of(&["A", "B"]);
+        assert_eq!(
+            hand_sizes(5, &hand_capacities(&roster)).unwrap(),
+            [(2, 1), (1, 1)]
+        );
+        assert_eq!(
+            hand_sizes(8, &hand_capacities(&roster)).unwrap(),
+            [(2, 2), (2, 2)]
+        );
+    }
+
+    #[test]
+    fn hand_sizes_follow_player_limits() {
+        let roster = roster_of(&["A:1", "B"]);
+        assert_eq!(
+            hand_sizes(5, &hand_capacities(&roster)).unwrap(),
+            [(1, 1), (2, 1)]
+        );
+        let roster = roster_of(&["A", "B:one-handed"]);
+        assert_eq!(
+            hand_sizes(4, &hand_capacities(&roster)).unwrap(),
+            [(2, 1), (0, 1)]
+        );
+        let roster = roster_of(&["A:1", "B:2:one-handed"]);
+        assert_eq!(
+            hand_sizes(4, &hand_capacities(&roster)).unwrap(),
+            [(1, 1), (0, 2)]
+        );
+        assert!(hand_sizes(5, &hand_capacities(&roster)).is_err());
+    }
+
+    #[test]
+    fn clashes() {
+        let score = TestScore::new(&[
+            (0.0, &["C4", "E4", "G4"]),
+            (1.0, &["C4"]),
+            (2.0, &["D4"]),
+            (2.0, &["F4"]), // Played at the same time, but in a different part
+            (3.0, &["C4", "E4"]),
+        ]);
+        let clashes = Clashes::new(&score.whacks());
+        let chords = |a: &str, b: &str| {
+            let chords = clashes
+                .between(&whacker(a), &whacker(b))
+                .unwrap_or_default();
+            chords
+                .iter()
+                .map(|c| Timestamp::ZERO.secs_until(c.onset))
+                .collect_vec()
+        };
+        assert_eq!(chords("C4", "E4"), [0.0, 3.0]);
+        assert_eq!(chords("G4", "C4"), [0.0]);
+        assert_eq!(chords("D4", "F4"), [2.0]);
+        assert_eq!(chords("C4", "D4"), [] as [f64; 0]);
+        let (largest_chord, size) = clashes.largest_chord.unwrap();
+        assert_eq!((largest_chord.onset, size), (Timestamp::ZERO, 3));
+        let hand = [whacker("C4"), whacker("D4"), whacker("E4"), whacker("G4")];
+        assert_eq!(clashes.in_hand(&hand).count(), 3);
+    }
+
+    #[test]
+    fn search_avoids_clashes() {
+        let score = TestScore::new(&[
+            (0.0, &["C4", "E4"]),
+            (1.0, &["D4", "F4"]),
+            (2.0, &["C4", "F4"]),
+        ]);
+        let roster = roster_of(&["A:2:one-handed", "B:2:one-handed"]);
+        let assignment = Assignment::search(&score, &roster, &[], &quick_search()).unwrap();
+        let mut hands = assignment
+            .players
+            .iter()
+            .map(|(_, right)| right)
+            .collect_vec();
+        hands.sort();
+        assert_eq!(
+            hands,
+            [
+                &[whacker("C4"), whacker("D4")],
+                &[whacker("E4"), whacker("F4")]
+            ]
+        );
+        // Chords can't have more notes than there are hands
+        let roster = roster_of(&["A:one-handed"]);
+        let error = Assignment::search(&score, &roster, &[], &quick_search());
+        assert!(error.unwrap_err().to_string().contains("needs 2 hands"));
+    }
+
+    #[test]
+    fn constraint_resolution() {
+        let score = TestScore::new(&[(0.0, &["C4", "D4", "E4", "F4", "G4"])]);
+        let instruments = score.whacks().keys().cloned().sorted().collect();
+        let roster = roster_of(&["A", "B", "C:one-handed"]);
+        let hand_capacities = hand_capacities(&roster);
+        let resolve = |constraints: &[&str]| {
+            let constraints = (constraints.iter())
+                .map(|c| match c.split_once(' ') {
+                    Some(("pin", c)) => Constraint::pin(c),
+                    Some(("forbid", c)) => Constraint::forbid(c),
+                    Some(("together", c)) => Constraint::together(c),
+                    Some(("apart", c)) => Constraint::apart(c),
+                    _ => unreachable!(),
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+                .unwrap();
+            HandConstraints::new(&constraints, &instruments, &roster, &hand_capacities)
+        };
+        let idx = |name: &str| {
+            let idx = instruments
+                .iter()
+                .position(|i| *i == whacker(name))
+                .unwrap();
+            WhackerIdx::from_usize(idx)
+        };
+
+        let constraints = resolve(&["pin C4=B:left", "forbid D4=A", "pin E4=C"]).unwrap();
+        let allowed = |name| constraints.allowed_hands[idx(name)].clone();
+        assert_eq!(
+            allowed("C4"),
+            Some(vec![false, false, true, false, false, false])
+        );
+        assert_eq!(
+            allowed("D4"),
+            Some(vec![false, false, true, true, false, true])
+        );
+        // Player C has no left hand
+        assert_eq!(
+            allowed("E4"),
+            Some(vec![false, false, false, false, false, true])
+        );
+        assert_eq!(allowed("F4"), None);
+        assert!(constraints.allows(idx("F4"), 4));
+
+        let constraints = resolve(&["together C4,D4", "apart D4,E4"]).unwrap();
+        assert!(matches!(
+            constraints.constraints[..],
+            [
+                (_, ResolvedConstraint::Pair { together: true, .. }),
+                (
+                    _,
+                    ResolvedConstraint::Pair {
+                        together: false,
+                        ..
+                    }
+                ),
+            ]
+        ));
+
+        let error = |constraints: &[&str]| resolve(constraints).unwrap_err().to_string();
+        assert_eq!(error(&["pin A4=A"]), "The score doesn't play A4");
+        assert_eq!(error(&["forbid C4=Z"]), "No player is called \"Z\"");
+        assert_eq!(
+            error(&["pin C4=A", "forbid C4=A"]),
+            "The constraints don't leave any hand which can play C4"
+        );
+    }
+
+    #[test]
+    fn search_follows_constraints() {
+        let score = TestScore::new(&[
+            (0.0, &["C4"]),
+            (1.0, &["D4"]),
+            (2.0, &["E4"]),
+            (3.0, &["F4"]),
+        ]);
+        // Only player A's left hand has room for two whackers
+        let roster = roster_of(&["A", "B:one-handed"]);
+        let constraints = [
+            Constraint::pin("C4=A:right").unwrap(),
+            Constraint::together("D4,F4").unwrap(),
+        ];
+        let assignment =
+            Assignment::search(&score, &roster, &constraints, &quick_search()).unwrap();
+        assert_eq!(
+            assignment.players,
+            [
+                (vec![whacker("D4"), whacker("F4")], vec![whacker("C4")]),
+                (vec![], vec![whacker("E4")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn constraints_on_unpitched_and_microtonal_instruments() {
+        let mut score = TestScore::new(&[(0.0, &["Shaker"]), (1.0, &["C4"]), (2.0, &["D4"])]);
+        score.events[2].instrument = "C4+50¢".parse().unwrap();
+        let roster = roster_of(&["A", "B"]);
+        let constraints = [
+            Constraint::pin("Shaker=B:left").unwrap(),
+            Constraint::forbid("C4+50=B").unwrap(),
+        ];
+        let assignment =
+            Assignment::search(&score, &roster, &constraints, &quick_search()).unwrap();
+        assert_eq!(assignment.players[1].0, [whacker("Shaker")]);
+        assert!(assignment.players[1].1.is_empty());
+        let (left, right) = &assignment.players[0];
+        assert!(left.contains(&whacker("C4+50¢")) || right.contains(&whacker("C4+50¢")));
+    }
+
+    #[test]
+    fn search_reports_broken_constraints() {
+        // C4 and D4 are played together, so they can never be in the same hand
+        let score = TestScore::new(&[(0.0, &["C4", "D4"]), (1.0, &["E4"])]);
+        let roster = roster_of(&["A", "B"]);
+        let constraints = [Constraint::together("C4,D4").unwrap()];
+        let error = Assignment::search(&score, &roster, &constraints, &quick_search());
+        let error = error.unwrap_err().to_string();
+        assert!(error.contains("C4 and D4 must be in the same hand (broken)"));
+        // A one-handed player can't keep two whackers apart
+        let roster = roster_of(&["A:one-handed"]);
+        let constraints = [Constraint::apart("C4,E4").unwrap()];
+        let score = TestScore::new(&[(0.0, &["C4"]), (1.0, &["E4"])]);
+        let error = Assignment::search(&score, &roster, &constraints, &quick_search());
+        let error = error.unwrap_err().to_string();
+        assert!(error.contains("C4 and E4 must be in different hands (broken)"));
+    }
+
+    #[test]
+    fn search_with_one_hand_in_use() {
+        let score = TestScore::new(&[(0.0, &["C4"]), (1.0, &["C4"])]);
+        let roster = roster_of(&["A", "B"]);
+        let assignment = Assignment::search(&score, &roster, &[], &quick_search()).unwrap();
+        assert_eq!(assignment.score, 0.0);
+        let score = TestScore::new(&[(0.0, &["C4"]), (1.0, &["D4"])]);
+        let roster = roster_of(&["A:one-handed"]);
+        let assignment = Assignment::search(&score, &roster, &[], &quick_search()).unwrap();
+        assert_eq!(
+            assignment.players,
+            [(vec![], vec![whacker("C4"), whacker("D4")])]
+        );
+    }
+
+    #[test]
+    fn cached_scores_match_full_rescore() {
+        let notes = [
+            "C4", "D4", "E4", "F4", "G4", "A4", "B4", "C5", "D5", "E5", "F5", "G5",
+        ];
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0);
+        let chords = (0..300)
+            .map(|idx| {
+                let chord_size = rng.gen_range(1..=2);
+                let chord = notes.choose_multiple(&mut rng, chord_size);
+                (idx as f64 * 0.25, chord.copied().collect_vec())
+            })
+            .collect_vec();
+        let chords = (chords.iter())
+            .map(|(onset, notes)| (*onset, notes.as_slice()))
+            .collect_vec();
+        let score = TestScore::new(&chords);
+        // Hands hold between one and three whackers, so every way of scoring a hand is used
+        let roster = roster_of(&["A", "B", "C:1"]);
+        let whacks = score.whacks();
+        let hand_capacities = hand_capacities(&roster);
+        let hand_sizes = hand_sizes(whacks.len(), &hand_capacities).unwrap();
+        let clashes = Clashes::new(&whacks);
+        let instruments = whacks.keys().cloned().sorted().collect();
+        let constraints =
+            HandConstraints::new(&[], &instruments, &roster, &hand_capacities).unwrap();
+        let ctx = SearchContext::new(
+            &whacks,
+            instruments,
+            hand_capacities,
+            0.1,
+            &clashes,
+            &constraints,
+        );
+
+        let mut assignment = FastAssignment::random(&ctx, &hand_sizes, &mut rng);
+        let mut num_transfers = 0;
+        for _ in 0..1_000 {
+            let mv = assignment.random_move(&ctx, &mut rng).unwrap();
+            num_transfers += matches!(mv, Move::Transfer { .. }) as usize;
+            let old_assignment = assignment.clone();
+            let made_move = assignment.make_move(&ctx, mv);
+            if rng.gen_bool(0.5) {
+                assignment.undo(made_move);
+                assert_eq!(assignment.whackers, old_assignment.whackers);
+                assert_eq!(assignment.hands, old_assignment.hands);
+            }
+            for (range, capacity) in assignment.hands.iter().zip_eq(&ctx.hand_capacities) {
+                assert!(range.len() <= *capacity);
+            }
+            let rescored =
+                FastAssignment::new(&ctx, assignment.whackers.clone(), assignment.hands.clone());
+            assert_eq!(assignment.slot_hands, rescored.slot_hands);
+            assert_eq!(assignment.positions, rescored.positions);
+            assert_eq!(assignment.hand_scores, rescored.hand_scores);
+            assert_eq!(assignment.hand_clashes, rescored.hand_clashes);
+            // Hands with two whackers are scored from `pair_scores`, which must match the
+            // full scoring
+            for range in &assignment.hands {
+                let whackers_in_hand = &assignment.whackers[range.clone()];
+                if whackers_in_hand.len() >= 2 {
+                    let score = score_for_hand(whackers_in_hand, &ctx);
+                    assert_eq!(score, swap_score(whackers_in_hand, &ctx));
+                }
+            }
+        }
+        assert_eq!(
+            assignment.hand_scores.iter().filter(|s| **s != 0.0).count(),
+            4
+        );
+        assert!(num_transfers > 0);
+    }
+
+    #[test]
+    fn search_changes_hand_sizes() {
+        // D4 is played so often that it needs a hand of its own, whereas the other whackers are
+        // rarely played and can share a hand.  So the best assignment isn't an even split.
+        let mut chords = (0..32)
+            .map(|idx| (idx as f64 * 0.25, ["D4"].as_slice()))
+            .collect_vec();
+        for (idx, note) in ["C4", "E4", "F4", "C4", "E4", "F4"].iter().enumerate() {
+            chords.push((idx as f64 * 1.5 + 0.1, std::slice::from_ref(note)));
+        }
+        let score = TestScore::new(&chords);
+        let roster = roster_of(&["A"]);
+        assert_eq!(hand_sizes(4, &hand_capacities(&roster)).unwrap(), [(2, 2)]);
+        let assignment = Assignment::search(&score, &roster, &[], &quick_search()).unwrap();
+        assert_eq!(
+            assignment.players,
+            [(
+                vec![whacker("C4"), whacker("E4"), whacker("F4")],
+                vec![whacker("D4")]
+            )]
+        );
+        // Capacities still limit the sizes
+        let roster = roster_of(&["A:2"]);
+        let assignment = Assignment::search(&score, &roster, &[], &quick_search()).unwrap();
+        assert!(assignment
+            .players
+            .iter()
+            .all(|(l, r)| l.len() <= 2 && r.len() <= 2));
+    }
+}
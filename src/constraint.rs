@@ -0,0 +1,114 @@
+//! Constraints on which players may be given which boomwhackers.
+
+use crate::{instrument::Instrument, music_xml::Hand};
+
+/// A rule which every [`Assignment`](crate::assign::Assignment) has to follow.  Players are
+/// referred to by their [`name`](crate::roster::Player::name), and instruments are written as
+/// they're named (see [`Instrument`]'s `FromStr` implementation), so unpitched and microtonal
+/// instruments can be constrained as well as boomwhackers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Constraint {
+    /// The `instrument` must be given to `player` (in the given `hand`, if there is one)
+    Pin {
+        instrument: Instrument,
+        player: String,
+        hand: Option<Hand>,
+    },
+    /// The `instrument` mustn't be given to `player`
+    Forbid {
+        instrument: Instrument,
+        player: String,
+    },
+    /// The two instruments must be held in the same hand
+    Together(Instrument, Instrument),
+    /// The two instruments must be held in different hands
+    Apart(Instrument, Instrument),
+}
+
+impl Constraint {
+    /// Parse a pin written as `<instrument>=<player>[:left|:right]`, e.g. `C4=Sam`,
+    /// `D4=Sam:left` or `Shaker=Sam`.
+    pub fn pin(s: &str) -> anyhow::Result<Self> {
+        let (instrument, player) = split_instrument_and_player(s)?;
+        let (player, hand) = match player.rsplit_once(':') {
+            Some((player, "left")) => (player, Some(Hand::Left)),
+            Some((player, "right")) => (player, Some(Hand::Right)),
+            _ => (player, None),
+        };
+        Ok(Self::Pin {
+            instrument,
+            player: player.to_owned(),
+            hand,
+        })
+    }
+
+    /// Parse a forbidden instrument written as `<instrument>=<player>`, e.g. `C2=Kim`.
+    pub fn forbid(s: &str) -> anyhow::Result<Self> {
+        let (instrument, player) = split_instrument_and_player(s)?;
+        Ok(Self::Forbid {
+            instrument,
+            player: player.to_owned(),
+        })
+    }
+
+    /// Parse a pair of instruments written as `<instrument>,<instrument>` (e.g. `C4,E4`) which
+    /// are kept together.
+    pub fn together(s: &str) -> anyhow::Result<Self> {
+        let (a, b) = split_instrument_pair(s)?;
+        Ok(Self::Together(a, b))
+    }
+
+    /// Parse a pair of instruments written as `<instrument>,<instrument>` (e.g. `C4,E4`) which
+    /// are kept apart.
+    pub fn apart(s: &str) -> anyhow::Result<Self> {
+        let (a, b) = split_instrument_pair(s)?;
+        Ok(Self::Apart(a, b))
+    }
+}
+
+fn split_instrument_and_player(s: &str) -> anyhow::Result<(Instrument, &str)> {
+    let (instrument, player) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected <instrument>=<player>, not {s:?}"))?;
+    Ok((instrument.parse()?, player.trim()))
+}
+
+fn split_instrument_pair(s: &str) -> anyhow::Result<(Instrument, Instrument)> {
+    let (a, b) = s
+        .split_once(',')
+        .ok_or_else(|| anyhow::anyhow!("Expected <instrument>,<instrument>, not {s:?}"))?;
+    Ok((a.parse()?, b.parse()?))
+}
+
+impl std::fmt::Display for Constraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constraint::Pin {
+                instrument,
+                player,
+                hand,
+            } => {
+                write!(f, "{} must be played by {player}", instrument.name())?;
+                match hand {
+                    Some(Hand::Left) => write!(f, "'s left hand"),
+                    Some(Hand::Right) => write!(f, "'s right hand"),
+                    None => Ok(()),
+                }
+            }
+            Constraint::Forbid { instrument, player } => {
+                write!(f, "{} mustn't be played by {player}", instrument.name())
+            }
+            Constraint::Together(a, b) => {
+                write!(f, "{} and {} must be in the same hand", a.name(), b.name())
+            }
+            Constraint::Apart(a, b) => {
+                write!(
+                    f,
+                    "{} and {} must be in different hands",
+                    a.name(),
+                    b.name()
+                )
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Problems found while loading a score, along with where in the score they were found.
+
+use std::fmt::{Display, Formatter};
+
+use crate::music_xml::MicrotonePolicy;
+
+/// A problem with a specific part of a score.  When loading strictly, the first `Diagnostic` is
+/// returned as an error.  When loading leniently (see
+/// [`LoadOptions::lenient`](crate::music_xml::LoadOptions::lenient)), the offending element is
+/// skipped and the `Diagnostic` is kept as a warning instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub location: Location,
+    pub reason: Reason,
+}
+
+/// Where a [`Diagnostic`] was found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    /// The `id` of the `<part>` (if the problem is inside a part)
+    pub part_id: Option<String>,
+    /// The `number` attribute of the `<measure>` (which isn't necessarily its index, e.g. for
+    /// pickup measures)
+    pub measure_number: Option<String>,
+    /// The `note_idx` of the note (see [`Event::note_idx`](crate::score::Event::note_idx)), if
+    /// the problem is with a pitched or unpitched note
+    pub note_idx: Option<usize>,
+    /// The path to the offending element, like `part[@id="P1"]/measure[3]/note[2]`.  Indices
+    /// are 1-based, and count only the siblings with the same tag.
+    pub path: String,
+}
+
+/// What's wrong with the element at a [`Location`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    /// Something which is valid, but which we can't load (e.g. a `<note>` which is neither
+    /// pitched, unpitched nor a rest)
+    Unsupported(String),
+    /// A `<note>`, `<backup>` or `<forward>` without a valid `<duration>`
+    MissingDuration,
+    /// A part which never specifies its `<divisions>`
+    MissingDivisions,
+    /// An `<alter>` which isn't a number
+    BadAlter(String),
+    /// An `<alter>` which isn't a whole number of semitones, and how the note was handled
+    MicrotonalAlter(String, MicrotonePolicy),
+    BadOctave(String),
+    /// A note which sounds (after its `<alter>` and any transposition) too far from C0 to be
+    /// played, given as the number of semitones above C0
+    PitchOutOfRange(i32),
+    BadStep(String),
+    BadStaff(String),
+    BadVoice(String),
+    /// A `<backup>` which moves before the start of the measure
+    BackupTooLong,
+    /// A `<chord/>` note with no earlier note to share a chord with
+    ChordWithoutStart,
+    /// A tempo mark which can't be read
+    BadTempo(String),
+    /// A `<swing>` whose ratio or `<swing-type>` can't be read
+    BadSwing(String),
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)?;
+        let location = &self.location;
+        if let Some(number) = &location.measure_number {
+            write!(f, " in measure {number}")?;
+        }
+        if let Some(part_id) = &location.part_id {
+            write!(f, " of part {part_id}")?;
+        }
+        if let Some(note_idx) = location.note_idx {
+            write!(f, " (note #{note_idx})")?;
+        }
+        if !location.path.is_empty() {
+            write!(f, " at {}", location.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Reason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::Unsupported(construct) => write!(f, "Unsupported {construct}"),
+            Reason::MissingDuration => write!(f, "Missing or invalid <duration>"),
+            Reason::MissingDivisions => write!(f, "Part never specifies its <divisions>"),
+            Reason::BadAlter(alter) => write!(f, "Invalid <alter> {alter:?}"),
+            Reason::MicrotonalAlter(alter, policy) => {
+                write!(f, "<alter> {alter:?} isn't a whole number of semitones")?;
+                match policy {
+                    MicrotonePolicy::Round => write!(f, ", so was rounded to the nearest semitone"),
+                    MicrotonePolicy::Reject => Ok(()),
+                    MicrotonePolicy::Distinct => write!(f, ", so is played on its own boomwhacker"),
+                }
+            }
+            Reason::BadOctave(octave) => write!(f, "Invalid <octave> {octave:?}"),
+            Reason::PitchOutOfRange(semis) => {
+                write!(
+                    f,
+                    "Note sounds {semis} semitones above C0, which is out of range"
+                )
+            }
+            Reason::BadStep(step) => write!(f, "Invalid <step> {step:?}"),
+            Reason::BadStaff(staff) => write!(f, "Invalid <staff> {staff:?}"),
+            Reason::BadVoice(voice) => write!(f, "Invalid <voice> {voice:?}"),
+            Reason::BackupTooLong => write!(f, "<backup> moves before the start of the measure"),
+            Reason::ChordWithoutStart => write!(f, "<chord/> note doesn't follow another note"),
+            Reason::BadTempo(tempo) => write!(f, "Invalid tempo {tempo:?}"),
+            Reason::BadSwing(ratio) => write!(f, "Invalid <swing> {ratio:?}"),
+        }
+    }
+}
+
+impl std::error::Error for Diagnostic {}
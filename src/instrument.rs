@@ -0,0 +1,86 @@
+use std::{
+    fmt::{Debug, Display, Formatter},
+    rc::Rc,
+    str::FromStr,
+};
+
+use crate::note::Note;
+
+/// Something which a player holds in one hand and plays: either a boomwhacker or an unpitched
+/// percussion instrument (e.g. a shaker or a tambourine).
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Instrument {
+    /// A boomwhacker, tuned to the given [`Note`]
+    Whacker(Note),
+    /// A boomwhacker tuned `cents` above a [`Note`] (between it and the next semitone).  These
+    /// are only created for microtonal notes when loading with
+    /// [`MicrotonePolicy::Distinct`](crate::music_xml::MicrotonePolicy::Distinct).
+    Microtonal { note: Note, cents: u8 },
+    /// An unpitched percussion instrument
+    Unpitched {
+        /// A key which uniquely identifies this instrument within the score
+        id: Rc<str>,
+        /// The name used to label this instrument in the annotated scores
+        name: Rc<str>,
+    },
+}
+
+impl Instrument {
+    pub fn name(&self) -> String {
+        match self {
+            Self::Whacker(note) => note.name(),
+            Self::Microtonal { note, cents } => format!("{}+{cents}¢", note.name()),
+            Self::Unpitched { name, .. } => name.to_string(),
+        }
+    }
+}
+
+impl FromStr for Instrument {
+    type Err = anyhow::Error;
+
+    /// Parse an instrument written like its [`name`](Self::name): a note like `C4`, a microtonal
+    /// note like `C4+50¢`, or otherwise the name of an unpitched instrument like `Shaker`.  The
+    /// `id` of an unpitched instrument isn't known until the score is loaded, so it's set to the
+    /// name and unpitched instruments should be compared by name.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        anyhow::ensure!(!s.is_empty(), "Expected an instrument name");
+        if let Some((note, cents)) = s.split_once('+') {
+            if let Ok(note) = note.parse::<Note>() {
+                let cents = (cents.trim_end_matches('¢').parse::<u8>().ok())
+                    .filter(|cents| (1..=99).contains(cents))
+                    .ok_or_else(|| anyhow::anyhow!("Invalid number of cents in {s:?}"))?;
+                return Ok(Self::Microtonal { note, cents });
+            }
+        }
+        Ok(match s.parse::<Note>() {
+            Ok(note) => Self::Whacker(note),
+            Err(_) => Self::Unpitched {
+                id: s.into(),
+                name: s.into(),
+            },
+        })
+    }
+}
+
+impl From<Note> for Instrument {
+    fn from(note: Note) -> Self {
+        Self::Whacker(note)
+    }
+}
+
+impl Display for Instrument {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>3}", self.name())
+    }
+}
+
+impl Debug for Instrument {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Whacker(note) => write!(f, "{note:?}"),
+            Self::Microtonal { .. } => write!(f, "Microtonal({})", self.name()),
+            Self::Unpitched { name, .. } => write!(f, "Unpitched({name})"),
+        }
+    }
+}
@@ -1,17 +1,97 @@
-use std::{path::PathBuf, time::Instant};
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 use itertools::Itertools;
 
-use crate::{assign::Assignment, music_xml::MusicXmlScore};
+use crate::{
+    assign::{Assignment, SearchOptions},
+    constraint::Constraint,
+    midi::{MidiScore, MIDI_MAGIC_BYTES},
+    musescore::MuseScore,
+    music_xml::{LoadOptions, MusicXmlScore},
+    roster::{Player, DEFAULT_NUM_PLAYERS},
+    score::Score,
+};
 
+mod abc;
 mod assign;
+mod constraint;
+mod diagnostic;
+mod instrument;
+mod midi;
+mod musescore;
 mod music_xml;
 mod note;
+mod repeats;
+mod roster;
+mod score;
+mod tempo;
 
 fn main() -> anyhow::Result<()> {
+    // Parse the command-line options, leaving the positional arguments in order
+    let mut options = LoadOptions::default();
+    let mut search_options = SearchOptions::default();
+    let mut roster = Vec::<Player>::new();
+    let mut constraints = Vec::<Constraint>::new();
+    let mut positional_args = Vec::new();
+    let mut raw_args = std::env::args().skip(1);
+    while let Some(arg) = raw_args.next() {
+        let mut option_value = || {
+            raw_args
+                .next()
+                .context(format!("Expected a value after {arg}"))
+        };
+        match arg.as_str() {
+            "--grace-lead-in" => {
+                let secs = option_value()?.parse::<f64>()?;
+                options.grace_note_lead_in = Duration::from_secs_f64(secs);
+            }
+            "--include-cues" => options.include_cue_notes = true,
+            "--part" => options.parts.push(option_value()?.parse()?),
+            "--channel" => {
+                let channel = option_value()?.parse::<u8>()?;
+                anyhow::ensure!(
+                    (1..=16).contains(&channel),
+                    "MIDI channels are numbered from 1 to 16, not {channel}"
+                );
+                options.midi_channels.push(channel);
+            }
+            "--lenient" => options.lenient = true,
+            "--microtones" => options.microtones = option_value()?.parse()?,
+            "--apply-octave-shifts" => options.apply_octave_shifts = true,
+            "--from" => options.excerpt_start = Some(option_value()?.parse()?),
+            "--to" => options.excerpt_end = Some(option_value()?.parse()?),
+            "--players" => {
+                let num_players = option_value()?.parse::<usize>()?;
+                let first_idx = roster.len();
+                roster.extend((first_idx..first_idx + num_players).map(Player::numbered));
+            }
+            "--player" => roster.push(option_value()?.parse()?),
+            "--pin" => constraints.push(Constraint::pin(&option_value()?)?),
+            "--forbid" => constraints.push(Constraint::forbid(&option_value()?)?),
+            "--together" => constraints.push(Constraint::together(&option_value()?)?),
+            "--apart" => constraints.push(Constraint::apart(&option_value()?)?),
+            "--timing" => options.timing = option_value()?.parse()?,
+            "--reaction-time" => {
+                let secs = option_value()?.parse::<f64>()?;
+                search_options.reaction_time = Duration::from_secs_f64(secs);
+            }
+            "--strategy" => search_options.strategy = option_value()?.parse()?,
+            "--restarts" => search_options.restarts = option_value()?.parse()?,
+            "--iterations" => search_options.iterations = option_value()?.parse()?,
+            "--seed" => search_options.seed = option_value()?.parse()?,
+            _ if arg.starts_with("--") => anyhow::bail!("Unknown option {arg}"),
+            _ => positional_args.push(arg),
+        }
+    }
+
     // Get the input file path
-    let mut args = std::env::args().skip(1);
+    let mut args = positional_args.into_iter();
     let input_file_path: PathBuf = args
         .next()
         .expect("Expected first arg to be the file-name")
@@ -20,25 +100,35 @@ fn main() -> anyhow::Result<()> {
         .next()
         .expect("Expected second arg to be output dir")
         .into();
-    // Load the MusicXML file and extract the whacks
-    let score = MusicXmlScore::load_file(input_file_path)?;
+    // Load the score and extract the whacks
+    let score = load_score(&input_file_path, &options)?;
+    for warning in score.warnings() {
+        eprintln!("Warning: {warning}");
+    }
+    let whacks = score.whacks();
+    anyhow::ensure!(
+        !whacks.is_empty(),
+        "The score doesn't contain any notes to play"
+    );
 
     // Print the whack times
-    for (whacker, times) in score.whacks.iter().sorted_by_key(|(w, _)| *w) {
+    for (whacker, times) in whacks.iter().sorted_by_key(|(w, _)| *w) {
         println!(
             "{:>3}: {:.2?}",
             whacker.name(),
-            times.into_iter().map(|w| w.timestamp).collect_vec()
+            times.iter().map(|event| event.onset).collect_vec()
         );
     }
-    println!("{} boomwhackers required", score.whacks.len());
+    println!("{} instruments required", whacks.len());
     println!();
 
-    let num_players = 7; // TODO: Make number of players no longer hard-coded
+    if roster.is_empty() {
+        roster = (0..DEFAULT_NUM_PLAYERS).map(Player::numbered).collect();
+    }
 
-    // Start searching for good assignments (for seven players)
+    // Start searching for good assignments
     let search_start = Instant::now();
-    let assignment = Assignment::search(&score, num_players, 0);
+    let assignment = Assignment::search(score.as_ref(), &roster, &constraints, &search_options)?;
     assignment.print();
     println!(
         "Found best score of {:.3} in {:.2?}",
@@ -46,25 +136,51 @@ fn main() -> anyhow::Result<()> {
         search_start.elapsed()
     );
 
-    // Construct musicXML files for each player
+    // Construct score files for each player
+    let mut player_paths = Vec::new();
     for (idx, (left_hand, right_hand)) in assignment.players.iter().enumerate() {
-        let music_xml_path = output_dir.join(&format!("player-{idx}.musicxml"));
-        let xml = score.annotated_xml(left_hand, right_hand);
-        std::fs::write(&music_xml_path, xml.as_bytes())?;
+        let (extension, bytes) = score.player_file(left_hand, right_hand);
+        let player_path = output_dir.join(format!("player-{idx}.{extension}"));
+        std::fs::write(&player_path, bytes)?;
+        player_paths.push(player_path);
     }
     // Create a JSON file with instructions for musescore's bulk conversion
     let mut conversion_jobs = Vec::new();
-    let mut pdf_paths = Vec::new();
-    for player_num in 0..num_players {
-        let music_xml_path = output_dir.join(&format!("player-{player_num}.musicxml"));
-        let pdf_path = output_dir.join(&format!("player-{player_num}.pdf"));
+    for (player_num, player_path) in player_paths.iter().enumerate() {
+        let pdf_path = output_dir.join(format!("player-{player_num}.pdf"));
         conversion_jobs.push(format!(
-            r#"{{ "in": {music_xml_path:?}, "out": {pdf_path:?} }}"#
+            r#"{{ "in": {player_path:?}, "out": {pdf_path:?} }}"#
         ));
-        pdf_paths.push(pdf_path);
     }
     let jobs_json = format!("[\n  {}\n]", conversion_jobs.iter().join(",\n  "));
-    std::fs::write(&output_dir.join("jobs.json"), jobs_json.as_bytes())?;
+    std::fs::write(output_dir.join("jobs.json"), jobs_json.as_bytes())?;
 
     Ok(())
 }
+
+/// Load a [`Score`] from a file, determining the format from the file's contents.  A path of `-`
+/// reads from stdin.
+fn load_score(path: &Path, options: &LoadOptions) -> anyhow::Result<Box<dyn Score>> {
+    let mut raw_bytes = Vec::new();
+    if path == Path::new("-") {
+        std::io::stdin()
+            .read_to_end(&mut raw_bytes)
+            .context("Error reading from stdin")?;
+    } else {
+        File::open(path)
+            .context(format!("Error loading {path:?}"))?
+            .read_to_end(&mut raw_bytes)
+            .context(format!("Error reading {path:?}"))?;
+    }
+    Ok(if raw_bytes.starts_with(MIDI_MAGIC_BYTES) {
+        Box::new(MidiScore::from_bytes(&raw_bytes, options)?)
+    } else if MuseScore::is_musescore(&raw_bytes) {
+        Box::new(MuseScore::from_raw_bytes(&raw_bytes, options)?)
+    } else if abc::looks_like_abc(&raw_bytes) {
+        let text = String::from_utf8_lossy(&raw_bytes);
+        let tree = abc::to_music_xml(&text).context("Error reading ABC tune")?;
+        Box::new(MusicXmlScore::from_partwise_tree(tree, options)?)
+    } else {
+        Box::new(MusicXmlScore::from_raw_bytes(&raw_bytes, options)?)
+    })
+}
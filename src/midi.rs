@@ -0,0 +1,659 @@
+//! Code for loading Standard MIDI Files (`.mid`), and for writing the notes played by each player
+//! back out as MIDI.
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+use itertools::Itertools;
+
+use crate::{
+    instrument::Instrument,
+    music_xml::LoadOptions,
+    note::Note,
+    score::{Articulations, Event, Score, Timestamp},
+    tempo::{TempoMarks, DEFAULT_TEMPO},
+};
+
+/// The first bytes of every Standard MIDI File (the type of the header chunk).
+pub const MIDI_MAGIC_BYTES: &[u8] = b"MThd";
+
+/// The (0-indexed) channel which General MIDI reserves for unpitched percussion.
+const PERCUSSION_CHANNEL: u8 = 9;
+
+/// Representation of a loaded MIDI file.
+#[derive(Debug)]
+pub struct MidiScore {
+    /// The `division` field of the header, which is either the number of ticks per quarter note
+    /// or (if the top bit is set) an SMPTE frame rate and the number of ticks per frame
+    division: u16,
+    /// Every tempo change in the file, as `(<tick>, <microseconds per quarter note>)`
+    tempo_changes: Vec<(u64, u32)>,
+    /// Every note which is played, sorted by start time.  The `note_idx` of each [`Event`]
+    /// indexes into this list.
+    notes: Vec<MidiNote>,
+    /// Every note which is played, sorted by onset
+    events: Vec<Event>,
+}
+
+/// A single note (i.e. a matching pair of note-on and note-off events) from a MIDI file.
+#[derive(Debug, Clone)]
+struct MidiNote {
+    instrument: Instrument,
+    /// The 0-indexed channel on which this note is played
+    channel: u8,
+    key: u8,
+    velocity: u8,
+    /// The tick of the note-on event
+    start: u64,
+    /// The tick of the matching note-off event
+    end: u64,
+}
+
+/// The contents of one `MTrk` chunk which we care about.
+#[derive(Debug, Clone, Default)]
+struct Track {
+    /// The first track name meta-event in this track
+    name: Option<String>,
+    notes: Vec<MidiNote>,
+    tempo_changes: Vec<(u64, u32)>,
+}
+
+/////////////
+// LOADING //
+/////////////
+
+impl MidiScore {
+    /// Reads a `MidiScore` from the bytes of a Standard MIDI File.
+    pub fn from_bytes(bytes: &[u8], options: &LoadOptions) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            options.excerpt_start.is_none() && options.excerpt_end.is_none(),
+            "Excerpts can't be chosen from MIDI files, since they don't contain measures or marks"
+        );
+        let mut reader = Reader::new(bytes);
+        let (chunk_type, header_bytes) = reader.read_chunk()?;
+        if chunk_type != MIDI_MAGIC_BYTES {
+            anyhow::bail!("File is not a MIDI file");
+        }
+        let mut header = Reader::new(header_bytes);
+        let format = header.read_u16()?;
+        let _num_tracks = header.read_u16()?; // We just read every track chunk in the file
+        let division = header.read_u16()?;
+        match format {
+            0 | 1 => {}
+            2 => anyhow::bail!("MIDI files with independent sequences (format 2) aren't supported"),
+            _ => anyhow::bail!("Unknown MIDI file format {format}"),
+        }
+        if division == 0 {
+            anyhow::bail!("MIDI file has zero ticks per quarter note");
+        }
+        let smpte_ticks_per_second = smpte_ticks_per_second(division)?;
+
+        let mut tracks = Vec::new();
+        while !reader.is_empty() {
+            let (chunk_type, chunk_bytes) = reader.read_chunk()?;
+            // Chunks of unknown types must be ignored
+            if chunk_type == b"MTrk" {
+                let track_num = tracks.len() + 1;
+                let track = read_track(chunk_bytes)
+                    .with_context(|| format!("Error reading MIDI track {track_num}"))?;
+                tracks.push(track);
+            }
+        }
+
+        // Decide which tracks and channels are played
+        for selector in &options.parts {
+            if selector.staves.is_some() {
+                anyhow::bail!("MIDI tracks don't have staves; use `--channel` instead");
+            }
+            let is_track_found = tracks.iter().enumerate().any(|(idx, track)| {
+                selector.matches(&(idx + 1).to_string(), track.name.as_deref())
+            });
+            if !is_track_found {
+                anyhow::bail!("No track has the number or name {:?}", selector.part);
+            }
+        }
+        let is_channel_played = |channel: u8| {
+            options.midi_channels.is_empty() || options.midi_channels.contains(&(channel + 1))
+        };
+        let mut notes = Vec::new();
+        let mut tempo_changes = Vec::new();
+        for (idx, track) in tracks.into_iter().enumerate() {
+            // Tempo changes apply to every track, regardless of which track they're written in
+            tempo_changes.extend(track.tempo_changes);
+            let is_track_played = options.parts.is_empty()
+                || (options.parts.iter())
+                    .any(|s| s.matches(&(idx + 1).to_string(), track.name.as_deref()));
+            if is_track_played {
+                notes.extend(
+                    (track.notes.into_iter()).filter(|note| is_channel_played(note.channel)),
+                );
+            }
+        }
+        notes.sort_by_key(|note| (note.start, note.channel, note.key));
+        tempo_changes.sort_by_key(|(tick, _)| *tick); // Stable, so later changes still win
+
+        // Convert ticks into quarter notes, and then into `Timestamp`s
+        let quarters_per_tick = match smpte_ticks_per_second {
+            // SMPTE timing is measured in real time, so the tempo is irrelevant.  We use the
+            // default tempo (which is never changed) to convert back to quarter notes.
+            Some(ticks_per_second) => DEFAULT_TEMPO / 60.0 / ticks_per_second,
+            None => 1.0 / division as f64,
+        };
+        let mut tempo_marks = TempoMarks::default();
+        if smpte_ticks_per_second.is_none() {
+            for &(tick, micros_per_quarter) in &tempo_changes {
+                let quarters_per_minute = 60_000_000.0 / micros_per_quarter.max(1) as f64;
+                tempo_marks.set_tempo(tick as f64 * quarters_per_tick, quarters_per_minute);
+            }
+        }
+        let score_end = notes.iter().map(|note| note.end).max().unwrap_or(0);
+        let tempo_map = tempo_marks.build(score_end as f64 * quarters_per_tick);
+        let to_timestamp =
+            |tick: u64| Timestamp::from_secs(tempo_map.secs_at(tick as f64 * quarters_per_tick));
+
+        let mut events = notes
+            .iter()
+            .enumerate()
+            .map(|(note_idx, note)| Event {
+                onset: to_timestamp(note.start),
+                release: to_timestamp(note.end),
+                instrument: note.instrument.clone(),
+                note_idx,
+                chord_note_idx: note_idx,
+                articulations: Articulations::default(),
+            })
+            .collect_vec();
+        events.sort();
+
+        Ok(Self {
+            division,
+            tempo_changes,
+            notes,
+            events,
+        })
+    }
+}
+
+/// If `division` specifies SMPTE timing, return the number of ticks per second.
+fn smpte_ticks_per_second(division: u16) -> anyhow::Result<Option<f64>> {
+    if division & 0x8000 == 0 {
+        return Ok(None); // Ticks per quarter note
+    }
+    // The upper byte is the negated number of frames per second (with 29 meaning 29.97)
+    let frames_per_second = match ((division >> 8) as u8 as i8).checked_neg() {
+        Some(29) => 29.97,
+        Some(fps @ (24 | 25 | 30)) => fps as f64,
+        _ => anyhow::bail!("Invalid SMPTE frame rate in MIDI file header"),
+    };
+    let ticks_per_frame = division & 0xff;
+    anyhow::ensure!(
+        ticks_per_frame > 0,
+        "MIDI file has zero ticks per SMPTE frame"
+    );
+    Ok(Some(frames_per_second * ticks_per_frame as f64))
+}
+
+/// Read the notes, tempo changes and name of a single `MTrk` chunk.
+fn read_track(bytes: &[u8]) -> anyhow::Result<Track> {
+    let mut reader = Reader::new(bytes);
+    let mut track = Track::default();
+    let mut tick = 0u64;
+    let mut running_status = None;
+    // For each `(channel, key)`, the notes which have started but not yet ended (as indices into
+    // `track.notes`).  If a key is struck again before it's released, the note-offs are matched
+    // to the note-ons in order.
+    let mut open_notes = HashMap::<(u8, u8), Vec<usize>>::new();
+    while !reader.is_empty() {
+        tick += reader.read_var_len()? as u64;
+        let mut status = reader.read_u8()?;
+        match status {
+            // Meta-event
+            0xFF => {
+                running_status = None;
+                let meta_type = reader.read_u8()?;
+                let length = reader.read_var_len()?;
+                let data = reader.read_bytes(length as usize)?;
+                match meta_type {
+                    0x03 if track.name.is_none() => {
+                        track.name = Some(String::from_utf8_lossy(data).trim().to_owned());
+                    }
+                    0x2F => break, // End of track
+                    0x51 if data.len() == 3 => {
+                        let micros_per_quarter = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                        track.tempo_changes.push((tick, micros_per_quarter));
+                    }
+                    _ => {}
+                }
+            }
+            // System exclusive messages
+            0xF0 | 0xF7 => {
+                running_status = None;
+                let length = reader.read_var_len()?;
+                reader.read_bytes(length as usize)?;
+            }
+            _ => {
+                // Channel message.  If the top bit isn't set, then this byte is actually the
+                // first data byte and the status is the same as the last message's.
+                let first_data_byte = if status & 0x80 == 0 {
+                    let data_byte = status;
+                    status = running_status.context("Data byte without a preceding status")?;
+                    data_byte
+                } else {
+                    running_status = Some(status);
+                    reader.read_data_byte()?
+                };
+                let channel = status & 0x0F;
+                match status & 0xF0 {
+                    // Program change and channel pressure only have one data byte
+                    0xC0 | 0xD0 => {}
+                    0x80 | 0x90 => {
+                        let key = first_data_byte;
+                        let velocity = reader.read_data_byte()?;
+                        let key_notes = open_notes.entry((channel, key)).or_default();
+                        // A note-on with zero velocity is a note-off
+                        if status & 0xF0 == 0x90 && velocity > 0 {
+                            key_notes.push(track.notes.len());
+                            track.notes.push(MidiNote {
+                                instrument: midi_instrument(channel, key),
+                                channel,
+                                key,
+                                velocity,
+                                start: tick,
+                                end: tick,
+                            });
+                        } else if !key_notes.is_empty() {
+                            let note_idx = key_notes.remove(0);
+                            track.notes[note_idx].end = tick;
+                        }
+                    }
+                    _ => {
+                        reader.read_data_byte()?; // All other messages have two data bytes
+                    }
+                }
+            }
+        }
+    }
+    // Notes which are never released last until the end of the track
+    for note_idx in open_notes.into_values().flatten() {
+        track.notes[note_idx].end = tick;
+    }
+    Ok(track)
+}
+
+/// Determine the [`Instrument`] which plays a given MIDI key on a given (0-indexed) channel.
+fn midi_instrument(channel: u8, key: u8) -> Instrument {
+    if channel != PERCUSSION_CHANNEL {
+        // Can't panic, because data bytes are always below 0x80
+        return Instrument::Whacker(Note::from_midi_key(key).unwrap());
+    }
+    let name = match gm_percussion_name(key) {
+        Some(name) => name.to_owned(),
+        None => format!("Drum {key}"),
+    };
+    Instrument::Unpitched {
+        id: format!("midi-percussion/{key}").into(),
+        name: name.into(),
+    }
+}
+
+/// The name of the General MIDI percussion instrument played by the given key, if there is one.
+pub fn gm_percussion_name(key: u8) -> Option<&'static str> {
+    let offset = key.checked_sub(GM_PERCUSSION_FIRST_KEY)?;
+    GM_PERCUSSION_NAMES.get(offset as usize).copied()
+}
+
+/// The names of the General MIDI percussion instruments, starting from key 35.
+const GM_PERCUSSION_FIRST_KEY: u8 = 35;
+const GM_PERCUSSION_NAMES: [&str; 47] = [
+    "Acoustic Bass Drum",
+    "Bass Drum",
+    "Side Stick",
+    "Acoustic Snare",
+    "Hand Clap",
+    "Electric Snare",
+    "Low Floor Tom",
+    "Closed Hi-Hat",
+    "High Floor Tom",
+    "Pedal Hi-Hat",
+    "Low Tom",
+    "Open Hi-Hat",
+    "Low-Mid Tom",
+    "Hi-Mid Tom",
+    "Crash Cymbal 1",
+    "High Tom",
+    "Ride Cymbal 1",
+    "Chinese Cymbal",
+    "Ride Bell",
+    "Tambourine",
+    "Splash Cymbal",
+    "Cowbell",
+    "Crash Cymbal 2",
+    "Vibraslap",
+    "Ride Cymbal 2",
+    "Hi Bongo",
+    "Low Bongo",
+    "Mute Hi Conga",
+    "Open Hi Conga",
+    "Low Conga",
+    "High Timbale",
+    "Low Timbale",
+    "High Agogo",
+    "Low Agogo",
+    "Cabasa",
+    "Maracas",
+    "Short Whistle",
+    "Long Whistle",
+    "Short Guiro",
+    "Long Guiro",
+    "Claves",
+    "Hi Wood Block",
+    "Low Wood Block",
+    "Mute Cuica",
+    "Open Cuica",
+    "Mute Triangle",
+    "Open Triangle",
+];
+
+/// A cursor over the bytes of a MIDI file.  All numbers are big-endian.
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn new(bytes: &'b [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn read_bytes(&mut self, len: usize) -> anyhow::Result<&'b [u8]> {
+        if self.bytes.len() < len {
+            anyhow::bail!("Unexpected end of MIDI file");
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    /// Read a data byte of a channel message, which must have its top bit clear.
+    fn read_data_byte(&mut self) -> anyhow::Result<u8> {
+        let byte = self.read_u8()?;
+        anyhow::ensure!(byte & 0x80 == 0, "Expected a data byte, not {byte:#04X}");
+        Ok(byte)
+    }
+
+    fn read_u16(&mut self) -> anyhow::Result<u16> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> anyhow::Result<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Read a variable-length quantity, which stores 7 bits per byte (most significant first),
+    /// with the top bit set on every byte except the last.
+    fn read_var_len(&mut self) -> anyhow::Result<u32> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.read_u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        anyhow::bail!("Variable-length quantity is longer than 4 bytes")
+    }
+
+    /// Read a chunk, returning its type and contents.
+    fn read_chunk(&mut self) -> anyhow::Result<(&'b [u8], &'b [u8])> {
+        let chunk_type = self.read_bytes(4)?;
+        let length = self.read_u32()?;
+        let contents = self.read_bytes(length as usize)?;
+        Ok((chunk_type, contents))
+    }
+}
+
+////////////////////////////
+// CREATING PLAYER SCORES //
+////////////////////////////
+
+impl MidiScore {
+    /// Returns a MIDI file containing only the notes played by a single player.  The left and
+    /// right hands are written to separate tracks, both of which keep the timing, channels and
+    /// velocities of the original file.
+    pub fn player_midi(&self, left_hand: &[Instrument], right_hand: &[Instrument]) -> Vec<u8> {
+        // The first track only contains the tempo changes
+        let mut tempo_track = Vec::new();
+        let mut last_tick = 0;
+        for &(tick, micros_per_quarter) in &self.tempo_changes {
+            write_var_len(&mut tempo_track, (tick - last_tick) as u32);
+            tempo_track.extend_from_slice(&[0xFF, 0x51, 0x03]);
+            tempo_track.extend_from_slice(&micros_per_quarter.to_be_bytes()[1..]);
+            last_tick = tick;
+        }
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MIDI_MAGIC_BYTES);
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&1u16.to_be_bytes()); // Format 1 (simultaneous tracks)
+        bytes.extend_from_slice(&3u16.to_be_bytes());
+        bytes.extend_from_slice(&self.division.to_be_bytes());
+        write_track(&mut bytes, tempo_track);
+        for (hand_name, instruments) in [("Left hand", left_hand), ("Right hand", right_hand)] {
+            write_track(&mut bytes, self.hand_track(hand_name, instruments));
+        }
+        bytes
+    }
+
+    /// Returns the events of a track containing every note played by the given `instruments`.
+    fn hand_track(&self, name: &str, instruments: &[Instrument]) -> Vec<u8> {
+        let mut track = Vec::new();
+        track.push(0);
+        track.extend_from_slice(&[0xFF, 0x03]);
+        write_var_len(&mut track, name.len() as u32);
+        track.extend_from_slice(name.as_bytes());
+
+        // Generate `(<tick>, <note index>, <is note-off>, <status>, <key>, <velocity>)` for every
+        // event.  Notes are sorted by their start, so sorting puts the note-off of a note before
+        // the note-on of a later repeat (so it isn't cut short), but still puts the note-on of a
+        // zero-length note before its note-off (so it isn't left hanging).
+        let events = instruments
+            .iter()
+            .flat_map(|instrument| {
+                (self.events.iter()).filter(move |event| &event.instrument == instrument)
+            })
+            .flat_map(|event| {
+                let note = &self.notes[event.note_idx];
+                [
+                    (
+                        note.start,
+                        event.note_idx,
+                        false,
+                        0x90 | note.channel,
+                        note.key,
+                        note.velocity,
+                    ),
+                    (
+                        note.end,
+                        event.note_idx,
+                        true,
+                        0x80 | note.channel,
+                        note.key,
+                        0,
+                    ),
+                ]
+            })
+            .sorted();
+        let mut last_tick = 0;
+        for (tick, _note_idx, _is_note_off, status, key, velocity) in events {
+            write_var_len(&mut track, (tick - last_tick) as u32);
+            track.extend_from_slice(&[status, key, velocity]);
+            last_tick = tick;
+        }
+        track
+    }
+}
+
+impl Score for MidiScore {
+    fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    fn player_file(
+        &self,
+        left_hand: &[Instrument],
+        right_hand: &[Instrument],
+    ) -> (&'static str, Vec<u8>) {
+        ("mid", self.player_midi(left_hand, right_hand))
+    }
+}
+
+/// Write an `MTrk` chunk containing the given `events`, adding the end-of-track meta-event.
+fn write_track(bytes: &mut Vec<u8>, mut events: Vec<u8>) {
+    events.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+    bytes.extend_from_slice(b"MTrk");
+    bytes.extend_from_slice(&(events.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&events);
+}
+
+/// Write a variable-length quantity (see [`Reader::read_var_len`]).
+fn write_var_len(bytes: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        groups.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    bytes.extend(groups.into_iter().rev());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a MIDI file from a `division` and the events of each track (without the
+    /// end-of-track meta-events).
+    fn midi_file(division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MIDI_MAGIC_BYTES);
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&division.to_be_bytes());
+        for track in tracks {
+            write_track(&mut bytes, track.to_vec());
+        }
+        bytes
+    }
+
+    /// Load a MIDI file, returning the `(onset, release, instrument name)` of each event.
+    fn events(bytes: &[u8], options: &LoadOptions) -> Vec<(f64, f64, String)> {
+        let score = MidiScore::from_bytes(bytes, options).unwrap();
+        let secs = |time: Timestamp| (Timestamp::ZERO.secs_until(time) * 1e6).round() / 1e6;
+        (score.events().iter())
+            .map(|e| (secs(e.onset), secs(e.release), e.instrument.name()))
+            .collect()
+    }
+
+    fn expected(events: &[(f64, f64, &str)]) -> Vec<(f64, f64, String)> {
+        (events.iter())
+            .map(|(onset, release, name)| (*onset, *release, name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn running_status_and_note_offs() {
+        // At the default 120 BPM, 96 ticks per quarter note is 0.5 seconds
+        let track = [
+            0x00, 0x90, 60, 100, // C4 on
+            0x60, 60, 0, // C4 off (running status, zero velocity)
+            0x00, 62, 100, // D4 on (running status)
+            0x60, 0x80, 62, 64, // D4 off
+        ];
+        assert_eq!(
+            events(&midi_file(96, &[&track]), &LoadOptions::default()),
+            expected(&[(0.0, 0.5, "C4"), (0.5, 1.0, "D4")])
+        );
+    }
+
+    #[test]
+    fn tempo_map() {
+        let tempo_track = [
+            0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // 60 BPM
+            0x60, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, // 120 BPM
+        ];
+        let track = [0x00, 0x90, 60, 100, 0x60, 62, 100, 0x00, 60, 0, 0x60, 62, 0];
+        assert_eq!(
+            events(
+                &midi_file(96, &[&tempo_track, &track]),
+                &LoadOptions::default()
+            ),
+            expected(&[(0.0, 1.0, "C4"), (1.0, 1.5, "D4")])
+        );
+    }
+
+    #[test]
+    fn smpte_timing() {
+        // 25 frames per second with 40 ticks per frame is 1000 ticks per second.  Tempo changes
+        // are ignored.
+        let track = [
+            0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // 60 BPM
+            0x00, 0x90, 60, 100, // C4 on
+            0x83, 0x74, 60, 0, // C4 off after 500 ticks
+        ];
+        let division = u16::from_be_bytes([-25i8 as u8, 40]);
+        assert_eq!(
+            events(&midi_file(division, &[&track]), &LoadOptions::default()),
+            expected(&[(0.0, 0.5, "C4")])
+        );
+        // Unknown frame rates (including -128, which can't be negated) and zero ticks per frame
+        // are rejected
+        for division in [[-20i8 as u8, 40], [0x80, 40], [-25i8 as u8, 0]] {
+            let bytes = midi_file(u16::from_be_bytes(division), &[&track]);
+            assert!(MidiScore::from_bytes(&bytes, &LoadOptions::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn channel_filter() {
+        let track = [
+            0x00, 0x90, 60, 100, 0x00, 0x91, 62, 100, // C4 on channel 1, D4 on channel 2
+            0x60, 0x80, 60, 0, 0x00, 0x81, 62, 0,
+        ];
+        let options = LoadOptions {
+            midi_channels: vec![2],
+            ..LoadOptions::default()
+        };
+        assert_eq!(
+            events(&midi_file(96, &[&track]), &options),
+            expected(&[(0.0, 0.5, "D4")])
+        );
+    }
+
+    #[test]
+    fn player_midi_keeps_zero_length_notes() {
+        // A zero-length C4 followed by a repeated C4, so the note-off of the first C4 happens at
+        // the same tick as the note-on of the second
+        let track = [
+            0x00, 0x90, 60, 100, 0x00, 60, 0, // Zero-length C4
+            0x00, 0x90, 60, 100, 0x60, 60, 0, // C4
+            0x00, 60, 100, 0x60, 60, 0, // C4
+        ];
+        let options = LoadOptions::default();
+        let score = MidiScore::from_bytes(&midi_file(96, &[&track]), &options).unwrap();
+        let c4 = Instrument::Whacker("C4".parse().unwrap());
+        let player_bytes = score.player_midi(&[c4], &[]);
+        assert_eq!(
+            events(&player_bytes, &options),
+            expected(&[(0.0, 0.0, "C4"), (0.0, 0.5, "C4"), (0.5, 1.0, "C4")])
+        );
+    }
+}
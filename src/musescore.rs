@@ -0,0 +1,1049 @@
+//! Code for loading MuseScore's native format (`.mscz`/`.mscx`).  MuseScore scores are converted
+//! into MusicXML so that their whacks are read by exactly the same code as [`MusicXmlScore`]s,
+//! but the annotations are written back into the original MuseScore document.
+
+use std::{collections::HashMap, io::Cursor};
+
+use anyhow::Context;
+use elementtree::Element;
+use itertools::Itertools;
+
+use crate::{
+    diagnostic::Diagnostic,
+    instrument::Instrument,
+    midi,
+    music_xml::{
+        looks_like_xml, note_type_quarters, read_archive_file, LoadOptions, MusicXmlScore,
+        ZIP_MAGIC_BYTES,
+    },
+    score::{Articulations, Event, Score},
+};
+
+/// Representation of a loaded MuseScore file.
+#[derive(Debug)]
+pub struct MuseScore {
+    /// The `<museScore>` document
+    tree: Element,
+    /// The score converted to MusicXML, which determines the whacks and how they're annotated
+    converted: MusicXmlScore,
+    /// For each `note_idx` in `converted`, the index of the corresponding `<Note>` in `tree` (see
+    /// [`chord_elems_mut`] for the order in which the `<Note>`s are counted)
+    note_map: Vec<usize>,
+}
+
+/// The tags of `<Chord>` children which make the chord a grace note.
+const GRACE_TAGS: [&str; 8] = [
+    "acciaccatura",
+    "appoggiatura",
+    "grace4",
+    "grace16",
+    "grace32",
+    "grace8after",
+    "grace16after",
+    "grace32after",
+];
+
+/////////////
+// LOADING //
+/////////////
+
+impl MuseScore {
+    /// Determine whether some bytes contain a MuseScore file, either compressed (`.mscz`) or
+    /// uncompressed (`.mscx`).
+    pub fn is_musescore(bytes: &[u8]) -> bool {
+        if bytes.starts_with(ZIP_MAGIC_BYTES) {
+            zip::ZipArchive::new(Cursor::new(bytes))
+                .is_ok_and(|archive| archive.file_names().any(|name| name.ends_with(".mscx")))
+        } else {
+            // The root element will be near the start of the file, after the XML declaration
+            let start = &bytes[..bytes.len().min(1024)];
+            looks_like_xml(bytes) && String::from_utf8_lossy(start).contains("<museScore")
+        }
+    }
+
+    /// Reads a `MuseScore` from some bytes, which are either compressed (`.mscz`) or
+    /// uncompressed (`.mscx`).
+    pub fn from_raw_bytes(bytes: &[u8], options: &LoadOptions) -> anyhow::Result<Self> {
+        let tree = if bytes.starts_with(ZIP_MAGIC_BYTES) {
+            let mscx_bytes = read_mscz_root_file(bytes)?;
+            Element::from_reader(mscx_bytes.as_slice())
+        } else {
+            Element::from_reader(bytes)
+        }
+        .context("File contains invalid XML")?;
+        let score = tree
+            .find("Score")
+            .context("MuseScore file doesn't contain a <Score>")?;
+        let (music_xml, note_map) = convert_score(score)?;
+        let converted = MusicXmlScore::from_partwise_tree(music_xml, options)?;
+        Ok(Self {
+            tree,
+            converted,
+            note_map,
+        })
+    }
+}
+
+/// Extract the `.mscx` file from a compressed `.mscz` archive.  Like `.mxl` files, the archive's
+/// manifest (`META-INF/container.xml`) lists the score as a `<rootfile>`.
+fn read_mscz_root_file(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(bytes)).context("Error extracting the zip archive")?;
+    let manifest_root_file = read_archive_file(&mut archive, "META-INF/container.xml")
+        .ok()
+        .and_then(|container_bytes| Element::from_reader(container_bytes.as_slice()).ok())
+        .and_then(|container| {
+            let rootfiles = container.find("rootfiles")?;
+            let root_file = rootfiles
+                .find_all("rootfile")
+                .filter_map(|rootfile| rootfile.get_attr("full-path"))
+                .find(|path| path.ends_with(".mscx"))?;
+            Some(root_file.to_owned())
+        });
+    let root_file_name = match manifest_root_file {
+        Some(name) => name,
+        // Excerpts are stored in sub-directories, so prefer files in the root of the archive
+        None => archive
+            .file_names()
+            .filter(|name| name.ends_with(".mscx"))
+            .min_by_key(|name| name.contains('/'))
+            .context("MuseScore archive doesn't contain a .mscx file")?
+            .to_owned(),
+    };
+    read_archive_file(&mut archive, &root_file_name)
+}
+
+/// A part of a MuseScore score, which may have several staves.
+#[derive(Debug)]
+struct Part<'t> {
+    name: String,
+    /// The `<Staff>`s (which contain the `<Measure>`s) of this part
+    staves: Vec<&'t Element>,
+    /// If this part is played on a drum set, the names of the drums, keyed by MIDI pitch
+    drum_names: Option<HashMap<u8, String>>,
+}
+
+/// Convert a MuseScore `<Score>` element into a `score-partwise` MusicXML tree.  This also
+/// returns the index of the `<Note>` which generated each (non-rest) `<note>` in the MusicXML.
+fn convert_score(score: &Element) -> anyhow::Result<(Element, Vec<usize>)> {
+    let staves_by_id = score
+        .find_all("Staff")
+        .filter_map(|staff| Some((staff.get_attr("id")?, staff)))
+        .collect::<HashMap<_, _>>();
+    let parts = score
+        .find_all("Part")
+        .enumerate()
+        .map(|(idx, part)| read_part(idx, part, &staves_by_id))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // `<Note>`s are numbered in document order, which is staff-by-staff.  But MusicXML parts
+    // contain all their staves at once, so we need to know where each measure's notes start.
+    let mut num_notes = 0;
+    let mut first_note_idxs = HashMap::<&str, Vec<usize>>::new();
+    for staff in score.find_all("Staff") {
+        let staff_note_idxs = first_note_idxs
+            .entry(staff.get_attr("id").unwrap_or_default())
+            .or_default();
+        for measure in staff.find_all("Measure") {
+            staff_note_idxs.push(num_notes);
+            num_notes += voices(measure)
+                .into_iter()
+                .flat_map(|voice| voice.find_all("Chord"))
+                .map(|chord| chord.find_all("Note").count())
+                .sum::<usize>();
+        }
+    }
+
+    let mut music_xml = Element::new("score-partwise");
+    music_xml.set_attr("version", "3.1");
+    let part_list = music_xml.append_new_child("part-list");
+    let mut part_elems = Vec::new();
+    let mut note_map = Vec::new();
+    for (part_idx, part) in parts.iter().enumerate() {
+        let part_id = format!("P{}", part_idx + 1);
+        let mut converter = PartConverter {
+            part,
+            part_id: &part_id,
+            note_map: &mut note_map,
+            used_drums: Vec::new(),
+            volta_ends: HashMap::new(),
+            open_voltas: HashMap::new(),
+        };
+        let mut part_elem = Element::new("part");
+        part_elem.set_attr("id", part_id.as_str());
+        let staff_measures = (part.staves.iter())
+            .map(|staff| {
+                let staff_id = staff.get_attr("id").unwrap_or_default();
+                let measures = staff.find_all("Measure");
+                measures.zip(&first_note_idxs[staff_id]).collect_vec()
+            })
+            .collect_vec();
+        let num_measures = staff_measures.iter().map(Vec::len).max().unwrap_or(0);
+        for measure_idx in 0..num_measures {
+            // Pair each measure with its staff number
+            let measures = (staff_measures.iter().enumerate())
+                .filter_map(|(staff_idx, measures)| {
+                    let (measure, first_note_idx) = measures.get(measure_idx)?;
+                    Some((staff_idx + 1, *measure, **first_note_idx))
+                })
+                .collect_vec();
+            let measure_elem = part_elem.append_new_child("measure");
+            measure_elem.set_attr("number", (measure_idx + 1).to_string());
+            converter.convert_measure(measure_idx, &measures, measure_elem)?;
+        }
+
+        // Add the part to the `<part-list>`, including any drums which it uses
+        let score_part = part_list.append_new_child("score-part");
+        score_part.set_attr("id", part_id.as_str());
+        score_part
+            .append_new_child("part-name")
+            .set_text(part.name.as_str());
+        for pitch in converter.used_drums.into_iter().sorted().dedup() {
+            let name = match part.drum_names.as_ref().and_then(|names| names.get(&pitch)) {
+                Some(name) => name.clone(),
+                None => midi::gm_percussion_name(pitch)
+                    .map_or_else(|| format!("Drum {pitch}"), str::to_owned),
+            };
+            let score_instrument = score_part.append_new_child("score-instrument");
+            score_instrument.set_attr("id", drum_instrument_id(&part_id, pitch));
+            score_instrument
+                .append_new_child("instrument-name")
+                .set_text(name);
+        }
+        part_elems.push(part_elem);
+    }
+    for part_elem in part_elems {
+        music_xml.append_child(part_elem);
+    }
+    Ok((music_xml, note_map))
+}
+
+/// Read the `idx`th `<Part>` of a score.
+fn read_part<'t>(
+    idx: usize,
+    part: &'t Element,
+    staves_by_id: &HashMap<&str, &'t Element>,
+) -> anyhow::Result<Part<'t>> {
+    let instrument = part.find("Instrument");
+    let name = [
+        part.find("trackName"),
+        instrument.and_then(|i| i.find("longName")),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|elem| plain_text(elem).trim().to_owned())
+    .find(|name| !name.is_empty())
+    .unwrap_or_else(|| format!("Part {}", idx + 1));
+    let staves = part
+        .find_all("Staff")
+        .map(|staff| {
+            let id = staff.get_attr("id").unwrap_or_default();
+            staves_by_id
+                .get(id)
+                .copied()
+                .with_context(|| format!("Part {name:?} refers to a non-existent staff {id:?}"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let drums = instrument.into_iter().flat_map(|i| i.find_all("Drum"));
+    let drum_names = drums
+        .filter_map(|drum| {
+            let pitch = drum.get_attr("pitch")?.parse().ok()?;
+            let name = drum.find("name")?.text().trim().to_owned();
+            Some((pitch, name))
+        })
+        .collect::<HashMap<u8, String>>();
+    let uses_drumset = [Some(part), instrument]
+        .into_iter()
+        .flatten()
+        .filter_map(|elem| elem.find("useDrumset"))
+        .any(|elem| elem.text().trim() == "1");
+    Ok(Part {
+        name,
+        staves,
+        drum_names: (uses_drumset || !drum_names.is_empty()).then_some(drum_names),
+    })
+}
+
+/// The state required to convert the measures of a single [`Part`] into MusicXML.
+struct PartConverter<'a, 't> {
+    part: &'a Part<'t>,
+    part_id: &'a str,
+    note_map: &'a mut Vec<usize>,
+    /// The pitches of every drum note in this part
+    used_drums: Vec<u8>,
+    /// The measures which end a volta, and the ending numbers of that volta
+    volta_ends: HashMap<usize, String>,
+    /// Voltas written in the old format (where the end is a separate `<endSpanner>`), keyed by
+    /// the spanner's `id`.  The values are `(<start measure>, <ending numbers>)`.
+    open_voltas: HashMap<String, (usize, String)>,
+}
+
+/// The position within a single voice of a measure which is being converted.
+#[derive(Debug, Default)]
+struct VoiceCursor {
+    /// The position, in quarter notes from the start of the measure
+    position: f64,
+    /// The ratios of the tuplets which contain the current position (innermost last)
+    tuplets: Vec<f64>,
+    /// Tuplets written in the old format, where each chord refers to its tuplet by `id`
+    tuplets_by_id: HashMap<String, f64>,
+    /// Set when a `<Fermata>` has been read, but not the chord or rest it applies to
+    is_fermata_pending: bool,
+}
+
+impl PartConverter<'_, '_> {
+    /// Convert the `measure_idx`th measure of each staff of the part into a MusicXML `<measure>`.
+    /// Each measure is given as `(<staff number>, <measure>, <index of its first <Note>>)`.
+    fn convert_measure(
+        &mut self,
+        measure_idx: usize,
+        measures: &[(usize, &Element, usize)],
+        measure_elem: &mut Element,
+    ) -> anyhow::Result<()> {
+        if measure_idx == 0 {
+            let attributes = measure_elem.append_new_child("attributes");
+            attributes.append_new_child("divisions").set_text("1");
+            if self.part.staves.len() > 1 {
+                attributes
+                    .append_new_child("staves")
+                    .set_text(self.part.staves.len().to_string());
+            }
+        }
+        // Repeats are written on every staff, so we only need to read them from the first one
+        if let Some((_, measure, _)) = measures.first() {
+            if measure.find("startRepeat").is_some() {
+                add_barline(measure_elem, "left")
+                    .append_new_child("repeat")
+                    .set_attr("direction", "forward");
+            }
+        }
+
+        let mut position = 0.0;
+        for &(staff, measure, first_note_idx) in measures {
+            let mut note_idx = first_note_idx;
+            for (voice_idx, voice) in voices(measure).into_iter().enumerate() {
+                // Move back to the start of the measure for the next voice
+                if position > 0.0 {
+                    append_duration(measure_elem, "backup", position);
+                }
+                let mut cursor = VoiceCursor::default();
+                for elem in voice.children() {
+                    self.convert_voice_elem(
+                        elem,
+                        (staff, voice_idx + 1),
+                        measure_idx,
+                        &mut cursor,
+                        &mut note_idx,
+                        measure_elem,
+                    )?;
+                }
+                position = cursor.position;
+            }
+        }
+
+        if let Some((_, measure, _)) = measures.first() {
+            if let Some(end_repeat) = measure.find("endRepeat") {
+                add_barline(measure_elem, "right")
+                    .append_new_child("repeat")
+                    .set_attr("direction", "backward")
+                    .set_attr("times", end_repeat.text().trim());
+            }
+        }
+        if let Some(numbers) = self.volta_ends.remove(&measure_idx) {
+            add_barline(measure_elem, "right")
+                .append_new_child("ending")
+                .set_attr("number", numbers)
+                .set_attr("type", "stop");
+        }
+        Ok(())
+    }
+
+    /// Convert one child of a `<voice>` (or of a `<Measure>`, in files without `<voice>`s).
+    fn convert_voice_elem(
+        &mut self,
+        elem: &Element,
+        (staff, voice): (usize, usize),
+        measure_idx: usize,
+        cursor: &mut VoiceCursor,
+        note_idx: &mut usize,
+        measure_elem: &mut Element,
+    ) -> anyhow::Result<()> {
+        match elem.tag().name() {
+            "Chord" | "Rest" => {
+                let is_grace = GRACE_TAGS.iter().any(|tag| elem.find(*tag).is_some());
+                let duration = if is_grace {
+                    0.0
+                } else {
+                    chord_rest_duration(elem, cursor)
+                        .context("Chord or rest has an invalid duration")?
+                };
+                // Fermatas are either separate elements before the chord, or articulations
+                let mut articulations = chord_articulations(elem);
+                articulations.fermata |= std::mem::take(&mut cursor.is_fermata_pending);
+
+                let note_elems = elem.find_all("Note").collect_vec();
+                if note_elems.is_empty() {
+                    // Rests (and chords without notes, which we treat the same)
+                    let note = measure_elem.append_new_child("note");
+                    note.append_new_child("rest");
+                    self.finish_note(note, duration, (staff, voice), articulations);
+                }
+                for (idx_in_chord, note_elem) in note_elems.into_iter().enumerate() {
+                    let note = measure_elem.append_new_child("note");
+                    if is_grace {
+                        note.append_new_child("grace");
+                    }
+                    if idx_in_chord > 0 {
+                        note.append_new_child("chord");
+                    }
+                    self.convert_note(note_elem, note)?;
+                    self.finish_note(note, duration, (staff, voice), articulations);
+                    self.note_map.push(*note_idx);
+                    *note_idx += 1;
+                }
+                cursor.position += duration;
+            }
+            "Tuplet" => {
+                let ratio = tuplet_ratio(elem).context("Tuplet has an invalid ratio")?;
+                match elem.get_attr("id") {
+                    Some(id) => {
+                        cursor.tuplets_by_id.insert(id.to_owned(), ratio);
+                    }
+                    None => cursor.tuplets.push(ratio),
+                }
+            }
+            "endTuplet" => {
+                cursor.tuplets.pop();
+            }
+            "location" => {
+                // Moves the position of the next element, e.g. for incomplete voices
+                let Some(fractions) = elem.find("fractions") else {
+                    return Ok(());
+                };
+                let offset = parse_fraction(fractions.text())
+                    .context("Location has an invalid offset")?
+                    * 4.0;
+                let (tag, duration) = if offset < 0.0 {
+                    ("backup", -offset)
+                } else {
+                    ("forward", offset)
+                };
+                append_duration(measure_elem, tag, duration);
+                cursor.position += offset;
+            }
+            "Fermata" => cursor.is_fermata_pending = true,
+            "TimeSig" => {
+                let (Some(beats), Some(beat_type)) = (elem.find("sigN"), elem.find("sigD")) else {
+                    return Ok(());
+                };
+                let time = measure_elem
+                    .append_new_child("attributes")
+                    .append_new_child("time");
+                time.append_new_child("beats").set_text(beats.text().trim());
+                time.append_new_child("beat-type")
+                    .set_text(beat_type.text().trim());
+            }
+            "Tempo" => {
+                // MuseScore stores tempos in quarter notes per second
+                let tempo = elem.find("tempo").map(|t| t.text().trim().parse::<f64>());
+                let Some(Ok(tempo)) = tempo else {
+                    return Ok(());
+                };
+                append_direction(measure_elem, staff)
+                    .append_new_child("sound")
+                    .set_attr("tempo", (tempo * 60.0).to_string());
+            }
+            "StaffText" | "SystemText" | "Expression" => {
+                // Tempo words (like `rit.` or `a tempo`) are often written as plain text
+                let text = plain_text(elem.find("text").unwrap_or(elem));
+                if !text.trim().is_empty() {
+                    append_direction(measure_elem, staff)
+                        .append_new_child("direction-type")
+                        .append_new_child("words")
+                        .set_text(text.trim());
+                }
+            }
+            "Jump" => {
+                let jump_to = elem.find("jumpTo").map_or("", |j| j.text().trim());
+                let sound = append_direction(measure_elem, staff).append_new_child("sound");
+                match jump_to {
+                    "start" | "" => sound.set_attr("dacapo", "yes"),
+                    segno => sound.set_attr("dalsegno", segno),
+                };
+            }
+            "Marker" => {
+                let label = elem.find("label").map_or("", |l| l.text().trim());
+                let kind = elem.find("subtype").map_or(label, |s| s.text().trim());
+                let attr = match kind {
+                    "segno" | "varsegno" => "segno",
+                    "codab" | "varcoda" | "codetta" => "coda",
+                    "coda" | "tocoda" | "tocodasym" | "toCoda" => "tocoda",
+                    "fine" => "fine",
+                    _ => return Ok(()), // Unknown or user-defined markers
+                };
+                append_direction(measure_elem, staff)
+                    .append_new_child("sound")
+                    .set_attr(attr, if attr == "fine" { "yes" } else { label });
+            }
+            "Spanner" if elem.get_attr("type") == Some("Volta") => {
+                // New format: the start of the volta says how many measures it covers
+                let Some(volta) = elem.find("Volta") else {
+                    return Ok(());
+                };
+                let num_measures = elem
+                    .navigate(&["next", "location", "measures"])
+                    .and_then(|m| m.text().trim().parse::<usize>().ok())
+                    .unwrap_or(1);
+                let numbers = self.start_volta(volta, measure_elem);
+                let end_idx = measure_idx + num_measures.saturating_sub(1);
+                self.volta_ends.insert(end_idx, numbers);
+            }
+            "Volta" => {
+                // Old format: the end of the volta is written as a separate `<endSpanner>`
+                let numbers = self.start_volta(elem, measure_elem);
+                let id = elem.get_attr("id").unwrap_or_default().to_owned();
+                self.open_voltas.insert(id, (measure_idx, numbers));
+            }
+            "endSpanner" => {
+                // The end of a spanner is written at the start of the measure after it ends
+                let id = elem.get_attr("id").unwrap_or_default();
+                if let Some((start_idx, numbers)) = self.open_voltas.remove(id) {
+                    let end_idx = measure_idx.saturating_sub(1).max(start_idx);
+                    self.volta_ends.insert(end_idx, numbers);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Add the `<barline>` which starts a volta, returning its ending numbers.
+    fn start_volta(&self, volta: &Element, measure_elem: &mut Element) -> String {
+        let numbers = volta
+            .find("endings")
+            .map_or("1", |e| e.text().trim())
+            .to_owned();
+        add_barline(measure_elem, "left")
+            .append_new_child("ending")
+            .set_attr("number", numbers.as_str())
+            .set_attr("type", "start");
+        numbers
+    }
+
+    /// Add the pitch (and ties) of a MuseScore `<Note>` to a MusicXML `<note>`.
+    fn convert_note(&mut self, note_elem: &Element, note: &mut Element) -> anyhow::Result<()> {
+        let pitch = note_elem
+            .find("pitch")
+            .and_then(|p| p.text().trim().parse::<u8>().ok())
+            .context("Note has no valid <pitch>")?;
+        let tpc = note_elem
+            .find("tpc")
+            .and_then(|t| t.text().trim().parse::<i32>().ok());
+        let (step, alter, octave) = spell_pitch(pitch, tpc);
+        if self.part.drum_names.is_some() {
+            let unpitched = note.append_new_child("unpitched");
+            unpitched.append_new_child("display-step").set_text(step);
+            unpitched
+                .append_new_child("display-octave")
+                .set_text(octave.to_string());
+            note.append_new_child("instrument")
+                .set_attr("id", drum_instrument_id(self.part_id, pitch));
+            self.used_drums.push(pitch);
+        } else {
+            let pitch_elem = note.append_new_child("pitch");
+            pitch_elem.append_new_child("step").set_text(step);
+            pitch_elem
+                .append_new_child("alter")
+                .set_text(alter.to_string());
+            pitch_elem
+                .append_new_child("octave")
+                .set_text(octave.to_string());
+        }
+
+        // Ties are either `<Spanner type="Tie">`s (with `<next>` at the start of the tie and
+        // `<prev>` at the end), or a `<Tie>` with a matching `<endSpanner>` in the old format
+        let tie_spanners = note_elem
+            .find_all("Spanner")
+            .filter(|s| s.get_attr("type") == Some("Tie"))
+            .collect_vec();
+        let starts_tie = note_elem.find("Tie").is_some()
+            || tie_spanners.iter().any(|s| s.find("next").is_some());
+        let stops_tie = note_elem.find("endSpanner").is_some()
+            || tie_spanners.iter().any(|s| s.find("prev").is_some());
+        if stops_tie {
+            note.append_new_child("tie").set_attr("type", "stop");
+        }
+        if starts_tie {
+            note.append_new_child("tie").set_attr("type", "start");
+        }
+        Ok(())
+    }
+
+    /// Add the elements which are shared between notes and rests.
+    fn finish_note(
+        &self,
+        note: &mut Element,
+        duration: f64,
+        (staff, voice): (usize, usize),
+        articulations: Articulations,
+    ) {
+        if note.find("grace").is_none() {
+            note.append_new_child("duration")
+                .set_text(duration.to_string());
+        }
+        note.append_new_child("voice").set_text(voice.to_string());
+        if self.part.staves.len() > 1 {
+            note.append_new_child("staff").set_text(staff.to_string());
+        }
+        if articulations == Articulations::default() {
+            return;
+        }
+        let notations = note.append_new_child("notations");
+        if articulations.fermata {
+            notations.append_new_child("fermata");
+        }
+        if articulations.tremolo {
+            (notations
+                .append_new_child("ornaments")
+                .append_new_child("tremolo"))
+            .set_attr("type", "single")
+            .set_text("3");
+        }
+        if articulations.staccato || articulations.accent {
+            let articulations_elem = notations.append_new_child("articulations");
+            if articulations.staccato {
+                articulations_elem.append_new_child("staccato");
+            }
+            if articulations.accent {
+                articulations_elem.append_new_child("accent");
+            }
+        }
+    }
+}
+
+/// Read the `<Articulation>`s and `<Tremolo>` of a `<Chord>`.
+fn chord_articulations(chord_elem: &Element) -> Articulations {
+    let mut articulations = Articulations {
+        tremolo: chord_elem.find("Tremolo").is_some(),
+        ..Articulations::default()
+    };
+    // Subtypes are named like `articStaccatoAbove` or `fermataAbove`
+    let subtypes = (chord_elem.find_all("Articulation"))
+        .filter_map(|articulation| articulation.find("subtype"))
+        .map(|subtype| subtype.text().to_lowercase());
+    for subtype in subtypes {
+        articulations.fermata |= subtype.contains("fermata");
+        articulations.staccato |= subtype.contains("staccat");
+        articulations.accent |= subtype.contains("accent");
+    }
+    articulations
+}
+
+/// The elements which contain the chords of a measure: either its `<voice>`s or (in older
+/// files) the measure itself.
+fn voices(measure: &Element) -> Vec<&Element> {
+    let voices = measure.find_all("voice").collect_vec();
+    if voices.is_empty() {
+        vec![measure]
+    } else {
+        voices
+    }
+}
+
+/// The length of a `<Chord>` or `<Rest>`, in quarter notes.
+fn chord_rest_duration(elem: &Element, cursor: &VoiceCursor) -> Option<f64> {
+    let duration_type = elem.find("durationType")?.text().trim();
+    if duration_type == "measure" {
+        // Measure rests store their actual length
+        return Some(parse_fraction(elem.find("duration")?.text())? * 4.0);
+    }
+    let num_dots = elem
+        .find("dots")
+        .map_or(Some(0), |dots| dots.text().trim().parse().ok())?;
+    let mut duration = note_type_quarters(duration_type, num_dots)?;
+    for ratio in &cursor.tuplets {
+        duration *= ratio;
+    }
+    if let Some(tuplet_id) = elem.find("Tuplet") {
+        duration *= cursor.tuplets_by_id.get(tuplet_id.text().trim())?;
+    }
+    Some(duration)
+}
+
+/// The ratio by which a `<Tuplet>` scales the lengths of its notes.
+fn tuplet_ratio(elem: &Element) -> Option<f64> {
+    let read = |tag: &str| elem.find(tag)?.text().trim().parse::<f64>().ok();
+    let ratio = read("normalNotes")? / read("actualNotes")?;
+    ratio.is_finite().then_some(ratio)
+}
+
+/// Parse a fraction like `3/4`, as used by MuseScore to store lengths in whole notes.
+fn parse_fraction(text: &str) -> Option<f64> {
+    let (numerator, denominator) = text.trim().split_once('/')?;
+    let value = numerator.trim().parse::<f64>().ok()? / denominator.trim().parse::<f64>().ok()?;
+    value.is_finite().then_some(value)
+}
+
+/// Spell a MIDI pitch as a MusicXML `(<step>, <alter>, <octave>)`, using MuseScore's 'tonal
+/// pitch class' if it's known.
+fn spell_pitch(pitch: u8, tpc: Option<i32>) -> (&'static str, i32, i32) {
+    const STEPS: [(&str, i32); 7] = [
+        ("F", 5),
+        ("C", 0),
+        ("G", 7),
+        ("D", 2),
+        ("A", 9),
+        ("E", 4),
+        ("B", 11),
+    ];
+    const SHARP_SPELLINGS: [(usize, i32); 12] = [
+        (1, 0),
+        (1, 1),
+        (3, 0),
+        (3, 1),
+        (5, 0),
+        (0, 0),
+        (0, 1),
+        (2, 0),
+        (2, 1),
+        (4, 0),
+        (4, 1),
+        (6, 0),
+    ];
+    let pitch = pitch as i32;
+    // Tonal pitch classes go around the circle of fifths, with 14 being C.  Every 7 steps along
+    // the circle adds a sharp.
+    let tpc_spelling = tpc
+        .filter(|tpc| (-1..=33).contains(tpc))
+        .map(|tpc| {
+            (
+                (tpc + 1).rem_euclid(7) as usize,
+                (tpc + 1).div_euclid(7) - 2,
+            )
+        })
+        // Ignore spellings which don't match the pitch
+        .filter(|&(step_idx, alter)| (pitch - alter - STEPS[step_idx].1).rem_euclid(12) == 0);
+    let (step_idx, alter) = tpc_spelling.unwrap_or(SHARP_SPELLINGS[pitch.rem_euclid(12) as usize]);
+    let (step, step_semis) = STEPS[step_idx];
+    let octave = (pitch - alter - step_semis).div_euclid(12) - 1;
+    (step, alter, octave)
+}
+
+/// The `id` of the `<score-instrument>` of the drum with the given pitch.
+fn drum_instrument_id(part_id: &str, pitch: u8) -> String {
+    format!("{part_id}-D{pitch}")
+}
+
+fn add_barline<'e>(measure_elem: &'e mut Element, location: &str) -> &'e mut Element {
+    measure_elem
+        .append_new_child("barline")
+        .set_attr("location", location)
+}
+
+fn append_direction(measure_elem: &mut Element, staff: usize) -> &mut Element {
+    let direction = measure_elem.append_new_child("direction");
+    direction
+        .append_new_child("staff")
+        .set_text(staff.to_string());
+    direction
+}
+
+/// Append a `<backup>` or `<forward>` element.
+fn append_duration(measure_elem: &mut Element, tag: &str, duration: f64) {
+    measure_elem
+        .append_new_child(tag)
+        .append_new_child("duration")
+        .set_text(duration.to_string());
+}
+
+/// The text of an element, including the text of any formatting elements within it (e.g. `<b>`).
+fn plain_text(elem: &Element) -> String {
+    let mut text = elem.text().to_owned();
+    for child in elem.children() {
+        text.push_str(&plain_text(child));
+        text.push_str(child.tail());
+    }
+    text
+}
+
+///////////////////////////////
+// CREATING ANNOTATED SCORES //
+///////////////////////////////
+
+impl MuseScore {
+    /// Returns a `.mscx` document containing this score, with the notes of the given
+    /// `{left,right}_hand`s coloured and labelled with lyrics.
+    pub fn annotated_mscx(&self, left_hand: &[Instrument], right_hand: &[Instrument]) -> String {
+        let annotations = self.converted.note_annotations(left_hand, right_hand);
+        // Convert the annotations from MusicXML `note_idx`s to `<Note>` indices
+        let mut colours = HashMap::new();
+        let mut lyrics = HashMap::new();
+        for (xml_note_idx, &note_idx) in self.note_map.iter().enumerate() {
+            if let Some(hand) = annotations.colours.get(&xml_note_idx) {
+                colours.insert(note_idx, *hand);
+            }
+            if let Some(note_lyrics) = annotations.lyrics.get(&xml_note_idx) {
+                lyrics.insert(note_idx, note_lyrics);
+            }
+        }
+
+        let mut new_tree = self.tree.clone();
+        let mut note_idx = 0;
+        for chord in chord_elems_mut(&mut new_tree) {
+            // Replace the chord's lyrics with our own
+            remove_children(chord, "Lyrics");
+            let mut chord_lyrics = Vec::new();
+            for note_elem in chord.find_all_mut("Note") {
+                remove_children(note_elem, "color");
+                if let Some(hand) = colours.get(&note_idx) {
+                    append_colour(note_elem, hand.colour());
+                }
+                chord_lyrics.extend(lyrics.get(&note_idx).into_iter().copied().flatten());
+                note_idx += 1;
+            }
+            for (verse, (instrument, hand)) in chord_lyrics.into_iter().enumerate() {
+                let lyrics_elem = chord.append_new_child("Lyrics");
+                if verse > 0 {
+                    lyrics_elem
+                        .append_new_child("no")
+                        .set_text(verse.to_string());
+                }
+                lyrics_elem
+                    .append_new_child("text")
+                    .set_text(instrument.name());
+                append_colour(lyrics_elem, hand.colour());
+            }
+        }
+        new_tree.to_string().unwrap()
+    }
+}
+
+impl Score for MuseScore {
+    fn events(&self) -> &[Event] {
+        self.converted.events()
+    }
+
+    fn warnings(&self) -> &[Diagnostic] {
+        self.converted.warnings()
+    }
+
+    fn player_file(
+        &self,
+        left_hand: &[Instrument],
+        right_hand: &[Instrument],
+    ) -> (&'static str, Vec<u8>) {
+        (
+            "mscx",
+            self.annotated_mscx(left_hand, right_hand).into_bytes(),
+        )
+    }
+}
+
+/// Every `<Chord>` in the main score of a `<museScore>` document, in the order in which their
+/// `<Note>`s are numbered.
+fn chord_elems_mut(tree: &mut Element) -> Vec<&mut Element> {
+    let mut chords = Vec::new();
+    let Some(score) = tree.find_mut("Score") else {
+        return chords;
+    };
+    for staff in score.find_all_mut("Staff") {
+        for measure in staff.find_all_mut("Measure") {
+            if measure.find("voice").is_some() {
+                for voice in measure.find_all_mut("voice") {
+                    chords.extend(voice.find_all_mut("Chord"));
+                }
+            } else {
+                chords.extend(measure.find_all_mut("Chord"));
+            }
+        }
+    }
+    chords
+}
+
+fn remove_children(elem: &mut Element, tag: &str) {
+    let indices = elem
+        .children()
+        .positions(|child| child.tag().name() == tag)
+        .collect_vec();
+    for idx in indices.into_iter().rev() {
+        elem.remove_child(idx);
+    }
+}
+
+/// Append a MuseScore `<color>` element, given a colour like `#ff0000`.
+fn append_colour(elem: &mut Element, hex_colour: &str) {
+    let channel = |idx: usize| u8::from_str_radix(&hex_colour[1 + idx * 2..3 + idx * 2], 16);
+    let colour_elem = elem.append_new_child("color");
+    for (idx, name) in ["r", "g", "b"].into_iter().enumerate() {
+        colour_elem.set_attr(name, channel(idx).unwrap_or(0).to_string());
+    }
+    colour_elem.set_attr("a", "255");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{music_xml::Hand, score::Timestamp};
+
+    /// A piano part with two staves.  The first measure of the upper staff has a grace chord, a
+    /// two-note chord, a triplet and a second voice (moved along by a `<location>`), and then
+    /// the last three measures are a repeat with first and second endings.
+    const MSCX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<museScore version="3.02">
+  <Score>
+    <Part>
+      <Staff id="1"/>
+      <Staff id="2"/>
+      <trackName>Piano</trackName>
+    </Part>
+    <Staff id="1">
+      <Measure>
+        <voice>
+          <Tempo><tempo>1</tempo></Tempo>
+          <Chord><durationType>quarter</durationType><Note><pitch>60</pitch></Note></Chord>
+          <Chord>
+            <acciaccatura/>
+            <durationType>eighth</durationType>
+            <Note><pitch>62</pitch></Note>
+            <Note><pitch>65</pitch></Note>
+          </Chord>
+          <Chord>
+            <durationType>quarter</durationType>
+            <Note><pitch>64</pitch></Note>
+            <Note><pitch>67</pitch></Note>
+          </Chord>
+          <Tuplet><normalNotes>2</normalNotes><actualNotes>3</actualNotes></Tuplet>
+          <Chord><durationType>eighth</durationType><Note><pitch>69</pitch></Note></Chord>
+          <Chord><durationType>eighth</durationType><Note><pitch>71</pitch></Note></Chord>
+          <Chord><durationType>eighth</durationType><Note><pitch>72</pitch></Note></Chord>
+          <endTuplet/>
+          <Rest><durationType>quarter</durationType></Rest>
+        </voice>
+        <voice>
+          <location><fractions>1/4</fractions></location>
+          <Chord><durationType>half</durationType><Note><pitch>65</pitch></Note></Chord>
+        </voice>
+      </Measure>
+      <Measure>
+        <startRepeat/>
+        <voice>
+          <Chord><durationType>whole</durationType><Note><pitch>69</pitch></Note></Chord>
+        </voice>
+      </Measure>
+      <Measure>
+        <endRepeat>2</endRepeat>
+        <voice>
+          <Spanner type="Volta">
+            <Volta><endings>1</endings></Volta>
+            <next><location><measures>1</measures></location></next>
+          </Spanner>
+          <Chord><durationType>whole</durationType><Note><pitch>71</pitch></Note></Chord>
+        </voice>
+      </Measure>
+      <Measure>
+        <voice>
+          <Spanner type="Volta">
+            <Volta><endings>2</endings></Volta>
+            <next><location><measures>1</measures></location></next>
+          </Spanner>
+          <Chord><durationType>whole</durationType><Note><pitch>72</pitch></Note></Chord>
+        </voice>
+      </Measure>
+    </Staff>
+    <Staff id="2">
+      <Measure>
+        <voice>
+          <Chord><durationType>whole</durationType><Note><pitch>48</pitch></Note></Chord>
+        </voice>
+      </Measure>
+      <Measure>
+        <startRepeat/>
+        <voice><Rest><durationType>measure</durationType><duration>4/4</duration></Rest></voice>
+      </Measure>
+      <Measure>
+        <endRepeat>2</endRepeat>
+        <voice><Rest><durationType>measure</durationType><duration>4/4</duration></Rest></voice>
+      </Measure>
+      <Measure>
+        <voice>
+          <Chord><durationType>whole</durationType><Note><pitch>43</pitch></Note></Chord>
+        </voice>
+      </Measure>
+    </Staff>
+  </Score>
+</museScore>
+"#;
+
+    fn load() -> MuseScore {
+        MuseScore::from_raw_bytes(MSCX.as_bytes(), &LoadOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn conversion() {
+        let secs = |time: Timestamp| (Timestamp::ZERO.secs_until(time) * 1e6).round() / 1e6;
+        let events = (load().events().iter())
+            .map(|e| (secs(e.onset), secs(e.release), e.instrument.name()))
+            .collect_vec();
+        let expected = [
+            (0.0, 1.0, "C4"),
+            (0.0, 4.0, "C3"),
+            // The grace chord leads into the next chord
+            (0.92, 0.92, "D4"),
+            (0.92, 0.92, "F4"),
+            (1.0, 2.0, "E4"),
+            (1.0, 2.0, "G4"),
+            // The second voice starts a quarter note into the measure
+            (1.0, 3.0, "F4"),
+            (2.0, 2.333333, "A4"),
+            (2.333333, 2.666667, "B4"),
+            (2.666667, 3.0, "C5"),
+            // The first ending is skipped on the second pass
+            (4.0, 8.0, "A4"),
+            (8.0, 12.0, "B4"),
+            (12.0, 16.0, "A4"),
+            (16.0, 20.0, "G2"),
+            (16.0, 20.0, "C5"),
+        ];
+        let expected = (expected.iter())
+            .map(|(onset, release, name)| (*onset, *release, name.to_string()))
+            .collect_vec();
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn annotation() {
+        let score = load();
+        let left_hand = ["F4", "C3"].map(|n| Instrument::Whacker(n.parse().unwrap()));
+        let right_hand = ["G4", "A4"].map(|n| Instrument::Whacker(n.parse().unwrap()));
+        let mscx = score.annotated_mscx(&left_hand, &right_hand);
+        let mut tree = Element::from_reader(mscx.as_bytes()).unwrap();
+        // `<Note>`s are numbered staff by staff, whereas the converted MusicXML interleaves the
+        // staves in each measure
+        let mut hands = Vec::new();
+        let mut lyrics = Vec::new();
+        for chord in chord_elems_mut(&mut tree) {
+            for note in chord.find_all("Note") {
+                let colour = note.find("color").map(|c| c.get_attr("r").unwrap());
+                hands.push(match colour {
+                    Some("255") => Some(Hand::Left),
+                    Some(_) => Some(Hand::Right),
+                    None => None,
+                });
+            }
+            let chord_lyrics = (chord.find_all("Lyrics"))
+                .map(|l| l.find("text").unwrap().text().to_owned())
+                .collect_vec();
+            lyrics.push(chord_lyrics.join(" "));
+        }
+        let (l, r) = (Some(Hand::Left), Some(Hand::Right));
+        // Staff 1's first measure (voice 1 and then voice 2), then its other measures, and then
+        // staff 2
+        assert_eq!(hands[..8], [None, None, l, None, r, r, None, None]);
+        assert_eq!(hands[8..], [l, r, None, None, l, None]);
+        assert_eq!(
+            lyrics,
+            ["", "F4", "G4", "A4", "", "", "F4", "A4", "", "", "C3", ""]
+        );
+    }
+}
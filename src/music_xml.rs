@@ -3,324 +3,1806 @@
 use std::{
     cmp::Reverse,
     collections::HashMap,
-    ffi::OsStr,
-    fs::File,
+    fmt::{Display, Formatter},
     io::{Cursor, Read},
-    path::Path,
+    ops::Range,
+    str::FromStr,
     time::Duration,
 };
 
 use anyhow::Context;
 use itertools::Itertools;
-use ordered_float::OrderedFloat;
 
-use crate::note::Note;
+use crate::{
+    diagnostic::{Diagnostic, Location, Reason},
+    instrument::Instrument,
+    note::Note,
+    repeats::{self, MeasureJumps},
+    score::{Articulations, Event, Score, Timestamp},
+    tempo::{GradualChange, Swing, TempoMarks, TempoWords, TimingModel},
+};
 
 /// Representation of a loaded MusicXML file.
 #[derive(Debug)]
 pub struct MusicXmlScore {
+    /// The score, always stored as `score-partwise` (timewise scores are converted on loading)
     tree: elementtree::Element,
-    pub whacks: HashMap<Note, Vec<Whack>>, // TODO: Not pub
-}
-
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-pub struct Whack {
-    pub timestamp: Timestamp,
-    /// The 0-based index of the `<note>` tag in the source XML `tree` (i.e. if `note_idx = 5`,
-    /// then there are 5 `<note>` tags before the one representing this `Whack`).
-    note_idx: usize,
-    /// The `note_idx` of the first `<note>` in the chord containing this `Whack`
-    chord_note_idx: usize,
+    /// The layout of the original document, which is also used when writing annotated scores
+    layout: Layout,
+    /// Every note played in the score, sorted by onset
+    events: Vec<Event>,
+    /// Maps the `note_idx` of every tied `<note>` that continues an earlier note (and therefore
+    /// doesn't generate an `Event` of its own) to the `note_idx` of the note which started the tie
+    tie_continuations: HashMap<usize, usize>,
+    /// The problems which were skipped when loading leniently, along with anything else the
+    /// user should know about (e.g. microtonal notes which were rounded)
+    warnings: Vec<Diagnostic>,
 }
 
 ///////////////////
 // READING FILES //
 ///////////////////
 
+/// Options which control how a [`MusicXmlScore`] is loaded.
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// How long before the note they lead into that grace notes are played.  Runs of grace notes
+    /// are spaced out by this amount.
+    pub grace_note_lead_in: Duration,
+    /// Should cue notes (`<cue/>`) be played?  These are usually another part's notes printed for
+    /// reference, so by default they are ignored.
+    pub include_cue_notes: bool,
+    /// Which parts (and staves) are played on boomwhackers.  If this is empty, every part is
+    /// played.  Parts which aren't played are still kept in the annotated scores.  In MIDI
+    /// files, the parts are the tracks, which are selected by their (1-indexed) number or name.
+    pub parts: Vec<PartSelector>,
+    /// Which (1-indexed) MIDI channels are played.  If this is empty, every channel is played.
+    /// This is ignored when loading MusicXML.
+    pub midi_channels: Vec<u8>,
+    /// If `true`, notes (and other elements) which can't be loaded are skipped with a warning.
+    /// Otherwise, the first problem stops the score from loading.
+    pub lenient: bool,
+    /// How notes whose `<alter>` isn't a whole number of semitones (e.g. quarter-tones) are
+    /// played.  These notes are always reported with a warning.
+    pub microtones: MicrotonePolicy,
+    /// If `true`, notes inside octave-shift lines (`8va`, `8vb`, `15ma`, etc.) are moved by the
+    /// shift.  The MusicXML spec says that these notes' `<pitch>`es are already the sounding
+    /// pitch, but some programs write the pitch as it's displayed on the staff instead.
+    pub apply_octave_shifts: bool,
+    /// Where the excerpt being rehearsed starts, or `None` to start at the beginning of the
+    /// score.  The excerpt starts the first time that this mark is played.  Notes outside the
+    /// excerpt aren't played, but keep the same timestamps as in the full score.  MIDI files
+    /// have no measures or marks, so can't be loaded with an excerpt.
+    pub excerpt_start: Option<ExcerptMark>,
+    /// Where the excerpt being rehearsed ends, or `None` to play until the end of the score.
+    /// The excerpt ends at the next time that this mark is played after `excerpt_start`.  A
+    /// measure number includes that measure in the excerpt, whereas the excerpt stops just before
+    /// any other kind of mark (so `B` to `D` means 'from letter B up to letter D').
+    pub excerpt_end: Option<ExcerptMark>,
+    /// How notes are placed in time relative to where they're written.  This defaults to
+    /// straight, so swing marks are only followed if asked for.  It's ignored when loading MIDI,
+    /// whose notes are already placed where they're played.
+    pub timing: TimingModel,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            grace_note_lead_in: Duration::from_millis(80),
+            include_cue_notes: false,
+            parts: Vec::new(),
+            midi_channels: Vec::new(),
+            lenient: false,
+            microtones: MicrotonePolicy::Round,
+            apply_octave_shifts: false,
+            excerpt_start: None,
+            excerpt_end: None,
+            timing: TimingModel::Straight,
+        }
+    }
+}
+
+/// How to handle notes which are between two semitones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MicrotonePolicy {
+    /// Play the note on the boomwhacker of the nearest semitone
+    Round,
+    /// Treat the note as an error (so it's skipped when loading leniently)
+    Reject,
+    /// Play the note on its own [`Instrument::Microtonal`] boomwhacker
+    Distinct,
+}
+
+impl FromStr for MicrotonePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "round" => Self::Round,
+            "reject" => Self::Reject,
+            "distinct" => Self::Distinct,
+            _ => anyhow::bail!("Unknown microtone policy {s:?} (expected round/reject/distinct)"),
+        })
+    }
+}
+
+/// A selection of a part (or some of its staves) to be played on boomwhackers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartSelector {
+    /// The `id` of the part (e.g. `P1`), or its `<part-name>` (ignoring case)
+    pub part: String,
+    /// The (1-indexed) staves to play, or `None` to play all of them
+    pub staves: Option<Vec<usize>>,
+}
+
+impl FromStr for PartSelector {
+    type Err = anyhow::Error;
+
+    /// Parses selectors like `P1`, `Boomwhackers` or `Piano:1,2` (staves 1 and 2 of the part
+    /// named 'Piano').
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (part, staves) = match s.rsplit_once(':') {
+            Some((part, staves_str)) => {
+                let staves = staves_str
+                    .split(',')
+                    .map(|staff| staff.trim().parse::<usize>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .with_context(|| format!("Invalid staff numbers {staves_str:?}"))?;
+                (part, Some(staves))
+            }
+            None => (s, None),
+        };
+        Ok(Self {
+            part: part.trim().to_owned(),
+            staves,
+        })
+    }
+}
+
+/// Which staves of a part are played on boomwhackers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PlayedStaves {
+    All,
+    Only(Vec<usize>),
+    None,
+}
+
+impl PlayedStaves {
+    /// Determine which staves of the part with the given `id` and `name` are played.
+    fn for_part(selectors: &[PartSelector], id: &str, name: Option<&str>) -> Self {
+        if selectors.is_empty() {
+            return Self::All;
+        }
+        let mut staves = Vec::new();
+        for selector in selectors.iter().filter(|s| s.matches(id, name)) {
+            match &selector.staves {
+                Some(selected_staves) => staves.extend_from_slice(selected_staves),
+                None => return Self::All,
+            }
+        }
+        if staves.is_empty() {
+            Self::None
+        } else {
+            Self::Only(staves)
+        }
+    }
+
+    fn contains(&self, staff: usize) -> bool {
+        match self {
+            Self::All => true,
+            Self::Only(staves) => staves.contains(&staff),
+            Self::None => false,
+        }
+    }
+}
+
+impl PartSelector {
+    pub fn matches(&self, id: &str, name: Option<&str>) -> bool {
+        self.part == id || name.is_some_and(|name| name.trim().eq_ignore_ascii_case(&self.part))
+    }
+}
+
+/// A point in a score, used to choose an excerpt to rehearse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExcerptMark {
+    /// The measure with the given `number` attribute
+    Measure(String),
+    /// The measure containing the given `<rehearsal>` mark (ignoring case)
+    Rehearsal(String),
+    /// The segno which is the target of a D.S.
+    Segno,
+    /// The coda which is the target of a 'To Coda'
+    Coda,
+}
+
+impl FromStr for ExcerptMark {
+    type Err = anyhow::Error;
+
+    /// Parses marks like `12` (measure 12), `B` (rehearsal mark B), `segno` or `coda`.  Measures
+    /// and rehearsal marks can also be given explicitly, as `bar:12` or `mark:12`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        anyhow::ensure!(!s.is_empty(), "Expected a measure number or rehearsal mark");
+        Ok(match s.split_once(':') {
+            Some(("bar" | "measure", number)) => Self::Measure(number.trim().to_owned()),
+            Some(("mark" | "rehearsal", name)) => Self::Rehearsal(name.trim().to_owned()),
+            _ if s.eq_ignore_ascii_case("segno") => Self::Segno,
+            _ if s.eq_ignore_ascii_case("coda") => Self::Coda,
+            _ if s.chars().all(|c| c.is_ascii_digit()) => Self::Measure(s.to_owned()),
+            _ => Self::Rehearsal(s.to_owned()),
+        })
+    }
+}
+
+impl Display for ExcerptMark {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Measure(number) => write!(f, "measure {number}"),
+            Self::Rehearsal(name) => write!(f, "rehearsal mark {name:?}"),
+            Self::Segno => write!(f, "the segno"),
+            Self::Coda => write!(f, "the coda"),
+        }
+    }
+}
+
 impl MusicXmlScore {
-    /// Load a `MusicXmlScore` from a file.
-    pub fn load_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let path = path.as_ref();
-        let mut raw_bytes = Vec::new();
-        File::open(&path)
-            .context(format!("Error loading {path:?}"))?
-            .read_to_end(&mut raw_bytes)
-            .context(format!("Error reading {path:?}"))?;
-        let extension = path
-            .extension()
-            .context("Can't read a file with no extension")?;
-        Self::from_raw_bytes(&raw_bytes, extension)
-    }
-
-    /// Reads a `MusicXmlScore` from some bytes, using the given `extension` to determine whether
-    /// or not those bytes are compressed.
-    pub fn from_raw_bytes(bytes: &[u8], extension: &OsStr) -> anyhow::Result<Self> {
-        let mut decompressed_bytes = Vec::new();
-        let xml_bytes = match extension.to_str() {
-            Some("xml") => bytes, // No decompression necessary
-            Some("mxl") => {
-                let mut archive = zip::ZipArchive::new(Cursor::new(bytes))
-                    .context("Error extracting the zip archive")?;
-                let xml_file_name = archive
-                    .file_names()
-                    .find(|f| !f.contains('/')) // First file in the root directory of the archive
-                    .context("MusicXML archive should have at least one file")?
-                    .to_owned();
-                let mut xml_file = archive
-                    .by_name(&xml_file_name)
-                    .context("MusicXML file not found in the archive")?;
-                xml_file.read_to_end(&mut decompressed_bytes).unwrap();
-                &decompressed_bytes
-            }
-            _ => {
-                return Err(anyhow::Error::msg(format!(
-                    "Unknown file extension {extension:?} for MusicXML."
-                )));
-            }
-        };
-        Self::from_xml_bytes(xml_bytes)
+    /// Reads a `MusicXmlScore` from some bytes, which are either compressed MusicXML (`.mxl`) or
+    /// uncompressed MusicXML.  The format is determined from the bytes themselves, so the file
+    /// extension doesn't matter.
+    pub fn from_raw_bytes(bytes: &[u8], options: &LoadOptions) -> anyhow::Result<Self> {
+        if bytes.starts_with(ZIP_MAGIC_BYTES) {
+            let xml_bytes = read_mxl_root_file(bytes)?;
+            Self::from_xml_bytes(&xml_bytes, options)
+        } else if looks_like_xml(bytes) {
+            Self::from_xml_bytes(bytes, options) // No decompression necessary
+        } else {
+            Err(anyhow::Error::msg(
+                "File is neither MusicXML nor compressed MusicXML",
+            ))
+        }
     }
 
     /// Read a `MusicXmlScore` from bytes of XML (which may have been uncompressed from the file).
-    fn from_xml_bytes(xml_bytes: &[u8]) -> anyhow::Result<Self> {
+    fn from_xml_bytes(xml_bytes: &[u8], options: &LoadOptions) -> anyhow::Result<Self> {
         let tree =
             elementtree::Element::from_reader(xml_bytes).context("File contains invalid XML")?;
+        // Timewise scores are converted to partwise, so the rest of the code only has to deal
+        // with one layout
+        match tree.tag().name() {
+            "score-partwise" => Self::from_tree(tree, Layout::Partwise, options),
+            "score-timewise" => {
+                Self::from_tree(timewise_to_partwise(&tree), Layout::Timewise, options)
+            }
+            root_name => anyhow::bail!("Unknown MusicXML root element <{root_name}>"),
+        }
+    }
+
+    /// Create a `MusicXmlScore` from a `score-partwise` tree (e.g. one which has been converted
+    /// from another format).
+    pub fn from_partwise_tree(
+        tree: elementtree::Element,
+        options: &LoadOptions,
+    ) -> anyhow::Result<Self> {
+        Self::from_tree(tree, Layout::Partwise, options)
+    }
+
+    fn from_tree(
+        tree: elementtree::Element,
+        layout: Layout,
+        options: &LoadOptions,
+    ) -> anyhow::Result<Self> {
+        let loaded = load_events(&tree, options)?;
         Ok(Self {
-            whacks: load_whacks(&tree)?,
             tree,
+            layout,
+            events: loaded.events,
+            tie_continuations: loaded.tie_continuations,
+            warnings: loaded.warnings,
         })
     }
 }
 
+/// The two ways that a MusicXML document can be laid out.  Both contain the same information,
+/// but `score-partwise` nests `<measure>`s inside `<part>`s whereas `score-timewise` nests
+/// `<part>`s inside `<measure>`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    Partwise,
+    Timewise,
+}
+
+fn timewise_to_partwise(tree: &elementtree::Element) -> elementtree::Element {
+    // Parts are matched up between measures by their `id`
+    transpose_score(tree, "score-partwise", "measure", "part", |part_elem, _| {
+        part_elem.get_attr("id").unwrap_or_default().to_owned()
+    })
+}
+
+fn partwise_to_timewise(tree: &elementtree::Element) -> elementtree::Element {
+    // Measures are matched up between parts by their position in the part (because measure
+    // numbers aren't necessarily unique)
+    transpose_score(tree, "score-timewise", "part", "measure", |_, idx| {
+        idx.to_string()
+    })
+}
+
+/// Swap the nesting of two elements (`<part>`s and `<measure>`s) in a MusicXML document, creating
+/// a new document with the root tag `new_root`.  The children of the original `outer` elements
+/// are grouped by `inner_key`, which is given each `inner` element and its index within its
+/// parent.  Any other elements of the root (e.g. the `<part-list>`) are kept in place.
+fn transpose_score(
+    tree: &elementtree::Element,
+    new_root: &str,
+    outer: &str,
+    inner: &str,
+    inner_key: impl Fn(&elementtree::Element, usize) -> String,
+) -> elementtree::Element {
+    let mut new_tree = elementtree::Element::new(new_root);
+    for (name, value) in tree.attrs() {
+        new_tree.set_attr(name.name(), value);
+    }
+    new_tree.set_text(tree.text());
+
+    let mut new_outer_keys = Vec::<String>::new();
+    let mut new_outers = HashMap::<String, elementtree::Element>::new();
+    for elem in tree.children() {
+        if elem.tag().name() != outer {
+            new_tree.append_child(elem.clone()); // Header elements are kept as they are
+            continue;
+        }
+        for (idx, inner_elem) in elem.find_all(inner).enumerate() {
+            let key = inner_key(inner_elem, idx);
+            let new_outer = new_outers.entry(key.clone()).or_insert_with(|| {
+                new_outer_keys.push(key);
+                let mut new_outer = elementtree::Element::new(inner);
+                for (name, value) in inner_elem.attrs() {
+                    new_outer.set_attr(name.name(), value);
+                }
+                new_outer
+            });
+            // The new inner element has the attributes of the old outer element (e.g. the
+            // measure number), and the contents of the old inner element
+            let new_inner = new_outer.append_new_child(outer);
+            for (name, value) in elem.attrs() {
+                new_inner.set_attr(name.name(), value);
+            }
+            for child in inner_elem.children() {
+                new_inner.append_child(child.clone());
+            }
+        }
+    }
+    for key in new_outer_keys {
+        new_tree.append_child(new_outers.remove(&key).unwrap());
+    }
+    new_tree
+}
+
+/// The bytes which start every zip archive (and therefore every `.mxl` file).
+pub const ZIP_MAGIC_BYTES: &[u8] = b"PK\x03\x04";
+
+/// Determine whether some bytes look like the start of an XML document, i.e. they start with `<`
+/// after an optional byte-order mark and whitespace.
+pub fn looks_like_xml(bytes: &[u8]) -> bool {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    bytes.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'<')
+}
+
+/// Extract the MusicXML score from a compressed `.mxl` archive.  The archive's manifest
+/// (`META-INF/container.xml`) lists the score as its first `<rootfile>`.  If there's no
+/// manifest, we fall back to the first MusicXML file in the root of the archive.
+fn read_mxl_root_file(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(bytes)).context("Error extracting the zip archive")?;
+    let root_file_name = match read_archive_file(&mut archive, "META-INF/container.xml") {
+        Ok(container_bytes) => {
+            let container = elementtree::Element::from_reader(container_bytes.as_slice())
+                .context("MusicXML archive has an invalid META-INF/container.xml")?;
+            container
+                .find("rootfiles")
+                .into_iter()
+                .flat_map(|rootfiles| rootfiles.find_all("rootfile"))
+                // Other root files (e.g. PDF renderings) have different media types
+                .find(|rootfile| {
+                    rootfile.get_attr("media-type").is_none_or(|media_type| {
+                        media_type == "application/vnd.recordare.musicxml+xml"
+                    })
+                })
+                .and_then(|rootfile| rootfile.get_attr("full-path"))
+                .context("MusicXML archive's container.xml has no MusicXML <rootfile>")?
+                .to_owned()
+        }
+        Err(_) => archive
+            .file_names()
+            .find(|f| !f.contains('/') && (f.ends_with(".xml") || f.ends_with(".musicxml")))
+            .context("MusicXML archive should have at least one file")?
+            .to_owned(),
+    };
+    read_archive_file(&mut archive, &root_file_name)
+}
+
+pub fn read_archive_file(
+    archive: &mut zip::ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let mut file = archive
+        .by_name(name)
+        .with_context(|| format!("{name:?} not found in the archive"))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)
+        .with_context(|| format!("Error decompressing {name:?}"))?;
+    Ok(bytes)
+}
+
+/// The notes of a score, as returned by [`load_events`].  See the fields of [`MusicXmlScore`].
+#[derive(Debug)]
+struct LoadedEvents {
+    events: Vec<Event>,
+    tie_continuations: HashMap<usize, usize>,
+    warnings: Vec<Diagnostic>,
+}
+
 /// Walk a tree of XML [`Element`](elementtree::Element)s and determine at what times each note is
-/// played.
-fn load_whacks(tree: &elementtree::Element) -> anyhow::Result<HashMap<Note, Vec<Whack>>> {
-    let mut whacks = HashMap::<Note, Vec<Whack>>::new();
-
-    // Stores `(<duration of new bpm>, <new bpm>)`
-    let mut bpm_changes = Vec::<(Timestamp, f64)>::new();
-    let mut whacks_loaded_so_far = 0;
-    for (part_idx, part) in tree.find_all("part").enumerate() {
-        // MusicXML expresses all its note values as an integer multiple of some 'division' value
-        // (presumably to avoid floating point errors).  For each part, this is stored in the
-        // `attributes/divisions` element of the first measure.
-        let divs_per_beat = divisions_per_beat(part).ok_or_else(|| {
-            anyhow::Error::msg(format!(
-                "Couldn't load 'divisions' for part {}",
-                part_idx + 1
-            ))
-        })?;
-
-        // Extract the note names
-        let mut current_chord_start = Timestamp::ZERO;
-        let mut current_chord_note_idx = whacks_loaded_so_far;
-        let mut next_chord_start = Timestamp::ZERO;
-        for (measure_idx, measure) in part.children().enumerate() {
-            let measure_name = format!("measure {} of part {}", measure_idx + 1, part_idx + 1);
-            assert_eq!(measure.tag().name(), "measure");
-
-            for elem in measure.children() {
-                match elem.tag().name() {
-                    // Extract bpm changes from `direction` elements
-                    "direction" => {
-                        if let Some(sound_elem) = elem.find("sound") {
-                            if let Some(tempo_str) = sound_elem.get_attr("tempo") {
-                                let new_bpm = tempo_str.parse::<f64>().with_context(|| {
-                                    format!("Error loading tempo mark in {measure_name}")
-                                })?;
-                                bpm_changes.push((next_chord_start, new_bpm));
-                            }
-                        }
+/// played.  This also returns the `note_idx`s of the tie continuations (see
+/// [`MusicXmlScore::tie_continuations`]) and any problems which were skipped when loading
+/// leniently.
+fn load_events(tree: &elementtree::Element, options: &LoadOptions) -> anyhow::Result<LoadedEvents> {
+    // `<backup>` elements (and multiple parts) mean that notes and tempo changes aren't read in
+    // time order.  So we first load the position of every note in quarter notes, and only
+    // convert them to `Timestamp`s once all the tempo changes are known.
+    let mut loaded = LoadedNotes::default();
+    let parts = tree.find_all("part").collect_vec();
+
+    // Expand repeats and jumps into the order in which the measures are actually played.  All
+    // the parts should have the same measures, but the markings may only be written in some of
+    // them, so the markings of every part are combined.
+    let mut measure_jumps = Vec::<MeasureJumps>::new();
+    for part in &parts {
+        let part_id = part.get_attr("id").unwrap_or_default();
+        for (child_idx, child) in part.children().enumerate() {
+            if child.tag().name() != "measure" {
+                let location = Location {
+                    part_id: Some(part_id.to_owned()),
+                    path: element_path(part_id, None, part, child_idx),
+                    ..Location::default()
+                };
+                let reason = Reason::Unsupported(format!("<{}> in a <part>", child.tag().name()));
+                loaded.report(Diagnostic { location, reason }, options)?;
+            }
+        }
+        let mut current_ending = None;
+        for (measure_idx, measure) in part.find_all("measure").enumerate() {
+            let jumps = read_measure_jumps(measure, &mut current_ending);
+            match measure_jumps.get_mut(measure_idx) {
+                Some(existing_jumps) => existing_jumps.merge(&jumps),
+                None => measure_jumps.push(jumps),
+            }
+        }
+    }
+    let playback_order = repeats::playback_order(&measure_jumps);
+    let excerpt = excerpt_range(&parts, &measure_jumps, &playback_order, options)?;
+    // The positions (in quarter notes) of the start and end of the excerpt, as found by the first
+    // part which reaches them
+    let mut excerpt_start = None::<f64>;
+    let mut excerpt_end = None::<f64>;
+
+    // Find which parts are played, and make sure that every selector picks out some part
+    let score_parts = read_part_list(tree);
+    let part_ids = parts
+        .iter()
+        .map(|part| part.get_attr("id").unwrap_or_default())
+        .collect_vec();
+    for selector in &options.parts {
+        let matches_any_part = part_ids
+            .iter()
+            .any(|id| selector.matches(id, score_parts.get(id).and_then(ScorePart::name)));
+        if !matches_any_part {
+            anyhow::bail!("No part has the id or name {:?}", selector.part);
+        }
+    }
+
+    let mut part_first_note_idx = 0;
+    let mut score_end = 0.0f64;
+    for (part_idx, part) in parts.into_iter().enumerate() {
+        // Parts which aren't played are still read, since they contain tempo marks which affect
+        // the played parts
+        let part_id = part_ids[part_idx];
+        let score_part = score_parts.get(part_id);
+        let part_info = PartInfo {
+            id: part_id,
+            instrument_names: score_part.map(|p| &p.instrument_names),
+            played_staves: PlayedStaves::for_part(
+                &options.parts,
+                part_id,
+                score_part.and_then(ScorePart::name),
+            ),
+        };
+
+        // Measures can be played several times, so determine up-front which `note_idx` each
+        // measure starts at.  That way, every performance of a note refers back to the same
+        // `<note>` element.  For the same reason, we also determine the attributes (divisions
+        // and time signature) which are active at the start of each measure.
+        let measures = part.find_all("measure").collect_vec();
+        let Some(mut attributes) = MeasureAttributes::initial(part) else {
+            // Without any divisions, none of the part's notes can be placed
+            let location = Location {
+                part_id: Some(part_id.to_owned()),
+                path: format!("part[@id={part_id:?}]"),
+                ..Location::default()
+            };
+            let reason = Reason::MissingDivisions;
+            loaded.report(Diagnostic { location, reason }, options)?;
+            part_first_note_idx += measures.iter().map(|m| num_counted_notes(m)).sum::<usize>();
+            continue;
+        };
+        let mut measure_first_note_idxs = Vec::with_capacity(measures.len());
+        let mut measure_attributes = Vec::with_capacity(measures.len());
+        let mut measure_octave_shifts = Vec::with_capacity(measures.len());
+        let mut octave_shifts = OctaveShifts::default();
+        let mut next_note_idx = part_first_note_idx;
+        for measure in &measures {
+            measure_first_note_idxs.push(next_note_idx);
+            measure_attributes.push(attributes);
+            measure_octave_shifts.push(octave_shifts.clone());
+            next_note_idx += num_counted_notes(measure);
+            for attributes_elem in measure.find_all("attributes") {
+                attributes.update(attributes_elem);
+            }
+            for direction_elem in measure.find_all("direction") {
+                octave_shifts.update(direction_elem);
+            }
+        }
+        part_first_note_idx = next_note_idx;
+        loaded.open_ties.clear(); // Ties can't continue between parts
+
+        let mut measure_start = 0.0;
+        for (playback_idx, &measure_idx) in playback_order.iter().enumerate() {
+            if playback_idx == excerpt.start {
+                excerpt_start.get_or_insert(measure_start);
+            }
+            if playback_idx == excerpt.end {
+                excerpt_end.get_or_insert(measure_start);
+            }
+            let Some(measure) = measures.get(measure_idx) else {
+                continue; // This part is missing some measures
+            };
+            let mut note_idx = measure_first_note_idxs[measure_idx];
+
+            let mut cursor = MeasureCursor::new(
+                measure_start,
+                measure_attributes[measure_idx],
+                measure_octave_shifts[measure_idx].clone(),
+                measure.get_attr("implicit") == Some("yes"),
+                &part_info,
+                options,
+            );
+            for (child_idx, elem) in measure.children().enumerate() {
+                let first_note_idx = note_idx;
+                let result = match elem.tag().name() {
+                    // Move the cursor without adding any notes (used to write multiple voices
+                    // into the same measure)
+                    "backup" => cursor.backup(elem),
+                    "forward" => cursor.forward(elem),
+                    // `<attributes>` can change the divisions or time signature mid-measure
+                    "attributes" => {
+                        cursor.attributes.update(elem);
+                        Ok(())
                     }
+                    // Extract tempo changes from `direction` elements
+                    "direction" => cursor.read_direction(elem, &mut loaded),
+                    // `<sound>` can also be written outside a `<direction>`
+                    "sound" => {
+                        let position = cursor.quarters_at(cursor.position);
+                        cursor.read_sound_swing(elem, position, &mut loaded)
+                    }
+                    "barline" => {
+                        cursor.read_barline(elem, &mut loaded);
+                        Ok(())
+                    }
+                    // Chord symbols and figured bass don't affect the notes or their timings
+                    "harmony" | "figured-bass" => Ok(()),
                     // Extract boomwhacker notes from `note` elements
-                    "note" => {
-                        add_whack(
-                            elem,
-                            divs_per_beat,
-                            &bpm_changes,
-                            &mut next_chord_start,
-                            &mut current_chord_start,
-                            &mut current_chord_note_idx,
-                            &mut whacks_loaded_so_far,
-                            &mut whacks,
-                        )
-                        .ok_or_else(|| {
-                            anyhow::Error::msg(format!("Error loading note in {measure_name}",))
-                        })?;
+                    "note" => cursor.add_note(elem, &mut note_idx, &mut loaded),
+                    _ => Ok(()),
+                };
+                // Skipped notes still have a `note_idx`, so that the later notes are still
+                // annotated correctly
+                let is_counted_note = is_counted_note(elem);
+                if result.is_err() && is_counted_note {
+                    note_idx = first_note_idx + 1;
+                }
+                let location = || Location {
+                    part_id: Some(part_id.to_owned()),
+                    measure_number: measure.get_attr("number").map(str::to_owned),
+                    note_idx: is_counted_note.then_some(first_note_idx),
+                    path: element_path(part_id, Some(measure_idx), measure, child_idx),
+                };
+                for reason in std::mem::take(&mut cursor.warnings) {
+                    let location = location();
+                    loaded.warn(Diagnostic { location, reason });
+                }
+                if let Err(reason) = result {
+                    let location = location();
+                    loaded.report(Diagnostic { location, reason }, options)?;
+                }
+            }
+            cursor.finish(&mut loaded);
+            measure_start = cursor.measure_end();
+        }
+        score_end = score_end.max(measure_start);
+        // Gradual changes with unfinished extension lines last until the next tempo mark
+        for (start, change) in std::mem::take(&mut loaded.open_gradual_changes).into_values() {
+            loaded.tempo_marks.add_gradual(start, None, change);
+        }
+    }
+
+    // Now that all the tempo changes are known, convert the note positions into `Timestamp`s
+    let tempo_map = loaded.tempo_marks.build(score_end);
+    let to_timestamp = |quarters: f64| Timestamp::from_secs(tempo_map.secs_at(quarters));
+    let excerpt = excerpt_start.unwrap_or(0.0)..excerpt_end.unwrap_or(f64::INFINITY);
+    let mut events = loaded
+        .whacks
+        .into_iter()
+        .filter(|whack| excerpt.contains(&whack.quarters))
+        .map(|whack| {
+            let lead_in = Duration::from_secs_f64(whack.lead_in);
+            Event {
+                onset: to_timestamp(whack.quarters) - lead_in,
+                release: to_timestamp(whack.quarters + whack.length) - lead_in,
+                instrument: whack.instrument,
+                note_idx: whack.note_idx,
+                chord_note_idx: whack.chord_note_idx,
+                articulations: whack.articulations,
+            }
+        })
+        .collect_vec();
+    // Sort the events by time, and return
+    events.sort();
+    Ok(LoadedEvents {
+        events,
+        tie_continuations: loaded.tie_continuations,
+        warnings: loaded.warnings,
+    })
+}
+
+/// Find which measures of the `playback_order` are in the excerpt chosen by the `options`,
+/// returning a range of indices into `playback_order`.
+fn excerpt_range(
+    parts: &[&elementtree::Element],
+    measure_jumps: &[MeasureJumps],
+    playback_order: &[usize],
+    options: &LoadOptions,
+) -> anyhow::Result<Range<usize>> {
+    let part_measures = parts
+        .iter()
+        .map(|part| part.find_all("measure").collect_vec())
+        .collect_vec();
+    // Is the `measure_idx`th measure (of any part) at the given `mark`?
+    let is_at = |mark: &ExcerptMark, measure_idx: usize| {
+        let mut measures = part_measures.iter().filter_map(|m| m.get(measure_idx));
+        match mark {
+            ExcerptMark::Measure(number) => {
+                measures.any(|measure| measure.get_attr("number") == Some(number))
+            }
+            ExcerptMark::Rehearsal(name) => measures
+                .flat_map(|measure| measure.find_all("direction"))
+                .flat_map(|direction| direction.find_all("direction-type"))
+                .flat_map(|direction_type| direction_type.find_all("rehearsal"))
+                .any(|rehearsal| rehearsal.text().trim().eq_ignore_ascii_case(name)),
+            ExcerptMark::Segno => measure_jumps[measure_idx].segno.is_some(),
+            ExcerptMark::Coda => measure_jumps[measure_idx].coda.is_some(),
+        }
+    };
+    // Find the first time that `mark` is played, at or after `playback_order[from]`
+    let find = |mark: &ExcerptMark, from: usize| {
+        (playback_order.iter().skip(from))
+            .position(|&measure_idx| is_at(mark, measure_idx))
+            .map(|offset| from + offset)
+    };
+
+    let start = match &options.excerpt_start {
+        Some(mark) => find(mark, 0).with_context(|| format!("The score doesn't contain {mark}"))?,
+        None => 0,
+    };
+    let end = match &options.excerpt_end {
+        Some(mark) => {
+            let end = match mark {
+                ExcerptMark::Measure(_) => find(mark, start).map(|idx| idx + 1),
+                _ => find(mark, start + 1),
+            };
+            end.with_context(|| {
+                format!("The score doesn't contain {mark} after the excerpt starts")
+            })?
+        }
+        None => playback_order.len(),
+    };
+    Ok(start..end)
+}
+
+/// Does a `<note>` have a `note_idx`?  Every `<note>` except rests is counted, even if it isn't
+/// played.
+fn is_counted_note(elem: &elementtree::Element) -> bool {
+    elem.tag().name() == "note" && elem.find("rest").is_none()
+}
+
+fn num_counted_notes(measure: &elementtree::Element) -> usize {
+    measure
+        .children()
+        .filter(|elem| is_counted_note(elem))
+        .count()
+}
+
+/// The path of the `child_idx`th child of `parent` (which is either a `<part>` or the
+/// `measure_idx`th `<measure>` of a part), in the form used by [`Location::path`].
+fn element_path(
+    part_id: &str,
+    measure_idx: Option<usize>,
+    parent: &elementtree::Element,
+    child_idx: usize,
+) -> String {
+    let mut path = format!("part[@id={part_id:?}]");
+    if let Some(measure_idx) = measure_idx {
+        path.push_str(&format!("/measure[{}]", measure_idx + 1));
+    }
+    let children = parent.children().collect_vec();
+    let tag = children[child_idx].tag().name();
+    let tag_idx = children[..child_idx]
+        .iter()
+        .filter(|sibling| sibling.tag().name() == tag)
+        .count();
+    path.push_str(&format!("/{tag}[{}]", tag_idx + 1));
+    path
+}
+
+/// The notes and tempo marks which have been loaded so far, with positions still measured in
+/// quarter notes.
+#[derive(Debug, Default)]
+struct LoadedNotes {
+    whacks: Vec<LoadedWhack>,
+    tempo_marks: TempoMarks,
+    /// Gradual tempo changes (e.g. `rit. - - -`) whose extension lines haven't finished yet,
+    /// keyed by the extension line's `(<element name>, <number>)`.  The values are `(<start
+    /// position>, <change>)`.
+    open_gradual_changes: HashMap<(String, String), (f64, GradualChange)>,
+    /// For every tie which hasn't yet been finished, the index within `whacks` of the note which
+    /// started it
+    open_ties: HashMap<Instrument, usize>,
+    /// See [`MusicXmlScore::tie_continuations`]
+    tie_continuations: HashMap<usize, usize>,
+    /// The problems which were skipped when loading leniently, along with anything else the
+    /// user should know about (e.g. microtonal notes which were rounded)
+    warnings: Vec<Diagnostic>,
+}
+
+impl LoadedNotes {
+    /// Report a problem with the score.  When loading strictly, the problem is returned as an
+    /// error.  Otherwise it's kept as a warning, and the caller skips the offending element.
+    fn report(&mut self, diagnostic: Diagnostic, options: &LoadOptions) -> Result<(), Diagnostic> {
+        if !options.lenient {
+            return Err(diagnostic);
+        }
+        self.warn(diagnostic);
+        Ok(())
+    }
+
+    /// Keep a warning about the score, which doesn't stop it from loading (even when loading
+    /// strictly).
+    fn warn(&mut self, diagnostic: Diagnostic) {
+        // Repeated measures are read several times, but each problem is only reported once
+        if !self.warnings.contains(&diagnostic) {
+            self.warnings.push(diagnostic);
+        }
+    }
+}
+
+/// An [`Event`] whose position is still measured in quarter notes from the start of the score.
+#[derive(Debug, Clone)]
+struct LoadedWhack {
+    instrument: Instrument,
+    quarters: f64,
+    /// The number of quarter notes for which this note is held (including any tied notes)
+    length: f64,
+    /// The number of seconds before `quarters` that this note is actually played (used to place
+    /// grace notes just before the note they lead into)
+    lead_in: f64,
+    note_idx: usize,
+    chord_note_idx: usize,
+    articulations: Articulations,
+}
+
+/// The position of the notes being read from a single `<measure>`.
+#[derive(Debug)]
+struct MeasureCursor<'opts> {
+    options: &'opts LoadOptions,
+    part: &'opts PartInfo<'opts>,
+    /// Number of quarter notes between the start of the score and the start of this measure
+    measure_start: f64,
+    /// The currently active divisions, time signature and transposition
+    attributes: MeasureAttributes,
+    /// The currently active octave-shift lines
+    octave_shifts: OctaveShifts,
+    /// Is this measure allowed to be shorter than its time signature (e.g. a pickup measure)?
+    is_implicit: bool,
+    /// The position of the next note to be read, in quarter notes from the start of the
+    /// measure.  Notes and `<forward>` move this forwards, whereas `<backup>` moves it
+    /// backwards.
+    position: f64,
+    /// The furthest that `position` has reached in this measure
+    furthest_position: f64,
+    /// The most recent chord (or single note) started by each `(staff, voice)` pair.  Following
+    /// `<chord/>` notes share its start time.
+    current_chords: HashMap<(usize, usize), ChordStart>,
+    /// The most recent chord started in any voice, used if a `<chord/>` note doesn't share a
+    /// voice with the note before it
+    last_chord: Option<ChordStart>,
+    /// The grace notes in each `(staff, voice)` which are waiting for the note they lead into
+    pending_graces: HashMap<(usize, usize), Vec<PendingGrace>>,
+    /// Problems with the element being read which don't stop it from being loaded
+    warnings: Vec<Reason>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ChordStart {
+    /// Start of the chord, in quarter notes from the start of the measure
+    position: f64,
+    /// The `note_idx` of the first `<note>` in the chord
+    note_idx: usize,
+}
+
+#[derive(Debug, Clone)]
+struct PendingGrace {
+    instrument: Instrument,
+    note_idx: usize,
+    chord_note_idx: usize,
+}
+
+impl<'opts> MeasureCursor<'opts> {
+    fn new(
+        measure_start: f64,
+        attributes: MeasureAttributes,
+        octave_shifts: OctaveShifts,
+        is_implicit: bool,
+        part: &'opts PartInfo<'opts>,
+        options: &'opts LoadOptions,
+    ) -> Self {
+        Self {
+            options,
+            part,
+            measure_start,
+            attributes,
+            octave_shifts,
+            is_implicit,
+            position: 0.0,
+            furthest_position: 0.0,
+            current_chords: HashMap::new(),
+            last_chord: None,
+            pending_graces: HashMap::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Number of quarter notes between the start of the score and the end of this measure.
+    fn measure_end(&self) -> f64 {
+        // A measure with no notes still lasts for its whole time signature (so that parts with
+        // empty measures stay in time with the other parts)
+        let length = match self.attributes.time_signature {
+            Some(time_sig) if self.furthest_position == 0.0 && !self.is_implicit => {
+                time_sig.quarters_per_measure()
+            }
+            _ => self.furthest_position,
+        };
+        self.quarters_at(length)
+    }
+
+    fn quarters_at(&self, position: f64) -> f64 {
+        self.measure_start + position
+    }
+
+    /// Read the duration of a `<note>`, `<backup>` or `<forward>` element, in quarter notes.
+    fn duration(&self, elem: &elementtree::Element) -> Result<f64, Reason> {
+        let divisions = element_duration(elem).ok_or(Reason::MissingDuration)?;
+        Ok(divisions / self.attributes.divisions)
+    }
+
+    fn backup(&mut self, elem: &elementtree::Element) -> Result<(), Reason> {
+        let duration = self.duration(elem)?;
+        // Allow a little leeway for floating-point errors
+        if duration > self.position + 1e-9 {
+            return Err(Reason::BackupTooLong);
+        }
+        self.position = (self.position - duration).max(0.0);
+        Ok(())
+    }
+
+    fn forward(&mut self, elem: &elementtree::Element) -> Result<(), Reason> {
+        self.advance(self.duration(elem)?);
+        Ok(())
+    }
+
+    fn advance(&mut self, quarters: f64) {
+        self.position += quarters;
+        self.furthest_position = self.furthest_position.max(self.position);
+    }
+
+    /// The position (in quarter notes) of a `<direction>`, taking its `<offset>` into account.
+    fn direction_position(&self, elem: &elementtree::Element) -> f64 {
+        let offset_divs = elem
+            .find("offset")
+            .and_then(|offset_elem| offset_elem.text().parse::<f64>().ok())
+            .unwrap_or(0.0);
+        self.quarters_at(self.position + offset_divs / self.attributes.divisions)
+    }
+
+    /// Read the tempo marks from a `<direction>`: explicit tempos, tempo words and the extension
+    /// lines which show how long gradual tempo changes last.
+    fn read_direction(
+        &mut self,
+        elem: &elementtree::Element,
+        loaded: &mut LoadedNotes,
+    ) -> Result<(), Reason> {
+        self.octave_shifts.update(elem);
+        let position = self.direction_position(elem);
+        let direction_types = elem.find_all("direction-type").collect_vec();
+        // Finish any gradual changes whose extension lines end here
+        for (key, _) in extension_lines(&direction_types).filter(|(_, ty)| *ty == "stop") {
+            if let Some((start, change)) = loaded.open_gradual_changes.remove(&key) {
+                loaded
+                    .tempo_marks
+                    .add_gradual(start, Some(position), change);
+            }
+        }
+
+        let words = direction_types
+            .iter()
+            .flat_map(|direction_type| direction_type.find_all("words"))
+            .map(|words_elem| words_elem.text())
+            .join(" ");
+        // `<swing>` elements say exactly how to swing, so they override any `Swing` text
+        match elem
+            .find("sound")
+            .filter(|sound| sound.find("swing").is_some())
+        {
+            Some(sound_elem) => self.read_sound_swing(sound_elem, position, loaded)?,
+            None => {
+                if let Some(swing) = Swing::from_words(&words) {
+                    self.set_swing(position, swing, loaded);
+                }
+            }
+        }
+
+        if let Some(tempo) = direction_tempo(elem)? {
+            loaded.tempo_marks.set_tempo(position, tempo);
+            return Ok(());
+        }
+        match TempoWords::parse(&words) {
+            Some(TempoWords::Gradual(change)) => {
+                // Gradual changes last until the end of their extension line (if there is one)
+                let line_start = extension_lines(&direction_types).find(|(_, ty)| *ty == "start");
+                match line_start {
+                    Some((key, _)) => {
+                        loaded.open_gradual_changes.insert(key, (position, change));
                     }
-                    _ => {}
+                    None => loaded.tempo_marks.add_gradual(position, None, change),
                 }
             }
+            Some(tempo_words) => {
+                let beat_length = self
+                    .attributes
+                    .time_signature
+                    .map_or(1.0, TimeSignature::beat_length);
+                loaded
+                    .tempo_marks
+                    .add_words(position, tempo_words, beat_length);
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Read the `<swing>` of a `<sound>` element (if it has one) at the given `position`.
+    fn read_sound_swing(
+        &self,
+        sound_elem: &elementtree::Element,
+        position: f64,
+        loaded: &mut LoadedNotes,
+    ) -> Result<(), Reason> {
+        let Some(swing_elem) = sound_elem.find("swing") else {
+            return Ok(());
+        };
+        let swing = read_swing(swing_elem)?;
+        self.set_swing(position, swing, loaded);
+        Ok(())
+    }
+
+    /// Change the swing from `position` onwards, unless swing is being ignored.
+    fn set_swing(&self, position: f64, swing: Option<Swing>, loaded: &mut LoadedNotes) {
+        if self.options.timing == TimingModel::Swing {
+            loaded.tempo_marks.set_swing(position, swing);
+        }
+    }
+
+    /// Read a `<barline>`, adding a hold if it has a fermata.
+    fn read_barline(&self, elem: &elementtree::Element, loaded: &mut LoadedNotes) {
+        if elem.find("fermata").is_some() {
+            let beat_length = self
+                .attributes
+                .time_signature
+                .map_or(1.0, TimeSignature::beat_length);
+            let position = self.quarters_at(self.position);
+            loaded.tempo_marks.add_hold(position, beat_length);
+        }
+    }
+
+    /// Read a `<note>` element, adding a [`LoadedWhack`] if it isn't a rest or the continuation
+    /// of a tie.  If the note can't be read, it still takes up time in the measure (if its
+    /// duration is known).
+    fn add_note(
+        &mut self,
+        elem: &elementtree::Element,
+        note_idx: &mut usize,
+        loaded: &mut LoadedNotes,
+    ) -> Result<(), Reason> {
+        // If no staff/voice tag is given, assign it to the first staff/voice
+        let staff = match elem.find("staff") {
+            Some(staff_elem) => (staff_elem.text().trim().parse::<usize>().ok())
+                .ok_or_else(|| Reason::BadStaff(staff_elem.text().to_owned()))?,
+            None => 1,
+        };
+        let voice = match elem.find("voice") {
+            Some(voice_elem) => (voice_elem.text().trim().parse::<usize>().ok())
+                .ok_or_else(|| Reason::BadVoice(voice_elem.text().to_owned()))?,
+            None => 1,
+        };
+        let is_chord = elem.find("chord").is_some();
+        let instrument = self.note_instrument(elem, staff);
+
+        let is_played = self.part.played_staves.contains(staff);
+
+        // Grace notes take up no time in the measure, so they can't be positioned until we
+        // reach the note they lead into
+        if elem.find("grace").is_some() {
+            let instrument = instrument?;
+            if let Some(instrument) = instrument.clone().filter(|_| is_played) {
+                let graces = self.pending_graces.entry((staff, voice)).or_default();
+                let chord_note_idx = match graces.last() {
+                    Some(last_grace) if is_chord => last_grace.chord_note_idx,
+                    _ => *note_idx,
+                };
+                graces.push(PendingGrace {
+                    instrument,
+                    note_idx: *note_idx,
+                    chord_note_idx,
+                });
+                *note_idx += 1;
+            } else if instrument.is_some() {
+                *note_idx += 1;
+            }
+            return Ok(());
+        }
+
+        // Determine where this note starts.  If it's the first note/rest in a chord, it also
+        // moves the cursor on to where the next note will start
+        let duration = self.duration(elem)?;
+        let chord = if is_chord {
+            *(self.current_chords.get(&(staff, voice)))
+                .or(self.last_chord.as_ref())
+                .ok_or(Reason::ChordWithoutStart)?
+        } else {
+            let chord = ChordStart {
+                position: self.position,
+                note_idx: *note_idx,
+            };
+            self.add_pending_graces((staff, voice), chord.position, loaded);
+            self.advance(duration);
+            self.current_chords.insert((staff, voice), chord);
+            self.last_chord = Some(chord);
+            chord
+        };
+
+        // Fermatas (on notes or rests) hold the music for roughly twice the written length
+        let articulations = read_articulations(elem);
+        if articulations.fermata {
+            let hold_position = self.quarters_at(chord.position + duration);
+            loaded.tempo_marks.add_hold(hold_position, duration);
+        }
+
+        // Rests don't generate whacks.  Notes which couldn't be read are only skipped after
+        // they've moved the cursor on, so that the following notes are still in time.
+        let Some(instrument) = instrument? else {
+            return Ok(());
+        };
+        // Cue notes (usually other players' parts, printed small for reference) take up time,
+        // but aren't played.  Neither are the notes of parts or staves which weren't selected.
+        let is_unplayed_cue = elem.find("cue").is_some() && !self.options.include_cue_notes;
+        if is_unplayed_cue || !is_played {
+            *note_idx += 1;
+            return Ok(());
+        }
+
+        // Actually add the note.  Staccato notes only sound for half of their written length.
+        let length = if articulations.staccato {
+            duration / 2.0
+        } else {
+            duration
+        };
+        let (is_tie_start, is_tie_stop) = tie_types(elem);
+        // If this note continues a tie, extend the tied note instead of whacking again
+        let open_tie = is_tie_stop
+            .then(|| loaded.open_ties.remove(&instrument))
+            .flatten();
+        let whack_idx = match open_tie {
+            Some(whack_idx) => {
+                let tied_whack = &mut loaded.whacks[whack_idx];
+                tied_whack.length += length;
+                // A tied note is only whacked once, but it's sustained if any part of it is
+                tied_whack.articulations.tremolo |= articulations.tremolo;
+                tied_whack.articulations.fermata |= articulations.fermata;
+                loaded
+                    .tie_continuations
+                    .insert(*note_idx, tied_whack.note_idx);
+                whack_idx
+            }
+            None => {
+                loaded.whacks.push(LoadedWhack {
+                    instrument: instrument.clone(),
+                    quarters: self.quarters_at(chord.position),
+                    length,
+                    lead_in: 0.0,
+                    note_idx: *note_idx,
+                    chord_note_idx: chord.note_idx,
+                    articulations,
+                });
+                loaded.whacks.len() - 1
+            }
+        };
+        if is_tie_start {
+            loaded.open_ties.insert(instrument, whack_idx);
+        }
+        *note_idx += 1;
+        Ok(())
+    }
+
+    /// Determine which [`Instrument`] plays a `<note>` on the given `staff`, or `None` if it's a
+    /// rest.
+    fn note_instrument(
+        &mut self,
+        elem: &elementtree::Element,
+        staff: usize,
+    ) -> Result<Option<Instrument>, Reason> {
+        let pitch_elem = elem.find("pitch");
+        let unpitched_elem = elem.find("unpitched");
+        if elem.find("rest").is_some() {
+            if pitch_elem.is_some() || unpitched_elem.is_some() {
+                let construct = "<note> which is both a rest and a pitch";
+                return Err(Reason::Unsupported(construct.to_owned()));
+            }
+            return Ok(None);
+        }
+        if let Some(pitch_elem) = pitch_elem {
+            // `<pitch>` is the written pitch, which we move to the sounding pitch
+            let (natural, alter) = read_pitch(pitch_elem)?;
+            let mut transposition = self.attributes.transpose;
+            if self.options.apply_octave_shifts {
+                transposition += self.octave_shifts.semitones(staff);
+            }
+            Ok(Some(self.pitched_instrument(
+                natural,
+                alter,
+                transposition,
+            )?))
+        } else if let Some(unpitched_elem) = unpitched_elem {
+            Ok(Some(self.part.unpitched_instrument(elem, unpitched_elem)))
+        } else {
+            // Every note must be pitched, unpitched or a rest
+            let construct = "<note> without a <pitch>, <unpitched> or <rest>";
+            Err(Reason::Unsupported(construct.to_owned()))
+        }
+    }
+
+    /// Determine which [`Instrument`] plays a note which is `alter` semitones above the
+    /// `natural` note, applying [`LoadOptions::microtones`] if it's between two semitones.  The
+    /// note sounds `transposition` semitones away from where it's written.
+    fn pitched_instrument(
+        &mut self,
+        natural: Note,
+        alter: f64,
+        transposition: i32,
+    ) -> Result<Instrument, Reason> {
+        let with_alter = |alter: f64| {
+            let semis = (i32::from(natural.semis_above_c0))
+                .saturating_add(alter as i32)
+                .saturating_add(transposition);
+            let semis_above_c0 = i8::try_from(semis).map_err(|_| Reason::PitchOutOfRange(semis))?;
+            Ok(Note { semis_above_c0 })
+        };
+        if alter.fract() == 0.0 {
+            return with_alter(alter).map(Instrument::Whacker);
         }
+        let policy = self.options.microtones;
+        let reason = Reason::MicrotonalAlter(alter.to_string(), policy);
+        let instrument = match policy {
+            MicrotonePolicy::Round => Instrument::Whacker(with_alter(alter.round())?),
+            MicrotonePolicy::Reject => return Err(reason),
+            MicrotonePolicy::Distinct => Instrument::Microtonal {
+                note: with_alter(alter.floor())?,
+                cents: (alter.rem_euclid(1.0) * 100.0).round().clamp(1.0, 99.0) as u8,
+            },
+        };
+        self.warnings.push(reason);
+        Ok(instrument)
     }
 
-    // Sort the whack times, and return
-    for times in whacks.values_mut() {
-        times.sort();
+    /// Add whacks for the grace notes which lead into a note at `position` (in quarter notes from
+    /// the start of the measure).  The grace notes are played just before that note, each chord
+    /// of grace notes taking [`LoadOptions::grace_note_lead_in`].
+    fn add_pending_graces(
+        &mut self,
+        staff_and_voice: (usize, usize),
+        position: f64,
+        loaded: &mut LoadedNotes,
+    ) {
+        let Some(graces) = self.pending_graces.remove(&staff_and_voice) else {
+            return;
+        };
+        let num_grace_chords = graces.iter().map(|g| g.chord_note_idx).unique().count();
+        let lead_in = self.options.grace_note_lead_in.as_secs_f64();
+        let mut grace_chord_idx = 0;
+        for (idx, grace) in graces.iter().enumerate() {
+            if idx > 0 && grace.chord_note_idx != graces[idx - 1].chord_note_idx {
+                grace_chord_idx += 1;
+            }
+            loaded.whacks.push(LoadedWhack {
+                instrument: grace.instrument.clone(),
+                quarters: self.quarters_at(position),
+                length: 0.0,
+                lead_in: (num_grace_chords - grace_chord_idx) as f64 * lead_in,
+                note_idx: grace.note_idx,
+                chord_note_idx: grace.chord_note_idx,
+                articulations: Articulations::default(),
+            });
+        }
+    }
+
+    /// Finish reading this measure.  Any grace notes which aren't followed by a note in their
+    /// voice lead into the end of the measure.
+    fn finish(&mut self, loaded: &mut LoadedNotes) {
+        let voices_with_graces = self.pending_graces.keys().copied().sorted().collect_vec();
+        for staff_and_voice in voices_with_graces {
+            self.add_pending_graces(staff_and_voice, self.furthest_position, loaded);
+        }
     }
-    Ok(whacks)
 }
 
-// TODO: Wrap the context into a struct
-#[must_use]
-fn add_whack(
-    elem: &elementtree::Element,
-    divs_per_beat: usize,
-    bpm_changes: &[(Timestamp, f64)],
-    next_chord_start: &mut Timestamp,
-    current_chord_start: &mut Timestamp,
-    chord_note_idx: &mut usize,
-    whacks_loaded_so_far: &mut usize,
-    whacks: &mut HashMap<Note, Vec<Whack>>,
-) -> Option<()> {
-    // Check that multiple voicings aren't being used
-    let voice = match elem.find("voice") {
-        Some(voice_elem) => voice_elem.text().parse::<usize>().ok()?,
-        None => 1, // If no voice tag is given, assign it to the first voice
+/// Read a `<pitch>` element, returning the natural [`Note`] given by its `<step>` and `<octave>`,
+/// and the number of semitones by which it's altered.
+fn read_pitch(pitch_elem: &elementtree::Element) -> Result<(Note, f64), Reason> {
+    let child_text =
+        |name: &'static str| pitch_elem.find(name).map_or("", |elem| elem.text()).trim();
+    let octave_text = child_text("octave");
+    let octave = (octave_text.parse::<i8>().ok())
+        .filter(|octave| (0..=9).contains(octave))
+        .ok_or_else(|| Reason::BadOctave(octave_text.to_owned()))?;
+    let alter = match pitch_elem.find("alter") {
+        Some(alter_elem) => (alter_elem.text().trim().parse::<f64>().ok())
+            .filter(|alter| alter.abs() <= 12.0)
+            .ok_or_else(|| Reason::BadAlter(alter_elem.text().to_owned()))?,
+        None => 0.0,
     };
-    assert_eq!(voice, 1, "Multiple voices aren't implemented yet");
-
-    // If this is the first note/rest in a chord, compute the start time of the
-    // next note to come after it
-    if elem.find("chord").is_none() {
-        let note_duration = note_duration(elem, divs_per_beat, bpm_changes, *next_chord_start)?;
-
-        // We're starting a chord (which may have only one note), so mark that
-        // the *next* note will come after this one
-        *current_chord_start = *next_chord_start;
-        *chord_note_idx = *whacks_loaded_so_far;
-        next_chord_start.secs.0 += note_duration.as_secs_f64();
-    }
-
-    // Actually add the note
-    match elem.find("pitch") {
-        // If the 'note' has a pitch, work out which boomwhacker this note
-        // actually plays and add its start
-        Some(pitch_elem) => {
-            let octave = pitch_elem.find("octave")?.text().parse::<i8>().ok()?;
-            let note_name = pitch_elem.find("step")?.text();
-            let alter = match pitch_elem.find("alter") {
-                Some(alter_elem) => alter_elem.text().parse::<i8>().ok()?,
-                None => 0,
-            };
-            let whack = Whack {
-                timestamp: *current_chord_start,
-                note_idx: *whacks_loaded_so_far,
-                chord_note_idx: *chord_note_idx,
+    let step = child_text("step");
+    let natural =
+        Note::from_note(octave, step, 0).ok_or_else(|| Reason::BadStep(step.to_owned()))?;
+    Ok((natural, alter))
+}
+
+/// The information about a part which is stored in the `<part-list>`.
+#[derive(Debug, Clone, Default)]
+struct ScorePart {
+    part_name: Option<String>,
+    /// The `<instrument-name>` of each of the part's `<score-instrument>`s, keyed by `id`
+    instrument_names: HashMap<String, String>,
+}
+
+impl ScorePart {
+    fn name(&self) -> Option<&str> {
+        self.part_name.as_deref()
+    }
+}
+
+/// Read the [`ScorePart`] of every part in the `<part-list>`, keyed by the part's `id`.
+fn read_part_list(tree: &elementtree::Element) -> HashMap<&str, ScorePart> {
+    tree.find("part-list")
+        .into_iter()
+        .flat_map(|part_list| part_list.find_all("score-part"))
+        .filter_map(|score_part| {
+            let id = score_part.get_attr("id")?;
+            let instrument_names = score_part
+                .find_all("score-instrument")
+                .filter_map(|instrument| {
+                    let instrument_id = instrument.get_attr("id")?.to_owned();
+                    let name = instrument.find("instrument-name")?.text().trim().to_owned();
+                    Some((instrument_id, name))
+                })
+                .collect();
+            let part = ScorePart {
+                part_name: score_part
+                    .find("part-name")
+                    .map(|name| name.text().to_owned()),
+                instrument_names,
             };
-            *whacks_loaded_so_far += 1;
-            whacks
-                .entry(Note::from_note(octave, note_name, alter)?)
-                .or_default()
-                .push(whack);
+            Some((id, part))
+        })
+        .collect()
+}
+
+/// The information needed to read the notes of a single part.
+#[derive(Debug)]
+struct PartInfo<'a> {
+    id: &'a str,
+    instrument_names: Option<&'a HashMap<String, String>>,
+    /// The staves of this part whose notes are played
+    played_staves: PlayedStaves,
+}
+
+impl PartInfo<'_> {
+    /// Determine which [`Instrument`] plays an unpitched `<note>`.  Unpitched notes are written
+    /// at some display position on the staff, and (optionally) refer to a `<score-instrument>`
+    /// of the part.  Both are used to identify the instrument, since drum kits are often
+    /// exported as one `<score-instrument>` with each drum at a different position.
+    fn unpitched_instrument(
+        &self,
+        note_elem: &elementtree::Element,
+        unpitched_elem: &elementtree::Element,
+    ) -> Instrument {
+        let display_step = unpitched_elem.find("display-step").map_or("", |e| e.text());
+        let display_octave = unpitched_elem
+            .find("display-octave")
+            .map_or("", |e| e.text());
+        let display_position = format!("{}{}", display_step.trim(), display_octave.trim());
+        let instrument_id = note_elem
+            .find("instrument")
+            .and_then(|instrument| instrument.get_attr("id"));
+
+        let id = format!(
+            "{}/{}/{}",
+            self.id,
+            instrument_id.unwrap_or_default(),
+            display_position
+        );
+        let instrument_name = instrument_id
+            .and_then(|id| self.instrument_names?.get(id))
+            .filter(|name| !name.is_empty());
+        let name = match instrument_name {
+            Some(name) => name.clone(),
+            None => format!("Perc {display_position}"),
+        };
+        Instrument::Unpitched {
+            id: id.into(),
+            name: name.into(),
         }
-        // If a 'note' has no pitch, it must be a rest
-        None => assert!(elem.find("rest").is_some()),
     }
-    Some(())
 }
 
-/// Load the number of divisions per beat, for a given part
-fn divisions_per_beat(part_elem: &elementtree::Element) -> Option<usize> {
-    part_elem
-        .children()
-        .next()?
-        .find("attributes")?
-        .find("divisions")?
-        .text()
-        .parse()
-        .ok()
-}
-
-fn note_duration(
-    elem: &elementtree::Element,
-    divs_per_beat: usize,
-    bpm_changes: &[(Timestamp, f64)],
-    next_chord_start: Timestamp,
-) -> Option<Duration> {
-    let num_divs_in_note = elem.find("duration")?.text().parse::<u32>().ok()?;
-    // Get the BPM at this note, so we know how long each `division` is
-    let current_bpm_idx = bpm_changes
-        .binary_search_by_key(&next_chord_start, |(dur, _new_bpm)| *dur)
-        .map_or_else(|gap_idx| gap_idx.saturating_sub(1), |hit_idx| hit_idx);
-    let current_bpm = bpm_changes
-        .get(current_bpm_idx)
-        .map_or(120.0, |(_start, bpm)| *bpm);
-    let div_duration = Duration::from_secs_f64(60.0 / current_bpm / divs_per_beat as f64);
-    let note_duration = div_duration * num_divs_in_note;
-    Some(note_duration)
-}
-
-/// Indication of a point in time where a note starts
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Timestamp {
-    secs: OrderedFloat<f64>,
-}
-
-impl Timestamp {
-    pub const ZERO: Self = Timestamp {
-        secs: OrderedFloat(0.0),
+/// Read the repeat barlines, voltas and jumps of a `<measure>`.  `current_ending` tracks the
+/// volta which is still open at the end of the previous measure.
+fn read_measure_jumps(
+    measure: &elementtree::Element,
+    current_ending: &mut Option<Vec<usize>>,
+) -> MeasureJumps {
+    let mut jumps = MeasureJumps::default();
+    let mut is_ending_finished = false;
+    let read_sound = |jumps: &mut MeasureJumps, sound_elem: &elementtree::Element| {
+        let attr = |name: &str| sound_elem.get_attr(name).map(str::to_owned);
+        jumps.da_capo |= sound_elem.get_attr("dacapo") == Some("yes");
+        jumps.fine |= sound_elem.get_attr("fine").is_some();
+        jumps.segno = jumps.segno.take().or_else(|| attr("segno"));
+        jumps.coda = jumps.coda.take().or_else(|| attr("coda"));
+        jumps.dal_segno = jumps.dal_segno.take().or_else(|| attr("dalsegno"));
+        jumps.to_coda = jumps.to_coda.take().or_else(|| attr("tocoda"));
     };
+    for elem in measure.children() {
+        match elem.tag().name() {
+            "barline" => {
+                if let Some(repeat_elem) = elem.find("repeat") {
+                    match repeat_elem.get_attr("direction") {
+                        Some("forward") => jumps.start_repeat = true,
+                        Some("backward") => {
+                            let times = repeat_elem.get_attr("times").and_then(|t| t.parse().ok());
+                            jumps.end_repeat = Some(times.unwrap_or(2));
+                        }
+                        _ => {}
+                    }
+                }
+                if let Some(ending_elem) = elem.find("ending") {
+                    match ending_elem.get_attr("type") {
+                        Some("start") => {
+                            let numbers = ending_elem.get_attr("number").unwrap_or("1");
+                            *current_ending = Some(
+                                numbers
+                                    .split(|c: char| !c.is_ascii_digit())
+                                    .filter_map(|n| n.parse().ok())
+                                    .collect(),
+                            );
+                        }
+                        Some("stop" | "discontinue") => is_ending_finished = true,
+                        _ => {}
+                    }
+                }
+            }
+            "direction" => {
+                if let Some(sound_elem) = elem.find("sound") {
+                    read_sound(&mut jumps, sound_elem);
+                }
+                // Segnos are often only written as symbols, without a corresponding `<sound>`
+                let has_segno_symbol = elem
+                    .find_all("direction-type")
+                    .any(|direction_type| direction_type.find("segno").is_some());
+                if has_segno_symbol && jumps.segno.is_none() {
+                    jumps.segno = Some(String::new());
+                }
+            }
+            "sound" => read_sound(&mut jumps, elem),
+            _ => {}
+        }
+    }
+    jumps.ending = current_ending.clone();
+    if is_ending_finished {
+        *current_ending = None;
+    }
+    jumps
+}
+
+/// Determine whether a `<note>` starts and/or stops a tie.  The `<tie>` element is the one which
+/// affects playback, but some exporters only write the `<notations><tied>` element, so we check
+/// both.
+fn tie_types(elem: &elementtree::Element) -> (bool, bool) {
+    let tie_elems = elem
+        .find_all("tie")
+        .chain(elem.find_all("notations").flat_map(|n| n.find_all("tied")));
+    let mut is_start = false;
+    let mut is_stop = false;
+    for tie_elem in tie_elems {
+        match tie_elem.get_attr("type") {
+            Some("start") => is_start = true,
+            Some("stop") => is_stop = true,
+            _ => {}
+        }
+    }
+    (is_start, is_stop)
+}
+
+/// The `<attributes>` which affect the timings and pitches of the notes in a measure.  These can
+/// be changed in any measure (or even part-way through a measure).
+#[derive(Debug, Clone, Copy)]
+struct MeasureAttributes {
+    /// The number of divisions per quarter note.  MusicXML expresses all its note values as an
+    /// integer multiple of some 'division' value (presumably to avoid floating point errors).
+    divisions: f64,
+    time_signature: Option<TimeSignature>,
+    /// The number of semitones between the written and sounding pitches, for parts written for
+    /// transposing instruments (e.g. `-2` for a B♭ clarinet, or `-12` for a guitar)
+    transpose: i32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TimeSignature {
+    /// The number of beats in each measure.  Additive time signatures (like `3+2`) are summed.
+    beats: f64,
+    /// The note value of each beat (e.g. `4` for quarter notes)
+    beat_type: f64,
+}
 
-    pub const MAX: Self = Timestamp {
-        secs: OrderedFloat(f64::MAX),
+impl MeasureAttributes {
+    /// The attributes at the start of a part, or `None` if the part never specifies its
+    /// divisions.  If the first measure's `<attributes>` appear after some notes, we still use
+    /// them for those notes.
+    fn initial(part_elem: &elementtree::Element) -> Option<Self> {
+        let divisions = part_elem
+            .find_all("measure")
+            .flat_map(|measure| measure.find_all("attributes"))
+            .find_map(|attributes_elem| attributes_elem.find("divisions"))?
+            .text()
+            .trim()
+            .parse()
+            .ok()?;
+        Some(Self {
+            divisions,
+            time_signature: None,
+            transpose: 0,
+        })
+    }
+
+    /// Apply any changes made by an `<attributes>` element.
+    fn update(&mut self, attributes_elem: &elementtree::Element) {
+        if let Some(divisions) = attributes_elem
+            .find("divisions")
+            .and_then(|divs_elem| divs_elem.text().trim().parse::<f64>().ok())
+            .filter(|divs| *divs > 0.0)
+        {
+            self.divisions = divisions;
+        }
+        if let Some(time_elem) = attributes_elem.find("time") {
+            // Unmeasured music (`<senza-misura>`) has no time signature
+            self.time_signature = TimeSignature::from_elem(time_elem);
+        }
+        if let Some(transpose_elem) = attributes_elem.find("transpose") {
+            self.transpose = read_transpose(transpose_elem);
+        }
+    }
+}
+
+/// Read the number of semitones by which a `<transpose>` element moves the written pitches to
+/// get the sounding pitches.
+fn read_transpose(transpose_elem: &elementtree::Element) -> i32 {
+    let child_number = |name: &'static str| {
+        (transpose_elem.find(name))
+            .and_then(|elem| elem.text().trim().parse::<f64>().ok())
+            .map_or(0, |value| value.round() as i32)
     };
+    let mut semitones = child_number("chromatic") + child_number("octave-change") * 12;
+    // `<double/>` means that the part is also played an octave lower
+    if transpose_elem.find("double").is_some() {
+        semitones -= 12;
+    }
+    semitones
+}
+
+/// The octave-shift lines (`8va`, `8vb`, `15ma`, etc.) which are active in a part.  These can
+/// start and stop part-way through a measure, and each one only affects a single staff.
+#[derive(Debug, Clone, Default)]
+struct OctaveShifts {
+    /// Maps the `number` of each active line to the staff it affects, and the number of
+    /// semitones between the written and sounding pitches of that staff's notes
+    active: HashMap<String, (usize, i32)>,
+}
 
-    pub fn secs_until(self, other: Self) -> f64 {
-        other.secs.0 - self.secs.0
+impl OctaveShifts {
+    /// Start or stop any octave-shift lines in a `<direction>`.
+    fn update(&mut self, direction_elem: &elementtree::Element) {
+        let staff = (direction_elem.find("staff"))
+            .and_then(|staff_elem| staff_elem.text().trim().parse::<usize>().ok())
+            .unwrap_or(1);
+        let shift_elems = (direction_elem.find_all("direction-type"))
+            .flat_map(|direction_type| direction_type.find_all("octave-shift"));
+        for shift_elem in shift_elems {
+            let number = shift_elem.get_attr("number").unwrap_or("1").to_owned();
+            // A size of 8 is one octave, 15 is two octaves, etc.
+            let size = (shift_elem.get_attr("size"))
+                .and_then(|size| size.trim().parse::<i32>().ok())
+                .unwrap_or(8);
+            let semitones = (size - 1) / 7 * 12;
+            // The `type` says which way the notes are displayed, relative to how they sound.  So
+            // an `8va` line, whose notes sound an octave higher than written, is a shift `down`.
+            match shift_elem.get_attr("type") {
+                Some("down") => _ = self.active.insert(number, (staff, semitones)),
+                Some("up") => _ = self.active.insert(number, (staff, -semitones)),
+                Some("stop") => _ = self.active.remove(&number),
+                _ => {} // `continue` doesn't change anything
+            }
+        }
+    }
+
+    /// The number of semitones by which the notes on the given `staff` are moved.
+    fn semitones(&self, staff: usize) -> i32 {
+        (self.active.values())
+            .filter(|(shift_staff, _)| *shift_staff == staff)
+            .map(|(_, semitones)| semitones)
+            .sum()
     }
 }
 
-impl std::fmt::Debug for Timestamp {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:>6.2}s", self.secs)
+impl TimeSignature {
+    fn from_elem(time_elem: &elementtree::Element) -> Option<Self> {
+        // Time signatures like `2/4 + 3/8` are written as several `<beats>`/`<beat-type>` pairs
+        let mut quarters_per_measure = 0.0;
+        let mut last_sig = None;
+        for (beats_elem, beat_type_elem) in time_elem
+            .find_all("beats")
+            .zip(time_elem.find_all("beat-type"))
+        {
+            let beats = beats_elem
+                .text()
+                .split('+')
+                .map(|b| b.trim().parse::<f64>().ok())
+                .sum::<Option<f64>>()?;
+            let beat_type = beat_type_elem.text().trim().parse::<f64>().ok()?;
+            quarters_per_measure += beats * 4.0 / beat_type;
+            last_sig = Some((beats, beat_type));
+        }
+        // Composite signatures are stored in units of the last beat type
+        let (_, beat_type) = last_sig?;
+        Some(Self {
+            beats: quarters_per_measure * beat_type / 4.0,
+            beat_type,
+        })
+    }
+
+    fn quarters_per_measure(self) -> f64 {
+        self.beats * 4.0 / self.beat_type
+    }
+
+    /// The length of the beat (in quarter notes) that tempo words like `Allegro` refer to.  In
+    /// compound time (e.g. 6/8), this is a dotted note.
+    fn beat_length(self) -> f64 {
+        let note_length = 4.0 / self.beat_type;
+        let is_compound = self.beat_type >= 8.0 && self.beats > 3.0 && self.beats % 3.0 == 0.0;
+        if is_compound {
+            note_length * 3.0
+        } else {
+            note_length
+        }
     }
 }
 
+/// Read the articulations, tremolos and fermatas from the `<notations>` of a `<note>`.
+fn read_articulations(note_elem: &elementtree::Element) -> Articulations {
+    let mut articulations = Articulations::default();
+    for notations in note_elem.find_all("notations") {
+        for elem in notations.children() {
+            match elem.tag().name() {
+                "fermata" => articulations.fermata = true,
+                "articulations" => {
+                    for articulation in elem.children() {
+                        match articulation.tag().name() {
+                            "staccato" | "staccatissimo" | "spiccato" => {
+                                articulations.staccato = true
+                            }
+                            "accent" | "strong-accent" => articulations.accent = true,
+                            _ => {}
+                        }
+                    }
+                }
+                // Both single-note and two-note tremolos are written as `<ornaments><tremolo>`
+                "ornaments" => articulations.tremolo |= elem.find("tremolo").is_some(),
+                _ => {}
+            }
+        }
+    }
+    articulations
+}
+
+/// Read the `<duration>` (in divisions) of a `<note>`, `<backup>` or `<forward>` element.
+fn element_duration(elem: &elementtree::Element) -> Option<f64> {
+    elem.find("duration")?.text().trim().parse().ok()
+}
+
+/// Find the extension lines which start or stop in some `<direction-type>`s, returning their
+/// `(<element name>, <number>)` along with their `type` attribute.
+fn extension_lines<'e>(
+    direction_types: &'e [&elementtree::Element],
+) -> impl Iterator<Item = ((String, String), &'e str)> + 'e {
+    direction_types
+        .iter()
+        .flat_map(|direction_type| direction_type.children())
+        .filter(|elem| matches!(elem.tag().name(), "dashes" | "bracket" | "wedge"))
+        .filter_map(|elem| {
+            let number = elem.get_attr("number").unwrap_or("1");
+            let key = (elem.tag().name().to_owned(), number.to_owned());
+            Some((key, elem.get_attr("type")?))
+        })
+}
+
+/// Read the tempo set by a `<direction>` (if any), in quarter notes per minute.  `<sound tempo>`
+/// is always measured in quarter notes, but `<metronome>` marks are measured in their own beat
+/// unit (e.g. half notes in cut time).
+fn direction_tempo(elem: &elementtree::Element) -> Result<Option<f64>, Reason> {
+    if let Some(tempo_str) = elem.find("sound").and_then(|s| s.get_attr("tempo")) {
+        let tempo = tempo_str.trim().parse::<f64>().ok().filter(|t| *t > 0.0);
+        return tempo
+            .map(Some)
+            .ok_or_else(|| Reason::BadTempo(tempo_str.to_owned()));
+    }
+    let metronome_elems = elem
+        .find_all("direction-type")
+        .flat_map(|direction_type| direction_type.find_all("metronome"));
+    for metronome_elem in metronome_elems {
+        let Some(beat_unit_elem) = metronome_elem.find("beat-unit") else {
+            continue;
+        };
+        let Some(per_minute_elem) = metronome_elem.find("per-minute") else {
+            continue; // Metric modulations (like `quarter = dotted quarter`) don't set a tempo
+        };
+        // `per-minute` is free text, so can contain things like `c. 72` or `72-80`
+        let per_minute_text = per_minute_elem.text();
+        let Some(per_minute) = per_minute_text
+            .split(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .find_map(|s| s.parse::<f64>().ok().filter(|bpm| *bpm > 0.0))
+        else {
+            continue;
+        };
+        let num_dots = metronome_elem
+            .children()
+            .skip_while(|c| c.tag().name() != "beat-unit")
+            .skip(1)
+            .take_while(|c| c.tag().name() == "beat-unit-dot")
+            .count();
+        let beat_unit_text = beat_unit_elem.text().trim();
+        let beat_unit = note_type_quarters(beat_unit_text, num_dots)
+            .ok_or_else(|| Reason::BadTempo(format!("{beat_unit_text} = {per_minute_text}")))?;
+        return Ok(Some(per_minute * beat_unit));
+    }
+    Ok(None)
+}
+
+/// Read a `<swing>` element, returning `None` if it asks for straight notes.
+fn read_swing(swing_elem: &elementtree::Element) -> Result<Option<Swing>, Reason> {
+    if swing_elem.find("straight").is_some() {
+        return Ok(None);
+    }
+    let child_text = |name: &'static str| swing_elem.find(name).map_or("", |elem| elem.text());
+    let (first, second) = (child_text("first"), child_text("second"));
+    // The swung notes are eighths unless a `<swing-type>` says otherwise
+    let unit = match child_text("swing-type").trim() {
+        "" => Some(0.5),
+        swing_type => note_type_quarters(swing_type, 0),
+    };
+    let bad_swing = || Reason::BadSwing(format!("{}:{}", first.trim(), second.trim()));
+    let (Some(unit), Ok(first), Ok(second)) = (
+        unit,
+        first.trim().parse::<f64>(),
+        second.trim().parse::<f64>(),
+    ) else {
+        return Err(bad_swing());
+    };
+    Swing::new(unit, first, second)
+        .map(Some)
+        .ok_or_else(bad_swing)
+}
+
+/// The length, in quarter notes, of a note type name (as used by `<type>` and `<beat-unit>`),
+/// with some number of augmentation dots.
+pub fn note_type_quarters(note_type: &str, num_dots: usize) -> Option<f64> {
+    let undotted = match note_type {
+        "maxima" => 32.0,
+        "long" => 16.0,
+        "breve" => 8.0,
+        "whole" => 4.0,
+        "half" => 2.0,
+        "quarter" => 1.0,
+        "eighth" => 0.5,
+        "16th" => 0.25,
+        "32nd" => 0.125,
+        "64th" => 0.0625,
+        "128th" => 0.03125,
+        "256th" => 0.015625,
+        "512th" => 0.0078125,
+        "1024th" => 0.00390625,
+        _ => return None,
+    };
+    // Each dot adds half the value of the previous one
+    Some(undotted * (2.0 - 0.5f64.powi(num_dots as i32)))
+}
+
 ///////////////////////////////
 // CREATING ANNOTATED SCORES //
 ///////////////////////////////
 
+/// How the notes of a score should be annotated for a single player.  Notes are identified by
+/// their `note_idx` (see [`Event::note_idx`]).
+#[derive(Debug, Clone, Default)]
+pub struct NoteAnnotations<'h> {
+    /// The hand which plays each note (or holds it, for tie continuations)
+    pub colours: HashMap<usize, Hand>,
+    /// The instruments to label on each note, sorted from highest to lowest
+    pub lyrics: HashMap<usize, Vec<(&'h Instrument, Hand)>>,
+}
+
 impl MusicXmlScore {
     /// Returns MusicXML to describe this `MusicXmlScore`, with the notes of the given
     /// `{left,right}_hand`s annotated with lyric marks.
-    pub fn annotated_xml(&self, left_hand: &[Note], right_hand: &[Note]) -> String {
-        // We label notes sorted from highest to lowest (because, in MusicXML, lyric marks are
-        // written from top to bottom, and we want the highest notes to be at the top).
-        let mut notes = Vec::new();
-        notes.extend(left_hand.iter().map(|note| (*note, Hand::Left)));
-        notes.extend(right_hand.iter().map(|note| (*note, Hand::Right)));
-        notes.sort_by_key(|(note, _)| Reverse(*note));
-        // Decide which notes need to be coloured
-        let mut coloured_notes = HashMap::<usize, Hand>::new();
-        for &(note, hand) in &notes {
-            for whack in &self.whacks[&note] {
-                coloured_notes.insert(whack.note_idx, hand);
-            }
-        }
-        // Decide which notes need `<lyric>` tags
-        let mut lyric_locations = HashMap::<usize, Vec<(Note, Hand)>>::new();
-        for &(note, hand) in &notes {
-            for whack in &self.whacks[&note] {
-                lyric_locations
-                    .entry(whack.chord_note_idx)
-                    .or_default()
-                    .push((note, hand));
-            }
-        }
+    pub fn annotated_xml(&self, left_hand: &[Instrument], right_hand: &[Instrument]) -> String {
+        let annotations = self.note_annotations(left_hand, right_hand);
         // Traverse the XML tree, modifying it so that the only lyric marks are those of the notes
         // played by this player
         let mut new_tree = self.tree.clone();
         let mut note_idx = 0;
         for part in new_tree.find_all_mut("part") {
-            for measure in part.children_mut() {
+            for measure in part.find_all_mut("measure") {
                 for note_elem in measure.children_mut().filter(|c| c.tag().name() == "note") {
-                    if note_elem.find("rest").is_some() {
-                        assert!(note_elem.find("pitch").is_none());
+                    if !is_counted_note(note_elem) {
                         continue; // Skip rests
                     }
                     // Colour the note
-                    let colour = coloured_notes
+                    let colour = annotations
+                        .colours
                         .get(&note_idx)
                         .map_or("#000000", |hand| hand.colour());
                     note_elem.set_attr("color", colour);
@@ -334,35 +1816,527 @@ impl MusicXmlScore {
                         note_elem.remove_child(idx);
                     }
                     // Add our own lyric tags
-                    for (note, hand) in lyric_locations.get(&note_idx).unwrap_or(&Vec::new()) {
+                    let lyrics = annotations.lyrics.get(&note_idx);
+                    for (instrument, hand) in lyrics.into_iter().flatten() {
                         let lyric_elem = note_elem
                             .append_new_child("lyric")
                             .set_attr("color", hand.colour())
                             .set_attr("number", "1");
                         lyric_elem.append_new_child("syllabic").set_text("single");
-                        lyric_elem.append_new_child("text").set_text(note.name());
+                        lyric_elem
+                            .append_new_child("text")
+                            .set_text(instrument.name());
                     }
                     // Update the `note_idx` now that we've finished with this note
                     note_idx += 1;
                 }
             }
         }
-        // Return `new_tree` as an XML string
+        // Return `new_tree` as an XML string, in the same layout as the original file
+        if self.layout == Layout::Timewise {
+            new_tree = partwise_to_timewise(&new_tree);
+        }
         new_tree.to_string().unwrap()
     }
+
+    /// Decide how the notes should be annotated for a player who plays the given
+    /// `{left,right}_hand`s.
+    pub fn note_annotations<'h>(
+        &self,
+        left_hand: &'h [Instrument],
+        right_hand: &'h [Instrument],
+    ) -> NoteAnnotations<'h> {
+        // We label notes sorted from highest to lowest (because lyric marks are written from top
+        // to bottom, and we want the highest notes to be at the top).
+        let mut instruments = Vec::new();
+        instruments.extend(left_hand.iter().map(|inst| (inst, Hand::Left)));
+        instruments.extend(right_hand.iter().map(|inst| (inst, Hand::Right)));
+        instruments.sort_by_key(|(inst, _)| Reverse(*inst));
+        // Decide which notes need to be coloured
+        let mut annotations = NoteAnnotations::default();
+        for &(instrument, hand) in &instruments {
+            for event in self.instrument_events(instrument) {
+                annotations.colours.insert(event.note_idx, hand);
+            }
+        }
+        // Tie continuations are coloured like the note they continue, but aren't labelled
+        for (&continuation_idx, tie_start_idx) in &self.tie_continuations {
+            if let Some(&hand) = annotations.colours.get(tie_start_idx) {
+                annotations.colours.insert(continuation_idx, hand);
+            }
+        }
+        // Decide which notes need lyrics
+        for &(instrument, hand) in &instruments {
+            // Repeated sections generate several `Event`s for the same `<note>`, but each
+            // `<note>` should only be labelled once
+            let chord_note_idxs = self
+                .instrument_events(instrument)
+                .map(|event| event.chord_note_idx)
+                .unique();
+            for chord_note_idx in chord_note_idxs {
+                annotations
+                    .lyrics
+                    .entry(chord_note_idx)
+                    .or_default()
+                    .push((instrument, hand));
+            }
+        }
+        annotations
+    }
+
+    fn instrument_events<'s>(
+        &'s self,
+        instrument: &'s Instrument,
+    ) -> impl Iterator<Item = &'s Event> + 's {
+        self.events
+            .iter()
+            .filter(move |event| &event.instrument == instrument)
+    }
+}
+
+impl Score for MusicXmlScore {
+    fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    fn player_file(
+        &self,
+        left_hand: &[Instrument],
+        right_hand: &[Instrument],
+    ) -> (&'static str, Vec<u8>) {
+        let xml = self.annotated_xml(left_hand, right_hand);
+        ("musicxml", xml.into_bytes())
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Hand {
+pub enum Hand {
     Left,
     Right,
 }
 
 impl Hand {
-    fn colour(self) -> &'static str {
+    pub fn colour(self) -> &'static str {
         match self {
             Hand::Left => "#ff0000",
             Hand::Right => "#00aa00",
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start of the first measure of every test score: two divisions per quarter note, and
+    /// 60 quarter notes per minute so that times are measured in quarter notes.
+    const SCORE_START: &str = r#"<attributes><divisions>2</divisions></attributes>
+        <direction><sound tempo="60"/></direction>"#;
+
+    /// Wrap the contents of some `<measure>`s in a score with a single part, `P1`.
+    fn score(measures: &[String]) -> String {
+        let measures = (measures.iter().enumerate())
+            .map(|(idx, measure)| {
+                let start = if idx == 0 { SCORE_START } else { "" };
+                format!("<measure number=\"{}\">{start}{measure}</measure>", idx + 1)
+            })
+            .join("\n");
+        format!(
+            "<score-partwise version=\"4.0\">
+                <part-list><score-part id=\"P1\"><part-name>Test</part-name></score-part></part-list>
+                <part id=\"P1\">{measures}</part>
+            </score-partwise>"
+        )
+    }
+
+    /// A `<note>` with a pitch like `C4` or `F#4`, lasting `duration` divisions.  `extra` is
+    /// added to the end of the note (e.g. `<voice>2</voice>`).
+    fn note(pitch: &str, duration: usize, extra: &str) -> String {
+        let (step, rest) = pitch.split_at(1);
+        let (alter, octave) = match rest.strip_prefix('#') {
+            Some(octave) => ("<alter>1</alter>", octave),
+            None => ("", rest),
+        };
+        format!(
+            "<note><pitch><step>{step}</step>{alter}<octave>{octave}</octave></pitch>\
+             <duration>{duration}</duration>{extra}</note>"
+        )
+    }
+
+    fn load(measures: &[String], options: &LoadOptions) -> anyhow::Result<LoadedEvents> {
+        let xml = score(measures);
+        let tree = elementtree::Element::from_reader(xml.as_bytes()).unwrap();
+        load_events(&tree, options)
+    }
+
+    /// Load a score, returning the `(onset, release, note name)` of each event.
+    fn events(measures: &[String], options: &LoadOptions) -> Vec<(f64, f64, String)> {
+        let secs = |time: Timestamp| (Timestamp::ZERO.secs_until(time) * 1e6).round() / 1e6;
+        (load(measures, options).unwrap().events.iter())
+            .map(|e| (secs(e.onset), secs(e.release), e.instrument.name()))
+            .collect()
+    }
+
+    fn expected(events: &[(f64, f64, &str)]) -> Vec<(f64, f64, String)> {
+        (events.iter())
+            .map(|(onset, release, name)| (*onset, *release, name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn voices_with_backup_and_forward() {
+        let measures = [
+            [
+                note("C5", 2, "<voice>1</voice>"),
+                note("D5", 2, "<voice>1</voice>"),
+                note("E5", 4, "<voice>1</voice>"),
+                "<backup><duration>8</duration></backup>".to_owned(),
+                note("C4", 4, "<voice>2</voice>"),
+                note("E4", 4, "<chord/><voice>2</voice>"),
+                "<forward><duration>2</duration></forward>".to_owned(),
+                note("D4", 2, "<voice>2</voice>"),
+            ]
+            .concat(),
+            note("C5", 8, "<voice>1</voice>"),
+        ];
+        let loaded = load(&measures, &LoadOptions::default()).unwrap();
+        assert_eq!(
+            events(&measures, &LoadOptions::default()),
+            expected(&[
+                (0.0, 1.0, "C5"),
+                (0.0, 2.0, "C4"),
+                (0.0, 2.0, "E4"),
+                (1.0, 2.0, "D5"),
+                (2.0, 4.0, "E5"),
+                (3.0, 4.0, "D4"),
+                (4.0, 8.0, "C5"),
+            ])
+        );
+        // Every event still refers back to its own `<note>`
+        let note_idxs = loaded.events.iter().map(|e| e.note_idx).collect_vec();
+        assert_eq!(note_idxs, [0, 3, 4, 1, 2, 5, 6]);
+        let chord_note_idxs = loaded.events.iter().map(|e| e.chord_note_idx).collect_vec();
+        assert_eq!(chord_note_idxs, [0, 3, 3, 1, 2, 5, 6]);
+    }
+
+    #[test]
+    fn ties_across_barlines_and_voices() {
+        let (start, stop) = ("<tie type=\"start\"/>", "<tie type=\"stop\"/>");
+        let measures = [
+            [
+                note("G4", 8, &format!("{start}<voice>1</voice>")),
+                "<backup><duration>8</duration></backup>".to_owned(),
+                note("C4", 8, &format!("{start}<voice>2</voice>")),
+            ]
+            .concat(),
+            // Each tie is finished in the other voice
+            [
+                note("C4", 4, &format!("{stop}<voice>1</voice>")),
+                note("G4", 4, "<voice>1</voice>"),
+                "<backup><duration>8</duration></backup>".to_owned(),
+                note("G4", 4, &format!("{stop}<voice>2</voice>")),
+            ]
+            .concat(),
+        ];
+        assert_eq!(
+            events(&measures, &LoadOptions::default()),
+            expected(&[(0.0, 6.0, "C4"), (0.0, 6.0, "G4"), (6.0, 8.0, "G4")])
+        );
+        let loaded = load(&measures, &LoadOptions::default()).unwrap();
+        assert_eq!(loaded.tie_continuations, HashMap::from([(2, 1), (4, 0)]));
+    }
+
+    #[test]
+    fn grace_and_cue_notes() {
+        let grace = |step: &str| {
+            format!("<note><grace/><pitch><step>{step}</step><octave>4</octave></pitch></note>")
+        };
+        let measures = [[
+            "<note><rest/><duration>2</duration></note>".to_owned(),
+            grace("D"),
+            grace("E"),
+            note("C4", 2, ""),
+            note("F4", 2, "<cue/>"),
+            "<harmony><root><root-step>C</root-step></root><kind>major</kind></harmony>".to_owned(),
+            note("G4", 4, ""),
+        ]
+        .concat()];
+        // Each grace note takes a lead-in before the note it leads into, and cue notes aren't
+        // played by default
+        assert_eq!(
+            events(&measures, &LoadOptions::default()),
+            expected(&[
+                (0.84, 0.84, "D4"),
+                (0.92, 0.92, "E4"),
+                (1.0, 2.0, "C4"),
+                (3.0, 5.0, "G4"),
+            ])
+        );
+        let options = LoadOptions {
+            grace_note_lead_in: Duration::from_millis(250),
+            include_cue_notes: true,
+            ..LoadOptions::default()
+        };
+        assert_eq!(
+            events(&measures, &options),
+            expected(&[
+                (0.5, 0.5, "D4"),
+                (0.75, 0.75, "E4"),
+                (1.0, 2.0, "C4"),
+                (2.0, 3.0, "F4"),
+                (3.0, 5.0, "G4"),
+            ])
+        );
+    }
+
+    #[test]
+    fn divisions_and_time_signature_changes() {
+        let divisions =
+            |divs: usize| format!("<attributes><divisions>{divs}</divisions></attributes>");
+        let measures = [
+            [note("C4", 2, ""), note("D4", 2, "")].concat(),
+            // Divisions can change at the start of a measure, or part-way through it
+            [
+                divisions(4),
+                note("E4", 2, ""),
+                divisions(1),
+                note("F4", 1, ""),
+            ]
+            .concat(),
+            // An empty measure lasts for its whole time signature
+            "<attributes><time><beats>3</beats><beat-type>4</beat-type></time></attributes>"
+                .to_owned(),
+            note("G4", 1, ""),
+        ];
+        assert_eq!(
+            events(&measures, &LoadOptions::default()),
+            expected(&[
+                (0.0, 1.0, "C4"),
+                (1.0, 2.0, "D4"),
+                (2.0, 2.5, "E4"),
+                (2.5, 3.5, "F4"),
+                (6.5, 7.5, "G4"),
+            ])
+        );
+    }
+
+    #[test]
+    fn tempo_marks_and_words() {
+        let direction = |direction_type: &str| {
+            format!("<direction><direction-type>{direction_type}</direction-type></direction>")
+        };
+        let measures = [
+            [note("C4", 2, ""), note("D4", 2, "")].concat(),
+            // Metronome marks are measured in their own beat unit, so this is 144 quarter notes
+            // per minute
+            [
+                direction(
+                    "<metronome><beat-unit>half</beat-unit><per-minute>72</per-minute></metronome>",
+                ),
+                note("E4", 4, ""),
+            ]
+            .concat(),
+            [
+                direction(
+                    "<metronome><beat-unit>quarter</beat-unit><beat-unit-dot/>\
+                     <per-minute>40</per-minute></metronome>",
+                ),
+                note("F4", 2, ""),
+            ]
+            .concat(),
+            // Without a time signature, tempo words are measured in quarter notes
+            [direction("<words>Allegro</words>"), note("G4", 2, "")].concat(),
+        ];
+        assert_eq!(
+            events(&measures, &LoadOptions::default()),
+            expected(&[
+                (0.0, 1.0, "C4"),
+                (1.0, 2.0, "D4"),
+                (2.0, 2.833333, "E4"),
+                (2.833333, 3.833333, "F4"),
+                (3.833333, 4.287879, "G4"),
+            ])
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        let measures = [[
+            note("C4", 2, ""),
+            "<note><pitch><step>E</step><octave>10</octave></pitch><duration>2</duration></note>"
+                .to_owned(),
+            "<note><pitch><step>F</step><octave>4</octave></pitch></note>".to_owned(),
+            note("D4", 2, ""),
+        ]
+        .concat()];
+        let diagnostic = |note_idx: usize, reason: Reason| Diagnostic {
+            location: Location {
+                part_id: Some("P1".to_owned()),
+                measure_number: Some("1".to_owned()),
+                note_idx: Some(note_idx),
+                path: format!("part[@id=\"P1\"]/measure[1]/note[{}]", note_idx + 1),
+            },
+            reason,
+        };
+        let bad_octave = diagnostic(1, Reason::BadOctave("10".to_owned()));
+        let missing_duration = diagnostic(2, Reason::MissingDuration);
+
+        // Loading strictly stops at the first problem
+        let error = load(&measures, &LoadOptions::default()).unwrap_err();
+        assert_eq!(error.downcast::<Diagnostic>().unwrap(), bad_octave);
+        // Loading leniently skips the broken notes, but a note whose pitch can't be read still
+        // takes up time
+        let options = LoadOptions {
+            lenient: true,
+            ..LoadOptions::default()
+        };
+        assert_eq!(
+            events(&measures, &options),
+            expected(&[(0.0, 1.0, "C4"), (2.0, 3.0, "D4")])
+        );
+        let loaded = load(&measures, &options).unwrap();
+        assert_eq!(loaded.warnings, [bad_octave, missing_duration]);
+        let note_idxs = loaded.events.iter().map(|e| e.note_idx).collect_vec();
+        assert_eq!(note_idxs, [0, 3]);
+    }
+
+    #[test]
+    fn alters_and_microtones() {
+        let altered = |alter: &str| {
+            format!(
+                "<note><pitch><step>C</step><alter>{alter}</alter><octave>4</octave></pitch>\
+                 <duration>2</duration></note>"
+            )
+        };
+        let measures = [[altered("1"), altered("-1"), altered("0.5")].concat()];
+        let with_policy = |microtones: MicrotonePolicy| LoadOptions {
+            microtones,
+            ..LoadOptions::default()
+        };
+        let microtonal_alter = |policy| Reason::MicrotonalAlter("0.5".to_owned(), policy);
+
+        let options = with_policy(MicrotonePolicy::Round);
+        assert_eq!(
+            events(&measures, &options),
+            expected(&[(0.0, 1.0, "C♯4"), (1.0, 2.0, "B3"), (2.0, 3.0, "C♯4")])
+        );
+        let loaded = load(&measures, &options).unwrap();
+        let reasons = loaded.warnings.into_iter().map(|w| w.reason).collect_vec();
+        assert_eq!(reasons, [microtonal_alter(MicrotonePolicy::Round)]);
+
+        let options = with_policy(MicrotonePolicy::Distinct);
+        assert_eq!(
+            events(&measures, &options),
+            expected(&[(0.0, 1.0, "C♯4"), (1.0, 2.0, "B3"), (2.0, 3.0, "C4+50¢")])
+        );
+
+        let options = with_policy(MicrotonePolicy::Reject);
+        let error = load(&measures, &options).unwrap_err();
+        let diagnostic = error.downcast::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.location.note_idx, Some(2));
+        assert_eq!(diagnostic.reason, microtonal_alter(MicrotonePolicy::Reject));
+    }
+
+    #[test]
+    fn pitches_transposed_out_of_range() {
+        let measures = [[
+            "<attributes><transpose><chromatic>0</chromatic><octave-change>1</octave-change>\
+             </transpose></attributes>"
+                .to_owned(),
+            note("B9", 2, ""),
+            note("C4", 2, ""),
+        ]
+        .concat()];
+        let options = LoadOptions {
+            lenient: true,
+            ..LoadOptions::default()
+        };
+        assert_eq!(events(&measures, &options), expected(&[(1.0, 2.0, "C5")]));
+        let loaded = load(&measures, &options).unwrap();
+        let reasons = loaded.warnings.into_iter().map(|w| w.reason).collect_vec();
+        assert_eq!(reasons, [Reason::PitchOutOfRange(131)]);
+    }
+
+    #[test]
+    fn transposition_and_octave_shifts() {
+        let octave_shift = |attrs: &str| {
+            format!(
+                "<direction><direction-type><octave-shift {attrs}/></direction-type></direction>"
+            )
+        };
+        let measures = [
+            // Written for a B♭ clarinet, so everything sounds a tone lower
+            [
+                "<attributes><transpose><diatonic>-1</diatonic><chromatic>-2</chromatic>\
+                 </transpose></attributes>"
+                    .to_owned(),
+                note("D4", 2, ""),
+            ]
+            .concat(),
+            [
+                octave_shift("type=\"down\" size=\"8\""),
+                note("E4", 2, ""),
+                octave_shift("type=\"stop\" size=\"8\""),
+                note("F4", 2, ""),
+            ]
+            .concat(),
+        ];
+        assert_eq!(
+            events(&measures, &LoadOptions::default()),
+            expected(&[(0.0, 1.0, "C4"), (1.0, 2.0, "D4"), (2.0, 3.0, "D♯4")])
+        );
+        // The 8va line only moves its notes if asked to
+        let options = LoadOptions {
+            apply_octave_shifts: true,
+            ..LoadOptions::default()
+        };
+        assert_eq!(
+            events(&measures, &options),
+            expected(&[(0.0, 1.0, "C4"), (1.0, 2.0, "D5"), (2.0, 3.0, "D♯4")])
+        );
+    }
+
+    #[test]
+    fn excerpts() {
+        let rehearsal = |name: &str| {
+            format!("<direction><direction-type><rehearsal>{name}</rehearsal></direction-type></direction>")
+        };
+        let measures = [
+            [
+                note("C4", 2, ""),
+                "<direction><sound tempo=\"120\"/></direction>".to_owned(),
+                "<note><rest/><duration>2</duration></note>".to_owned(),
+            ]
+            .concat(),
+            [rehearsal("A"), note("D4", 2, "")].concat(),
+            note("E4", 2, ""),
+            [rehearsal("B"), note("F4", 2, "")].concat(),
+        ];
+        // The notes in the excerpt keep their timings from the full score, including the tempo
+        // change before the excerpt
+        let excerpt = expected(&[(1.5, 2.0, "D4"), (2.0, 2.5, "E4")]);
+        let options = LoadOptions {
+            excerpt_start: Some(ExcerptMark::Rehearsal("a".to_owned())),
+            excerpt_end: Some(ExcerptMark::Rehearsal("B".to_owned())),
+            ..LoadOptions::default()
+        };
+        assert_eq!(events(&measures, &options), excerpt);
+        // An end measure is included in the excerpt
+        let options = LoadOptions {
+            excerpt_start: Some(ExcerptMark::Measure("2".to_owned())),
+            excerpt_end: Some(ExcerptMark::Measure("3".to_owned())),
+            ..LoadOptions::default()
+        };
+        assert_eq!(events(&measures, &options), excerpt);
+
+        let options = LoadOptions {
+            excerpt_start: Some(ExcerptMark::Rehearsal("C".to_owned())),
+            ..LoadOptions::default()
+        };
+        assert!(load(&measures, &options).is_err());
+    }
+}
@@ -1,4 +1,7 @@
-use std::fmt::{Debug, Display, Formatter};
+use std::{
+    fmt::{Debug, Display, Formatter},
+    str::FromStr,
+};
 
 /// Representation of the note to which a single boomwhacker is tuned
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -20,8 +23,19 @@ impl Note {
             "B" => 11,
             _ => return None, // Invalid note name
         };
+        let semis_above_c0 = octave
+            .checked_mul(12)?
+            .checked_add(note_semitones_from_c)?
+            .checked_add(alter)?;
+        Some(Self { semis_above_c0 })
+    }
+
+    /// Create the `Note` with the given MIDI key number (where middle C, `C4`, is 60), or `None`
+    /// if the key isn't a valid MIDI key.
+    pub fn from_midi_key(key: u8) -> Option<Self> {
+        let key = i8::try_from(key).ok()?;
         Some(Self {
-            semis_above_c0: octave * 12 + note_semitones_from_c + alter,
+            semis_above_c0: key - 12,
         })
     }
 
@@ -33,14 +47,37 @@ impl Note {
         let note_name = NOTE_NAMES_SHARPS[semis_above_nearest_c as usize];
         format!("{note_name}{octave}")
     }
+}
 
-    pub fn name_flats(&self) -> String {
-        // Split `self.semis_above_c0` into `(octave * 12) + semis_above_nearest_c`
-        let semis_above_nearest_c = self.semis_above_c0.rem_euclid(12);
-        let octave = self.semis_above_c0.div_euclid(12);
+impl FromStr for Note {
+    type Err = anyhow::Error;
 
-        let note_name = NOTE_NAMES_FLATS[semis_above_nearest_c as usize];
-        format!("{note_name}{octave}")
+    /// Parse a note name like `C4`, `F#3` or `B♭2`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let octave_start = s
+            .find(|c: char| c.is_ascii_digit() || c == '-')
+            .ok_or_else(|| anyhow::anyhow!("Note {s:?} has no octave"))?;
+        let (name, octave) = s.split_at(octave_start);
+        let mut name_chars = name.chars();
+        let letter = name_chars
+            .next()
+            .map(|c| c.to_ascii_uppercase().to_string());
+        let mut alter = 0i8;
+        for accidental in name_chars {
+            let step = match accidental {
+                '#' | '♯' => 1,
+                'b' | '♭' => -1,
+                _ => anyhow::bail!("Invalid accidental {accidental:?} in note {s:?}"),
+            };
+            alter = (alter.checked_add(step))
+                .ok_or_else(|| anyhow::anyhow!("Too many accidentals in note {s:?}"))?;
+        }
+        let octave = (octave.parse::<i8>().ok())
+            .filter(|octave| (0..=9).contains(octave))
+            .ok_or_else(|| anyhow::anyhow!("Invalid octave in note {s:?}"))?;
+        Self::from_note(octave, letter.as_deref().unwrap_or_default(), alter)
+            .ok_or_else(|| anyhow::anyhow!("Invalid note name {s:?}"))
     }
 }
 
@@ -59,6 +96,36 @@ impl Debug for Note {
 const NOTE_NAMES_SHARPS: [&str; 12] = [
     "C", "C♯", "D", "D♯", "E", "F", "F♯", "G", "G♯", "A", "A♯", "B",
 ];
-const NOTE_NAMES_FLATS: [&str; 12] = [
-    "C", "D♭", "D", "E♭", "E", "F", "G♭", "G", "A♭", "A", "B♭", "B",
-];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let name = |s: &str| s.parse::<Note>().unwrap().name();
+        assert_eq!(name("C4"), "C4");
+        assert_eq!(name("f#3"), "F♯3");
+        assert_eq!(name("B♭2"), "A♯2");
+        assert_eq!(name("Cb4"), "B3");
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |s: &str| s.parse::<Note>().unwrap_err().to_string();
+        assert_eq!(error("C20"), "Invalid octave in note \"C20\"");
+        assert_eq!(error("C-1"), "Invalid octave in note \"C-1\"");
+        assert_eq!(error("H4"), "Invalid note name \"H4\"");
+        assert_eq!(error("C"), "Note \"C\" has no octave");
+        assert_eq!(
+            error(&format!("C{}4", "#".repeat(200))),
+            format!("Too many accidentals in note \"C{}4\"", "#".repeat(200))
+        );
+    }
+
+    #[test]
+    fn from_note_overflow() {
+        assert_eq!(Note::from_note(11, "B", 0), None);
+        assert_eq!(Note::from_note(4, "C", i8::MAX), None);
+    }
+}
@@ -0,0 +1,308 @@
+//! Code for 'unrolling' repeats, voltas and jumps (D.C./D.S./Coda/Fine) into the order in which
+//! the measures of a score are actually performed.
+
+/// The repeat/jump markings attached to a single measure of a score.  All the jumps happen at
+/// the end of the measure, whereas the targets (repeat starts, segnos and codas) refer to the
+/// start of the measure.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MeasureJumps {
+    /// Does a forward repeat barline start this measure?
+    pub start_repeat: bool,
+    /// If this measure ends with a backward repeat barline, how many times is the repeated
+    /// section played in total?
+    pub end_repeat: Option<usize>,
+    /// If this measure is part of a volta (i.e. a 1st/2nd/etc. ending), which passes through the
+    /// repeat play it?
+    pub ending: Option<Vec<usize>>,
+    /// The name of the segno which starts at this measure (the target of a D.S.)
+    pub segno: Option<String>,
+    /// The name of the coda which starts at this measure (the target of a 'To Coda')
+    pub coda: Option<String>,
+    /// Does this measure end with a D.C.?
+    pub da_capo: bool,
+    /// If this measure ends with a D.S., which segno does it jump to?
+    pub dal_segno: Option<String>,
+    /// If this measure ends with a 'To Coda', which coda does it jump to?
+    pub to_coda: Option<String>,
+    /// Does the piece end after this measure (once a D.C./D.S. has been taken)?
+    pub fine: bool,
+}
+
+impl MeasureJumps {
+    /// Combine the markings of two copies of the same measure (e.g. from different parts, which
+    /// don't necessarily all contain the same markings).
+    pub fn merge(&mut self, other: &Self) {
+        self.start_repeat |= other.start_repeat;
+        self.end_repeat = self.end_repeat.or(other.end_repeat);
+        self.ending = self.ending.take().or_else(|| other.ending.clone());
+        self.segno = self.segno.take().or_else(|| other.segno.clone());
+        self.coda = self.coda.take().or_else(|| other.coda.clone());
+        self.da_capo |= other.da_capo;
+        self.dal_segno = self.dal_segno.take().or_else(|| other.dal_segno.clone());
+        self.to_coda = self.to_coda.take().or_else(|| other.to_coda.clone());
+        self.fine |= other.fine;
+    }
+
+    fn is_jump(&self) -> bool {
+        self.da_capo || self.dal_segno.is_some()
+    }
+}
+
+/// Determine the order in which the measures are played, returning the indices into `measures`
+/// of every performed measure.
+///
+/// This follows the usual conventions: repeats are not taken again after a D.C. or D.S., in
+/// which case only the final ending of each volta is played.  Only the first D.C./D.S. is taken,
+/// so the resulting order is always finite.
+pub fn playback_order(measures: &[MeasureJumps]) -> Vec<usize> {
+    let loops_back = endings_which_loop_back(measures);
+
+    let mut order = Vec::new();
+    let mut idx = 0;
+    let mut repeat_start = 0;
+    let mut pass = 1;
+    let mut has_jumped = false;
+    let mut is_looping_back = false;
+    while let Some(measure) = measures.get(idx) {
+        if measure.start_repeat && !is_looping_back {
+            repeat_start = idx;
+            pass = 1;
+        }
+        is_looping_back = false;
+
+        // Skip voltas which aren't played in this pass
+        if let Some(passes) = &measure.ending {
+            let is_played = if has_jumped {
+                !loops_back[idx]
+            } else {
+                passes.contains(&pass)
+            };
+            if !is_played {
+                idx += 1;
+                continue;
+            }
+        }
+
+        order.push(idx);
+
+        // Once we've jumped, 'Fine' and 'To Coda' become active
+        if has_jumped && measure.fine {
+            break;
+        }
+        if has_jumped {
+            if let Some(coda_name) = &measure.to_coda {
+                match find_target(measures, idx + 1, coda_name, |m| &m.coda) {
+                    Some(coda_idx) => {
+                        idx = coda_idx;
+                        continue;
+                    }
+                    None => break, // A 'To Coda' with no coda ends the piece
+                }
+            }
+        }
+        // Backward repeats send us back to the last forward repeat (or the start of the piece)
+        if let Some(times) = measure.end_repeat {
+            // A volta which loops back is played on every pass it's numbered with, so the
+            // section must be repeated at least once more than its highest number
+            let times =
+                (measure.ending.iter().flatten().max()).map_or(times, |&n| times.max(n + 1));
+            if !has_jumped && pass < times {
+                pass += 1;
+                idx = repeat_start;
+                is_looping_back = true;
+                continue;
+            }
+            // If there's no explicit forward repeat, the next repeat starts after this one
+            repeat_start = idx + 1;
+            pass = 1;
+        } else if is_volta_end(measures, idx) {
+            // The final ending doesn't loop back, but still finishes its repeat
+            repeat_start = idx + 1;
+            pass = 1;
+        }
+        // D.C./D.S. (only taken once)
+        if !has_jumped && measure.is_jump() {
+            has_jumped = true;
+            idx = match &measure.dal_segno {
+                Some(segno_name) => find_target(measures, 0, segno_name, |m| &m.segno).unwrap_or(0),
+                None => 0,
+            };
+            repeat_start = idx;
+            pass = 1;
+            continue;
+        }
+        idx += 1;
+    }
+    order
+}
+
+/// Find the first measure at or after `start_idx` with the target named `name`.  If no target
+/// has that name, the first target of any name is used instead.
+fn find_target(
+    measures: &[MeasureJumps],
+    start_idx: usize,
+    name: &str,
+    target: impl Fn(&MeasureJumps) -> &Option<String>,
+) -> Option<usize> {
+    let candidates = || (start_idx..measures.len()).filter(|&idx| target(&measures[idx]).is_some());
+    candidates()
+        .find(|&idx| target(&measures[idx]).as_deref() == Some(name))
+        .or_else(|| candidates().next())
+}
+
+/// Is `idx` the last measure of a volta?
+fn is_volta_end(measures: &[MeasureJumps], idx: usize) -> bool {
+    let ending = measures[idx].ending.as_ref();
+    ending.is_some() && measures.get(idx + 1).and_then(|m| m.ending.as_ref()) != ending
+}
+
+/// For each measure, determine whether or not it's part of a volta which ends in a backward
+/// repeat.  After a D.C./D.S., these endings are skipped.
+fn endings_which_loop_back(measures: &[MeasureJumps]) -> Vec<bool> {
+    let mut loops_back = vec![false; measures.len()];
+    let mut span_start = 0;
+    for (idx, measure) in measures.iter().enumerate() {
+        let Some(passes) = &measure.ending else {
+            continue;
+        };
+        if idx == 0 || measures[idx - 1].ending.as_ref() != Some(passes) {
+            span_start = idx;
+        }
+        if is_volta_end(measures, idx) && measure.end_repeat.is_some() {
+            loops_back[span_start..=idx].fill(true);
+        }
+    }
+    loops_back
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain() -> MeasureJumps {
+        MeasureJumps::default()
+    }
+
+    fn start_repeat(mut m: MeasureJumps) -> MeasureJumps {
+        m.start_repeat = true;
+        m
+    }
+
+    fn end_repeat(mut m: MeasureJumps) -> MeasureJumps {
+        m.end_repeat = Some(2);
+        m
+    }
+
+    fn ending(mut m: MeasureJumps, passes: &[usize]) -> MeasureJumps {
+        m.ending = Some(passes.to_vec());
+        m
+    }
+
+    #[test]
+    fn simple_repeat() {
+        let measures = [plain(), end_repeat(plain()), plain()];
+        assert_eq!(playback_order(&measures), [0, 1, 0, 1, 2]);
+    }
+
+    #[test]
+    fn back_to_back_repeats() {
+        let measures = [
+            start_repeat(end_repeat(plain())),
+            start_repeat(end_repeat(plain())),
+        ];
+        assert_eq!(playback_order(&measures), [0, 0, 1, 1]);
+        // Without forward repeats, the second repeat starts after the first one
+        let measures = [end_repeat(plain()), end_repeat(plain())];
+        assert_eq!(playback_order(&measures), [0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn voltas() {
+        // |: A |1 B :|2 C | D
+        let measures = [
+            start_repeat(plain()),
+            end_repeat(ending(plain(), &[1])),
+            ending(plain(), &[2]),
+            plain(),
+        ];
+        assert_eq!(playback_order(&measures), [0, 1, 0, 2, 3]);
+    }
+
+    #[test]
+    fn volta_with_several_passes() {
+        // |: A |1,2 B :|3 C | D
+        let measures = [
+            start_repeat(plain()),
+            end_repeat(ending(plain(), &[1, 2])),
+            ending(plain(), &[3]),
+            plain(),
+        ];
+        assert_eq!(playback_order(&measures), [0, 1, 0, 1, 0, 2, 3]);
+    }
+
+    #[test]
+    fn repeat_after_voltas() {
+        // |: A |1 B :|2 C | D | E :|
+        let measures = [
+            start_repeat(plain()),
+            end_repeat(ending(plain(), &[1])),
+            ending(plain(), &[2]),
+            plain(),
+            end_repeat(plain()),
+        ];
+        assert_eq!(playback_order(&measures), [0, 1, 0, 2, 3, 4, 3, 4]);
+    }
+
+    #[test]
+    fn da_capo_al_fine() {
+        let mut fine = plain();
+        fine.fine = true;
+        let mut da_capo = plain();
+        da_capo.da_capo = true;
+        let measures = [plain(), fine, da_capo];
+        assert_eq!(playback_order(&measures), [0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn da_capo_skips_repeats_and_first_endings() {
+        // |: A |1 B :|2 C (Fine) | D (D.C. al Fine)
+        let mut fine = ending(plain(), &[2]);
+        fine.fine = true;
+        let mut da_capo = plain();
+        da_capo.da_capo = true;
+        let measures = [
+            start_repeat(plain()),
+            end_repeat(ending(plain(), &[1])),
+            fine,
+            da_capo,
+        ];
+        assert_eq!(playback_order(&measures), [0, 1, 0, 2, 3, 0, 2]);
+    }
+
+    #[test]
+    fn dal_segno_al_coda() {
+        let mut segno = plain();
+        segno.segno = Some(String::new());
+        let mut to_coda = plain();
+        to_coda.to_coda = Some(String::new());
+        let mut dal_segno = plain();
+        dal_segno.dal_segno = Some(String::new());
+        let mut coda = plain();
+        coda.coda = Some(String::new());
+        let measures = [plain(), segno, to_coda, dal_segno, coda, plain()];
+        assert_eq!(playback_order(&measures), [0, 1, 2, 3, 1, 2, 4, 5]);
+    }
+
+    #[test]
+    fn dal_segno_to_named_segno() {
+        let segno = |name: &str| {
+            let mut m = plain();
+            m.segno = Some(name.to_owned());
+            m
+        };
+        let mut dal_segno = plain();
+        dal_segno.dal_segno = Some("second".to_owned());
+        let measures = [segno("first"), segno("second"), dal_segno];
+        assert_eq!(playback_order(&measures), [0, 1, 2, 1, 2]);
+    }
+}
@@ -0,0 +1,65 @@
+//! The players who are available to play the boomwhackers, and what each of them can manage.
+
+use std::str::FromStr;
+
+/// The number of players used if no roster is given.
+pub const DEFAULT_NUM_PLAYERS: usize = 7;
+
+/// A single player in the roster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Player {
+    pub name: String,
+    /// The most whackers that this player can manage in each hand, or `None` if there's no
+    /// limit
+    pub max_whackers_per_hand: Option<usize>,
+    /// If `true`, this player only plays with their right hand
+    pub one_handed: bool,
+}
+
+impl Player {
+    /// A player with no limits, named after their (0-indexed) position in the roster.
+    pub fn numbered(idx: usize) -> Self {
+        Self {
+            name: format!("Player {}", idx + 1),
+            max_whackers_per_hand: None,
+            one_handed: false,
+        }
+    }
+
+    /// The most whackers that this player can be given in their `(left, right)` hands.
+    pub fn hand_capacities(&self) -> (usize, usize) {
+        let max = self.max_whackers_per_hand.unwrap_or(usize::MAX);
+        (if self.one_handed { 0 } else { max }, max)
+    }
+}
+
+impl FromStr for Player {
+    type Err = anyhow::Error;
+
+    /// Parse a player written as `<name>[:<max whackers per hand>][:one-handed]`, e.g. `Sam`,
+    /// `Sam:1` or `Sam:2:one-handed`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut fields = s.split(':');
+        let name = fields.next().unwrap_or_default().trim();
+        anyhow::ensure!(!name.is_empty(), "Player {s:?} has no name");
+        let mut player = Self {
+            name: name.to_owned(),
+            max_whackers_per_hand: None,
+            one_handed: false,
+        };
+        for field in fields.map(str::trim) {
+            match field {
+                "one-handed" => player.one_handed = true,
+                "" => {}
+                _ => {
+                    let max = field.parse::<usize>().map_err(|_| {
+                        anyhow::anyhow!("Invalid whackers per hand {field:?} for player {name:?}")
+                    })?;
+                    anyhow::ensure!(max > 0, "Player {name:?} must be able to hold a whacker");
+                    player.max_whackers_per_hand = Some(max);
+                }
+            }
+        }
+        Ok(player)
+    }
+}
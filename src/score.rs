@@ -0,0 +1,132 @@
+//! A format-neutral model of a score, which is all that the assignment search needs to know
+//! about the music.
+
+use std::{collections::HashMap, time::Duration};
+
+use ordered_float::OrderedFloat;
+
+use crate::{diagnostic::Diagnostic, instrument::Instrument};
+
+/// A score loaded from any of the supported file formats.
+pub trait Score {
+    /// Every note played in this score (with repeated sections played once per repeat), sorted
+    /// by onset.
+    fn events(&self) -> &[Event];
+
+    /// The problems which were skipped because the score was loaded leniently.
+    fn warnings(&self) -> &[Diagnostic] {
+        &[]
+    }
+
+    /// Create the file given to a single player who plays the given `{left,right}_hand`s,
+    /// returning the file's extension and contents.
+    fn player_file(
+        &self,
+        left_hand: &[Instrument],
+        right_hand: &[Instrument],
+    ) -> (&'static str, Vec<u8>);
+
+    /// The [`Event`]s of this score, grouped by the [`Instrument`] which plays them.
+    fn whacks(&self) -> Whacks {
+        let mut whacks = Whacks::new();
+        for event in self.events() {
+            let instrument_events = whacks.entry(event.instrument.clone()).or_default();
+            instrument_events.push(event.clone());
+        }
+        whacks
+    }
+}
+
+/// The [`Event`]s played by each instrument, sorted by onset
+pub type Whacks = HashMap<Instrument, Vec<Event>>;
+
+/// A single note played in a score.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct Event {
+    pub onset: Timestamp,
+    /// The time when this note stops sounding (i.e. the end of the last note in its tie chain)
+    pub release: Timestamp,
+    pub instrument: Instrument,
+    /// The index of the note in the source file which generated this `Event`.  Its meaning
+    /// depends on the file format: for MusicXML, this is the 0-based index of the `<note>` tag in
+    /// the XML tree (i.e. if `note_idx = 5`, then there are 5 `<note>` tags before the one
+    /// representing this `Event`), whereas for MIDI files it indexes the notes of the
+    /// [`MidiScore`](crate::midi::MidiScore).
+    pub note_idx: usize,
+    /// The `note_idx` of the first note in the chord containing this `Event`
+    pub chord_note_idx: usize,
+    pub articulations: Articulations,
+}
+
+impl Event {
+    /// The time when the hand playing this note is free to do something else.  Most notes are a
+    /// single whack, but sustained notes keep the whacker in use until they're released.
+    pub fn hand_free_at(&self) -> Timestamp {
+        if self.articulations.is_sustained() {
+            self.release
+        } else {
+            self.onset
+        }
+    }
+}
+
+/// The markings which change how an [`Event`] is played.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Articulations {
+    /// The note is played short (its `release` is already brought forward to match)
+    pub staccato: bool,
+    pub accent: bool,
+    /// The whacker is shaken for the whole length of the note
+    pub tremolo: bool,
+    /// The note is held (e.g. by pressing the whacker against the body) for its whole length
+    pub fermata: bool,
+}
+
+impl Articulations {
+    /// Does playing this note keep the hand busy until the note is released?
+    pub fn is_sustained(self) -> bool {
+        self.tremolo || self.fermata
+    }
+}
+
+/// Indication of a point in time where a note starts
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    secs: OrderedFloat<f64>,
+}
+
+impl Timestamp {
+    pub const ZERO: Self = Timestamp {
+        secs: OrderedFloat(0.0),
+    };
+
+    pub const MAX: Self = Timestamp {
+        secs: OrderedFloat(f64::MAX),
+    };
+
+    pub fn from_secs(secs: f64) -> Self {
+        Timestamp {
+            secs: OrderedFloat(secs),
+        }
+    }
+
+    pub fn secs_until(self, other: Self) -> f64 {
+        other.secs.0 - self.secs.0
+    }
+}
+
+impl std::ops::Sub<Duration> for Timestamp {
+    type Output = Self;
+
+    fn sub(self, rhs: Duration) -> Self {
+        Timestamp {
+            secs: self.secs - rhs.as_secs_f64(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>6.2}s", self.secs)
+    }
+}
@@ -0,0 +1,470 @@
+//! Code for converting positions in a score (measured in quarter notes) into times in seconds,
+//! taking into account tempo marks, gradual tempo changes, fermatas and swing.
+
+use std::str::FromStr;
+
+use itertools::Itertools;
+use ordered_float::OrderedFloat;
+
+/// The tempo used before the first tempo mark, in quarter notes per minute.
+pub const DEFAULT_TEMPO: f64 = 120.0;
+
+/// How much an `accel.`/`rit.` changes the tempo, if the new tempo isn't given explicitly.
+const ACCELERANDO_FACTOR: f64 = 1.25;
+const RITARDANDO_FACTOR: f64 = 0.75;
+
+/// How notes are placed in time, relative to where they're written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimingModel {
+    /// Play every note exactly where it's written, ignoring any swing marks
+    Straight,
+    /// Swing pairs of notes as marked by `<swing>` elements and `Swing` text
+    Swing,
+}
+
+impl FromStr for TimingModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "straight" => Self::Straight,
+            "swing" => Self::Swing,
+            _ => anyhow::bail!("Unknown timing model {s:?} (expected straight/swing)"),
+        })
+    }
+}
+
+/// How pairs of short notes are played.  Swing is measured in pairs of `unit`s, where the first
+/// `unit` of every pair is lengthened and the second is shortened (so a 2:1 swing of eighth notes
+/// plays each pair of eighths like a triplet quarter and eighth).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Swing {
+    /// The length of the notes which are swung, in quarter notes
+    unit: f64,
+    /// The fraction of each pair which is taken by its first note.  `0.5` is straight.
+    first_fraction: f64,
+}
+
+impl Swing {
+    /// Swing notes of length `unit` (in quarter notes) in the ratio `first:second`, returning
+    /// `None` if the ratio isn't valid.
+    pub fn new(unit: f64, first: f64, second: f64) -> Option<Self> {
+        let is_valid = unit > 0.0 && first > 0.0 && second > 0.0;
+        is_valid.then(|| Self {
+            unit,
+            first_fraction: first / (first + second),
+        })
+    }
+
+    /// The standard 'triplet' swing of eighth notes.
+    pub fn eighths() -> Self {
+        Self::new(0.5, 2.0, 1.0).unwrap()
+    }
+
+    /// Parse text which turns swing on or off (like `Swing`, `Med. Swing` or `Straight 8ths`),
+    /// returning `Some(None)` if the text asks for straight notes.  The whole text must be one of
+    /// these phrases, so that text like `No swing` or `Swing feel ends` doesn't turn swing on.
+    pub fn from_words(text: &str) -> Option<Option<Self>> {
+        let text = text.to_lowercase();
+        // Ignore how fast the swing is, as in `Med. Swing` or `Up-tempo Swing`
+        let phrase = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .skip_while(|w| matches!(*w, "slow" | "med" | "medium" | "fast" | "up" | "tempo"))
+            .join(" ");
+        match phrase.as_str() {
+            "straight" | "straight 8ths" | "straight eighths" | "even 8ths" | "even eighths"
+            | "no swing" => Some(None),
+            "swing" | "swung" | "shuffle" | "swing 8ths" | "swing eighths" | "swung 8ths"
+            | "swung eighths" => Some(Some(Self::eighths())),
+            _ => None,
+        }
+    }
+
+    /// Move a position (in quarter notes since the swing started) to where it's played.
+    fn apply(self, quarters: f64) -> f64 {
+        let pair_length = self.unit * 2.0;
+        let pair_start = (quarters / pair_length).floor() * pair_length;
+        let fraction = (quarters - pair_start) / pair_length;
+        let swung_fraction = if fraction < 0.5 {
+            fraction * 2.0 * self.first_fraction
+        } else {
+            self.first_fraction + (fraction - 0.5) * 2.0 * (1.0 - self.first_fraction)
+        };
+        pair_start + swung_fraction * pair_length
+    }
+}
+
+/// The tempo marks of a score, in the order that they were read from the file (which needn't be
+/// time order).  Once all the marks have been read, these are resolved into a [`TempoMap`].
+/// All positions are measured in quarter notes from the start of the score, and all tempos are
+/// measured in quarter notes per minute.
+#[derive(Debug, Clone, Default)]
+pub struct TempoMarks {
+    events: Vec<(f64, TempoEvent)>,
+    /// Fermatas, stored as `(<position where the hold ends>, <quarter notes of extra time>)`
+    holds: Vec<(f64, f64)>,
+    /// Changes of swing, stored as `(<position>, <new swing, or `None` for straight>)`
+    swings: Vec<(f64, Option<Swing>)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TempoEvent {
+    /// The end of a gradual tempo change, after which the tempo is multiplied by the given
+    /// factor (unless an explicit tempo is given at the same position)
+    GradualEnd(f64),
+    /// An explicit new tempo
+    Set(f64),
+    /// Return to the tempo before the last gradual change
+    ATempo,
+    /// Return to the first tempo of the piece
+    TempoPrimo,
+    /// The start of a gradual tempo change.  The end is a separate `GradualEnd` event.
+    GradualStart,
+}
+
+/// The direction of a gradual tempo change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GradualChange {
+    Accelerando,
+    Ritardando,
+}
+
+/// A tempo instruction which can be written in words, like `Allegro` or `rit.`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TempoWords {
+    /// A tempo like `Allegro`, measured in beats per minute (where the beat depends on the time
+    /// signature)
+    Beats(f64),
+    Gradual(GradualChange),
+    ATempo,
+    TempoPrimo,
+}
+
+impl TempoMarks {
+    /// Set an explicit new tempo.
+    pub fn set_tempo(&mut self, position: f64, quarters_per_minute: f64) {
+        self.events
+            .push((position, TempoEvent::Set(quarters_per_minute)));
+    }
+
+    /// Add a tempo instruction which was written in words.  `beat_length` is the length of one
+    /// beat (in quarter notes).
+    pub fn add_words(&mut self, position: f64, words: TempoWords, beat_length: f64) {
+        match words {
+            TempoWords::Beats(bpm) => self.set_tempo(position, bpm * beat_length),
+            TempoWords::Gradual(change) => self.add_gradual(position, None, change),
+            TempoWords::ATempo => self.events.push((position, TempoEvent::ATempo)),
+            TempoWords::TempoPrimo => self.events.push((position, TempoEvent::TempoPrimo)),
+        }
+    }
+
+    /// Add a gradual tempo change, which is linearly interpolated from `start` to `end`.  If
+    /// `end` isn't known, the change lasts until the next tempo mark (or the end of the score).
+    pub fn add_gradual(&mut self, start: f64, end: Option<f64>, change: GradualChange) {
+        let factor = match change {
+            GradualChange::Accelerando => ACCELERANDO_FACTOR,
+            GradualChange::Ritardando => RITARDANDO_FACTOR,
+        };
+        self.events.push((start, TempoEvent::GradualStart));
+        // Use NaN to mark that the end still needs to be determined
+        let end = end.filter(|end| *end > start).unwrap_or(f64::NAN);
+        self.events.push((end, TempoEvent::GradualEnd(factor)));
+    }
+
+    /// Add a fermata which holds the music at `position` for an extra `extra_quarters`.
+    pub fn add_hold(&mut self, position: f64, extra_quarters: f64) {
+        self.holds.push((position, extra_quarters));
+    }
+
+    /// Start swinging notes from `position` onwards, or play them straight if `swing` is `None`.
+    pub fn set_swing(&mut self, position: f64, swing: Option<Swing>) {
+        self.swings.push((position, swing));
+    }
+
+    /// Resolve these tempo marks into a [`TempoMap`].  `score_end` is the position of the end
+    /// of the score.
+    pub fn build(mut self, score_end: f64) -> TempoMap {
+        // Gradual changes without an explicit end last until the next tempo change
+        let explicit_positions = self
+            .events
+            .iter()
+            .filter(|(pos, event)| !pos.is_nan() && *event != TempoEvent::GradualStart)
+            .map(|(pos, _)| *pos)
+            .sorted_by_key(|pos| OrderedFloat(*pos))
+            .collect_vec();
+        for idx in 0..self.events.len() {
+            if self.events[idx].0.is_nan() {
+                let start = self.events[idx - 1].0; // `GradualStart` is always pushed just before
+                self.events[idx].0 = explicit_positions
+                    .iter()
+                    .copied()
+                    .find(|pos| *pos > start)
+                    .unwrap_or(score_end.max(start));
+            }
+        }
+        // Sort events by position, making sure that gradual changes end before any new tempo at
+        // the same position (so that the new tempo wins)
+        self.events
+            .sort_by_key(|(pos, event)| (OrderedFloat(*pos), event_priority(*event)));
+
+        let mut points = vec![TempoPoint {
+            position: 0.0,
+            quarters_per_minute: DEFAULT_TEMPO,
+            ramps_to_next: false,
+        }];
+        let mut first_tempo = None;
+        let mut tempo_before_gradual = DEFAULT_TEMPO;
+        let mut gradual_start_tempo = DEFAULT_TEMPO;
+        for &(position, event) in &self.events {
+            let current_tempo = points.last().unwrap().quarters_per_minute;
+            let (new_tempo, ramps_to_next) = match event {
+                TempoEvent::Set(tempo) => {
+                    first_tempo.get_or_insert(tempo);
+                    tempo_before_gradual = tempo;
+                    (tempo, false)
+                }
+                TempoEvent::ATempo => (tempo_before_gradual, false),
+                TempoEvent::TempoPrimo => (first_tempo.unwrap_or(tempo_before_gradual), false),
+                TempoEvent::GradualStart => {
+                    gradual_start_tempo = current_tempo;
+                    (current_tempo, true)
+                }
+                TempoEvent::GradualEnd(factor) => (gradual_start_tempo * factor, false),
+            };
+            let point = TempoPoint {
+                position,
+                quarters_per_minute: new_tempo,
+                ramps_to_next,
+            };
+            // Later events at the same position override earlier ones (but a ramp which ends here
+            // still ramps to the overriding tempo)
+            match points.last_mut() {
+                Some(last) if last.position == position => {
+                    last.quarters_per_minute = new_tempo;
+                    last.ramps_to_next |= ramps_to_next;
+                }
+                _ => points.push(point),
+            }
+        }
+
+        // Precompute the number of seconds before each point, so that lookups are fast
+        let mut point_secs = vec![0.0];
+        for (p1, p2) in points.iter().tuple_windows() {
+            let secs = *point_secs.last().unwrap() + segment_secs(p1, p2, p2.position);
+            point_secs.push(secs);
+        }
+
+        let holds = self
+            .holds
+            .into_iter()
+            .sorted_by_key(|(pos, _)| OrderedFloat(*pos))
+            // Fermatas are often written in several parts, so only count each position once
+            .coalesce(|(pos1, extra1), (pos2, extra2)| {
+                if (pos1 - pos2).abs() < 1e-6 {
+                    Ok((pos1, extra1.max(extra2)))
+                } else {
+                    Err(((pos1, extra1), (pos2, extra2)))
+                }
+            })
+            .collect_vec();
+        // Swing marks are often written in several parts, so later marks at the same position
+        // replace earlier ones
+        let swings = self
+            .swings
+            .into_iter()
+            .sorted_by_key(|(pos, _)| OrderedFloat(*pos))
+            .coalesce(|(pos1, swing1), (pos2, swing2)| {
+                if (pos1 - pos2).abs() < 1e-6 {
+                    Ok((pos1, swing2))
+                } else {
+                    Err(((pos1, swing1), (pos2, swing2)))
+                }
+            })
+            .collect_vec();
+        let mut tempo_map = TempoMap {
+            points,
+            point_secs,
+            hold_secs: Vec::new(),
+            swings,
+        };
+        tempo_map.hold_secs = holds
+            .into_iter()
+            .map(|(pos, extra_quarters)| {
+                // The hold happens at the tempo of the held note, not any new tempo which starts
+                // once the hold is over
+                let tempo = tempo_map.tempo_at((pos - 1e-9).max(0.0));
+                (pos, extra_quarters * 60.0 / tempo)
+            })
+            .collect();
+        tempo_map
+    }
+}
+
+fn event_priority(event: TempoEvent) -> usize {
+    match event {
+        TempoEvent::GradualEnd(_) => 0,
+        TempoEvent::Set(_) | TempoEvent::ATempo | TempoEvent::TempoPrimo => 1,
+        TempoEvent::GradualStart => 2,
+    }
+}
+
+/// A map from positions in the score (in quarter notes) to times (in seconds).
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    /// The points where the tempo changes, sorted by position.  The first point is always at
+    /// position 0.
+    points: Vec<TempoPoint>,
+    /// The number of seconds before each of the `points` (not including holds)
+    point_secs: Vec<f64>,
+    /// Fermatas, stored as `(<position where the hold ends>, <extra seconds>)`
+    hold_secs: Vec<(f64, f64)>,
+    /// Changes of swing, sorted by position (see [`TempoMarks::swings`])
+    swings: Vec<(f64, Option<Swing>)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TempoPoint {
+    position: f64,
+    quarters_per_minute: f64,
+    /// If `true`, the tempo changes linearly until the next point.  Otherwise, it stays
+    /// constant.
+    ramps_to_next: bool,
+}
+
+impl TempoMap {
+    /// The number of seconds between the start of the score and when the given position is
+    /// played (taking any swing into account).
+    pub fn secs_at(&self, quarters: f64) -> f64 {
+        let quarters = self.swung_position(quarters);
+        let point_idx = self.point_idx_at(quarters);
+        let point = &self.points[point_idx];
+        let secs = match self.points.get(point_idx + 1) {
+            Some(next_point) => segment_secs(point, next_point, quarters),
+            None => (quarters - point.position) * 60.0 / point.quarters_per_minute,
+        };
+        let hold_secs: f64 = self
+            .hold_secs
+            .iter()
+            .take_while(|(pos, _)| *pos <= quarters)
+            .map(|(_, secs)| secs)
+            .sum();
+        self.point_secs[point_idx] + secs + hold_secs
+    }
+
+    /// The tempo (in quarter notes per minute) at the given position.
+    pub fn tempo_at(&self, quarters: f64) -> f64 {
+        let point_idx = self.point_idx_at(quarters);
+        let point = &self.points[point_idx];
+        match self.points.get(point_idx + 1) {
+            Some(next_point) if point.ramps_to_next => {
+                let progress = (quarters - point.position) / (next_point.position - point.position);
+                point.quarters_per_minute
+                    + (next_point.quarters_per_minute - point.quarters_per_minute) * progress
+            }
+            _ => point.quarters_per_minute,
+        }
+    }
+
+    /// The position at which a note written at `quarters` is played, after applying the swing
+    /// which is active there.  Swing is measured in pairs from the mark which started it.
+    fn swung_position(&self, quarters: f64) -> f64 {
+        let swing_idx = self.swings.partition_point(|(pos, _)| *pos <= quarters);
+        match swing_idx.checked_sub(1).map(|idx| self.swings[idx]) {
+            Some((start, Some(swing))) => start + swing.apply(quarters - start),
+            _ => quarters,
+        }
+    }
+
+    /// The index of the last point at or before `quarters`.
+    fn point_idx_at(&self, quarters: f64) -> usize {
+        self.points
+            .partition_point(|p| p.position <= quarters)
+            .saturating_sub(1)
+    }
+}
+
+/// The number of seconds between `p1` and `quarters`, assuming that `quarters` lies between
+/// `p1` and `p2`.
+fn segment_secs(p1: &TempoPoint, p2: &TempoPoint, quarters: f64) -> f64 {
+    let quarters_since_p1 = quarters - p1.position;
+    let tempo1 = p1.quarters_per_minute;
+    let tempo2 = p2.quarters_per_minute;
+    if !p1.ramps_to_next || (tempo1 - tempo2).abs() < 1e-9 {
+        return quarters_since_p1 * 60.0 / tempo1;
+    }
+    // The tempo changes linearly, so integrate `60 / tempo` over the segment
+    let tempo_gradient = (tempo2 - tempo1) / (p2.position - p1.position);
+    let tempo_at_quarters = tempo1 + tempo_gradient * quarters_since_p1;
+    60.0 / tempo_gradient * (tempo_at_quarters / tempo1).ln()
+}
+
+impl TempoWords {
+    /// Parse some text (like `Allegro` or `rit.`) as a tempo instruction.
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim().to_lowercase();
+        let words = text
+            .split(|c: char| !c.is_alphabetic())
+            .filter(|w| !w.is_empty())
+            .collect_vec();
+        if text.starts_with("a tempo") {
+            return Some(Self::ATempo);
+        }
+        if text.starts_with("tempo i") || text.starts_with("tempo primo") {
+            return Some(Self::TempoPrimo);
+        }
+        let gradual = words.iter().find_map(|word| match *word {
+            "accel" | "accelerando" | "stringendo" | "string" => Some(GradualChange::Accelerando),
+            "rit" | "ritard" | "ritardando" | "ritenuto" | "riten" | "rall" | "rallentando"
+            | "allarg" | "allargando" => Some(GradualChange::Ritardando),
+            _ => None,
+        });
+        if let Some(change) = gradual {
+            return Some(Self::Gradual(change));
+        }
+        // Look for a tempo term anywhere in the text, to catch things like `Allegro ma non
+        // troppo` or `Moderately fast (Allegretto)`
+        words
+            .iter()
+            .find_map(|word| TEMPO_TERMS.iter().find(|(term, _)| term == word))
+            .map(|(_, bpm)| Self::Beats(*bpm))
+    }
+}
+
+/// Approximate tempos (in beats per minute) of the standard tempo terms.
+const TEMPO_TERMS: [(&str, f64); 15] = [
+    ("grave", 40.0),
+    ("largo", 50.0),
+    ("lento", 52.0),
+    ("larghetto", 63.0),
+    ("adagio", 70.0),
+    ("adagietto", 75.0),
+    ("andante", 90.0),
+    ("andantino", 96.0),
+    ("moderato", 110.0),
+    ("allegretto", 116.0),
+    ("allegro", 132.0),
+    ("vivace", 160.0),
+    ("vivacissimo", 170.0),
+    ("presto", 180.0),
+    ("prestissimo", 200.0),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swing_words() {
+        let swing = Some(Some(Swing::eighths()));
+        assert_eq!(Swing::from_words("Swing"), swing);
+        assert_eq!(Swing::from_words("Med. Swing"), swing);
+        assert_eq!(Swing::from_words("Swung 8ths"), swing);
+        assert_eq!(Swing::from_words("Straight 8ths"), Some(None));
+        assert_eq!(Swing::from_words("even eighths"), Some(None));
+        assert_eq!(Swing::from_words("No swing"), Some(None));
+        assert_eq!(Swing::from_words("Swing feel ends"), None);
+        assert_eq!(Swing::from_words("Play it straight, then swing"), None);
+        assert_eq!(Swing::from_words("Allegro"), None);
+    }
+}